@@ -0,0 +1,90 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use giallo::{HighlightOptions, Registry, ThemeVariant};
+use std::fs;
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+/// Highlights the same snippet N times, either on a single thread or once on each of N threads
+/// sharing the registry. With linear scaling the parallel versions should take about as long
+/// for N threads as the sequential one for a single snippet, until we run out of cores.
+fn highlight_parallel_benchmark(c: &mut Criterion) {
+    let mut registry =
+        Registry::load_from_file("builtin.zst").expect("Failed to load registry from builtin.zst");
+    registry.link_grammars();
+
+    let ts_content = fs::read_to_string("src/fixtures/samples/simple.ts").unwrap();
+    let options = HighlightOptions::new("typescript", ThemeVariant::Single("vitesse-black"));
+    let highlight = || {
+        let result = registry.highlight(&ts_content, &options).unwrap();
+        std::hint::black_box(result);
+    };
+    // Warm the pattern cache, we only want to measure matching
+    highlight();
+
+    let max_threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let mut group = c.benchmark_group("highlight simple.ts N times");
+    let mut num_threads = 1;
+    while num_threads <= max_threads {
+        group.throughput(Throughput::Elements(num_threads as u64));
+        group.bench_with_input(
+            BenchmarkId::new("single thread", num_threads),
+            &num_threads,
+            |b, &num_threads| {
+                b.iter(|| {
+                    for _ in 0..num_threads {
+                        highlight();
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("one per thread", num_threads),
+            &num_threads,
+            |b, &num_threads| {
+                b.iter_custom(|iters| {
+                    // Threads are started and have highlighted once before timing, to only
+                    // measure matching
+                    let barrier = Barrier::new(num_threads + 1);
+                    let mut elapsed = Duration::ZERO;
+                    std::thread::scope(|s| {
+                        for _ in 0..num_threads {
+                            s.spawn(|| {
+                                highlight();
+                                barrier.wait();
+                                for _ in 0..iters {
+                                    highlight();
+                                }
+                                barrier.wait();
+                            });
+                        }
+                        barrier.wait();
+                        let start = Instant::now();
+                        barrier.wait();
+                        elapsed = start.elapsed();
+                    });
+                    elapsed
+                })
+            },
+        );
+        // Same from threads started for the iteration, which have not searched anything yet:
+        // compiled patterns are shared so this should be close to the warm threads above
+        group.bench_with_input(
+            BenchmarkId::new("one per new thread", num_threads),
+            &num_threads,
+            |b, &num_threads| {
+                b.iter(|| {
+                    std::thread::scope(|s| {
+                        for _ in 0..num_threads {
+                            s.spawn(highlight);
+                        }
+                    });
+                })
+            },
+        );
+        num_threads *= 2;
+    }
+    group.finish();
+}
+
+criterion_group!(benches, highlight_parallel_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use giallo::{HighlightOptions, Registry, ThemeVariant};
+use std::fs;
+
+/// Only measures matching the scopes against the theme rules: the code is tokenized once and
+/// the style caches are cleared before each run.
+fn apply_theme_benchmark(c: &mut Criterion) {
+    let mut registry =
+        Registry::load_from_file("builtin.zst").expect("Failed to load registry from builtin.zst");
+    registry.link_grammars();
+
+    let jquery_content =
+        fs::read_to_string("src/fixtures/samples/jquery.js").expect("Failed to read jQuery file");
+    let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+    let tokenized = registry.tokenize_code(&jquery_content, &options).unwrap();
+
+    let mut group = c.benchmark_group("apply theme to jquery.js");
+    // From a few dozen rules to several hundreds
+    for theme in [
+        "vitesse-black",
+        "github-dark",
+        "one-dark-pro",
+        "catppuccin-mocha",
+    ] {
+        group.bench_with_input(BenchmarkId::from_parameter(theme), theme, |b, theme| {
+            b.iter(|| {
+                registry.clear_style_caches();
+                let result = registry
+                    .apply_theme(&tokenized, ThemeVariant::Single(theme))
+                    .unwrap();
+                std::hint::black_box(result);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, apply_theme_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,313 @@
+use std::ops::Range;
+
+use crate::highlight::HighlightedText;
+use crate::registry::{HighlightedCode, line_endings};
+
+/// How many bytes we look at to decide whether the content is binary, same as git
+const BINARY_DETECTION_LEN: usize = 8000;
+
+/// The encoding some bytes were decoded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextEncoding {
+    /// UTF-8, with or without BOM
+    Utf8,
+    /// UTF-16 little endian, only detected with a BOM
+    Utf16Le,
+    /// UTF-16 big endian, only detected with a BOM
+    Utf16Be,
+    /// ISO-8859-1: every byte is the character with the same code point.
+    /// Used when the content is not valid UTF-8 and doesn't contain a single valid multi-byte
+    /// UTF-8 sequence.
+    Latin1,
+}
+
+/// A run of characters taking the same number of bytes in the decoded text and in the
+/// original bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Run {
+    decoded_start: usize,
+    original_start: usize,
+    /// How many bytes each character of the run takes in the decoded text
+    decoded_len: usize,
+    /// How many bytes each character of the run takes in the original bytes
+    original_len: usize,
+}
+
+/// Some bytes decoded to a string, with what is needed to go back to positions in the original
+/// bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedText {
+    /// The decoded text, without BOM
+    pub text: String,
+    /// The encoding the text was decoded from
+    pub encoding: TextEncoding,
+    /// Whether some invalid sequences were replaced with `U+FFFD`
+    pub lossy: bool,
+    /// Whether the content looks like binary, ie has a NUL byte near its start.
+    /// Binary content is not decoded and `text` is empty.
+    pub binary: bool,
+    runs: Vec<Run>,
+    original_len: usize,
+}
+
+impl DecodedText {
+    /// Decodes `bytes`, detecting the encoding from the BOM if there is one.
+    ///
+    /// Without BOM, the content is UTF-8 with invalid sequences replaced by `U+FFFD`, unless it
+    /// doesn't have any valid multi-byte UTF-8 sequence in which case it is assumed to be
+    /// Latin-1. Content with a NUL byte in the first 8000 bytes is considered binary and left
+    /// undecoded.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut decoder = Decoder::new(bytes.len());
+        let encoding = if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+            decoder.skip(3);
+            decoder.decode_utf8(rest);
+            TextEncoding::Utf8
+        } else if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+            decoder.skip(2);
+            decoder.decode_utf16(rest, u16::from_le_bytes);
+            TextEncoding::Utf16Le
+        } else if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+            decoder.skip(2);
+            decoder.decode_utf16(rest, u16::from_be_bytes);
+            TextEncoding::Utf16Be
+        } else if bytes[..bytes.len().min(BINARY_DETECTION_LEN)].contains(&0) {
+            decoder.binary = true;
+            TextEncoding::Utf8
+        } else if looks_like_latin1(bytes) {
+            decoder.decode_latin1(bytes);
+            TextEncoding::Latin1
+        } else {
+            decoder.decode_utf8(bytes);
+            TextEncoding::Utf8
+        };
+
+        Self {
+            text: decoder.text,
+            encoding,
+            lossy: decoder.lossy,
+            binary: decoder.binary,
+            runs: decoder.runs,
+            original_len: bytes.len(),
+        }
+    }
+
+    /// The position in the original bytes of the given byte offset in `text`.
+    /// An offset in the middle of a character is the start of that character and the end of the
+    /// text is the end of the original bytes.
+    pub fn original_offset(&self, offset: usize) -> usize {
+        if offset >= self.text.len() {
+            return self.original_len;
+        }
+        let idx = self.runs.partition_point(|run| run.decoded_start <= offset) - 1;
+        let run = self.runs[idx];
+        run.original_start + (offset - run.decoded_start) / run.decoded_len * run.original_len
+    }
+
+    /// The span in the original bytes of each token of the highlighted `text`, in the same
+    /// shape as `HighlightedCode::tokens`.
+    pub fn original_spans(&self, tokens: &[Vec<HighlightedText>]) -> Vec<Vec<Range<usize>>> {
+        let mut offset = 0;
+        tokens
+            .iter()
+            .zip(line_endings(&self.text))
+            .map(|(line, line_ending)| {
+                let spans = line
+                    .iter()
+                    .map(|token| {
+                        let start = offset;
+                        offset += token.text.len();
+                        self.original_offset(start)..self.original_offset(offset)
+                    })
+                    .collect();
+                offset += line_ending.as_str().len();
+                spans
+            })
+            .collect()
+    }
+}
+
+/// The result of `Registry::highlight_bytes`
+#[derive(Debug, Clone)]
+pub struct HighlightedBytes<'a> {
+    /// The decoded content
+    pub decoded: DecodedText,
+    /// The highlighted decoded content
+    pub highlighted: HighlightedCode<'a>,
+}
+
+impl HighlightedBytes<'_> {
+    /// The span in the original bytes of each token, in the same shape as
+    /// `HighlightedCode::tokens`.
+    pub fn original_spans(&self) -> Vec<Vec<Range<usize>>> {
+        self.decoded.original_spans(&self.highlighted.tokens)
+    }
+}
+
+/// Bytes that are not valid UTF-8 and don't even contain a valid multi-byte sequence are very
+/// unlikely to be UTF-8 with a few errors
+fn looks_like_latin1(bytes: &[u8]) -> bool {
+    let mut has_invalid = false;
+    for chunk in bytes.utf8_chunks() {
+        if !chunk.valid().is_ascii() {
+            return false;
+        }
+        has_invalid |= !chunk.invalid().is_empty();
+    }
+    has_invalid
+}
+
+struct Decoder {
+    text: String,
+    runs: Vec<Run>,
+    original_offset: usize,
+    lossy: bool,
+    binary: bool,
+}
+
+impl Decoder {
+    fn new(capacity: usize) -> Self {
+        Self {
+            text: String::with_capacity(capacity),
+            runs: Vec::new(),
+            original_offset: 0,
+            lossy: false,
+            binary: false,
+        }
+    }
+
+    fn skip(&mut self, original_len: usize) {
+        self.original_offset += original_len;
+    }
+
+    /// Adds `s`, every character of which takes `decoded_len` bytes in the text and took
+    /// `original_len` bytes in the original bytes.
+    /// Text copied as is from the original bytes can use 1 for both, whatever its characters.
+    fn push(&mut self, s: &str, decoded_len: usize, original_len: usize) {
+        if s.is_empty() {
+            return;
+        }
+        let extends_last_run = self
+            .runs
+            .last()
+            .is_some_and(|run| run.decoded_len == decoded_len && run.original_len == original_len);
+        if !extends_last_run {
+            self.runs.push(Run {
+                decoded_start: self.text.len(),
+                original_start: self.original_offset,
+                decoded_len,
+                original_len,
+            });
+        }
+        self.original_offset += s.len() / decoded_len * original_len;
+        self.text.push_str(s);
+    }
+
+    fn push_char(&mut self, c: char, original_len: usize) {
+        self.push(c.encode_utf8(&mut [0; 4]), c.len_utf8(), original_len);
+    }
+
+    fn decode_utf8(&mut self, bytes: &[u8]) {
+        for chunk in bytes.utf8_chunks() {
+            // Valid parts are copied as is so they map byte to byte
+            self.push(chunk.valid(), 1, 1);
+            if !chunk.invalid().is_empty() {
+                self.lossy = true;
+                self.push_char(char::REPLACEMENT_CHARACTER, chunk.invalid().len());
+            }
+        }
+    }
+
+    fn decode_utf16(&mut self, bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) {
+        let units = bytes
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]));
+        for c in char::decode_utf16(units) {
+            match c {
+                Ok(c) => self.push_char(c, c.len_utf16() * 2),
+                Err(_) => {
+                    self.lossy = true;
+                    self.push_char(char::REPLACEMENT_CHARACTER, 2);
+                }
+            }
+        }
+        // A dangling byte
+        if bytes.len() % 2 == 1 {
+            self.lossy = true;
+            self.push_char(char::REPLACEMENT_CHARACTER, 1);
+        }
+    }
+
+    fn decode_latin1(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.push_char(char::from(b), 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn original_offsets(decoded: &DecodedText) -> Vec<usize> {
+        (0..=decoded.text.len())
+            .filter(|&i| decoded.text.is_char_boundary(i))
+            .map(|i| decoded.original_offset(i))
+            .collect()
+    }
+
+    #[test]
+    fn can_decode_bytes() {
+        let decoded = DecodedText::decode("café ☕".as_bytes());
+        assert_eq!(decoded.text, "café ☕");
+        assert_eq!(decoded.encoding, TextEncoding::Utf8);
+        assert!(!decoded.lossy && !decoded.binary);
+        assert_eq!(original_offsets(&decoded), vec![0, 1, 2, 3, 5, 6, 9]);
+
+        // BOM is removed
+        let decoded = DecodedText::decode(b"\xEF\xBB\xBFa\xC3\xA9");
+        assert_eq!(decoded.text, "aé");
+        assert_eq!(original_offsets(&decoded), vec![3, 4, 6]);
+
+        // Invalid sequences are replaced
+        let decoded = DecodedText::decode(b"\xC3\xA9\xFF\xFEa");
+        assert_eq!(decoded.text, "é\u{FFFD}\u{FFFD}a");
+        assert!(decoded.lossy);
+        assert_eq!(original_offsets(&decoded), vec![0, 2, 3, 4, 5]);
+
+        // No valid multi-byte sequence: Latin-1
+        let decoded = DecodedText::decode(b"caf\xE9 = 1");
+        assert_eq!(decoded.text, "café = 1");
+        assert_eq!(decoded.encoding, TextEncoding::Latin1);
+        assert!(!decoded.lossy);
+        assert_eq!(original_offsets(&decoded), vec![0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn can_decode_utf16() {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend("a\u{e9}\u{1D54F}".encode_utf16().flat_map(u16::to_le_bytes));
+        let decoded = DecodedText::decode(&bytes);
+        assert_eq!(decoded.text, "a\u{e9}\u{1D54F}");
+        assert_eq!(decoded.encoding, TextEncoding::Utf16Le);
+        assert_eq!(original_offsets(&decoded), vec![2, 4, 6, 10]);
+        // In the middle of a character
+        assert_eq!(decoded.original_offset(2), 4);
+
+        let mut bytes = vec![0xFE, 0xFF];
+        bytes.extend("ab".encode_utf16().flat_map(u16::to_be_bytes));
+        bytes.push(0);
+        let decoded = DecodedText::decode(&bytes);
+        assert_eq!(decoded.text, "ab\u{FFFD}");
+        assert_eq!(decoded.encoding, TextEncoding::Utf16Be);
+        assert!(decoded.lossy && !decoded.binary);
+    }
+
+    #[test]
+    fn detects_binary_content() {
+        let decoded = DecodedText::decode(b"\x7FELF\x02\x01\x01\x00\x00");
+        assert!(decoded.binary);
+        assert!(decoded.text.is_empty());
+        assert!(!DecodedText::decode(b"").binary);
+    }
+}
@@ -1,5 +1,8 @@
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
+
+use crate::grammars::GrammarFormat;
 
 pub(crate) type GialloResult<T> = Result<T, Error>;
 
@@ -14,15 +17,44 @@ pub enum Error {
     /// JSON parsing failed when loading a grammar or a theme.
     Json(serde_json::Error),
 
+    /// JSON parsing failed when loading a grammar or a theme file.
+    /// The line and column of the error are available on the `serde_json::Error`.
+    #[allow(missing_docs)]
+    JsonFile {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+
     /// bitcode failure.
     #[cfg(feature = "dump")]
     Bitcode(bitcode::Error),
 
+    /// XML plist parsing failed when loading a grammar.
+    #[cfg(feature = "plist")]
+    Plist(plist::Error),
+
+    /// YAML parsing failed when loading a grammar.
+    #[cfg(feature = "yaml")]
+    Yaml(serde_yaml_ng::Error),
+
+    /// Tried to load a grammar in a format whose feature is not enabled.
+    UnsupportedGrammarFormat(GrammarFormat),
+
+    /// Tried to load a `.tmTheme` theme without the `plist` feature.
+    UnsupportedThemeFormat(PathBuf),
+
     /// An invalid hex color was encountered.
     /// Can only happen when loading a theme.
     #[allow(missing_docs)]
     InvalidHexColor { value: String, reason: String },
 
+    /// A theme includes a file that doesn't exist.
+    #[allow(missing_docs)]
+    MissingThemeInclude { theme: String, include: String },
+
+    /// A theme includes itself, directly or through the themes it includes.
+    ThemeIncludeCycle(String),
+
     /// A grammar was not found in the registry.
     /// Only happens when asking to highlight something with a grammar we can't find
     GrammarNotFound(String),
@@ -47,6 +79,16 @@ pub enum Error {
     /// The user tried to create a dump after linking.
     /// Dump has to be done pre-linking.
     DumpAfterLinking,
+
+    /// The `LineState` given to `Registry::tokenize_line` was not produced for the requested
+    /// grammar or refers to rules the registry doesn't have.
+    InvalidLineState,
+
+    /// Highlighting was cancelled with the `CancellationToken` given in the `HighlightOptions`.
+    Cancelled,
+
+    /// The bytes given to `Registry::highlight_bytes` look like binary content.
+    BinaryContent,
 }
 
 impl fmt::Display for Error {
@@ -54,11 +96,38 @@ impl fmt::Display for Error {
         match self {
             Error::Io(err) => write!(f, "I/O error: {}", err),
             Error::Json(err) => write!(f, "JSON parsing error: {}", err),
+            Error::JsonFile { path, error } => {
+                write!(f, "JSON parsing error in '{}': {}", path.display(), error)
+            }
             #[cfg(feature = "dump")]
             Error::Bitcode(err) => write!(f, "bitcode encoding/decoding error: {}", err),
+            #[cfg(feature = "plist")]
+            Error::Plist(err) => write!(f, "plist parsing error: {}", err),
+            #[cfg(feature = "yaml")]
+            Error::Yaml(err) => write!(f, "YAML parsing error: {}", err),
+            Error::UnsupportedGrammarFormat(format) => {
+                write!(
+                    f,
+                    "loading {:?} grammars requires enabling its feature",
+                    format
+                )
+            }
+            Error::UnsupportedThemeFormat(path) => {
+                write!(
+                    f,
+                    "loading the .tmTheme theme '{}' requires enabling the `plist` feature",
+                    path.display()
+                )
+            }
             Error::InvalidHexColor { value, reason } => {
                 write!(f, "invalid hex color '{}': {}", value, reason)
             }
+            Error::MissingThemeInclude { theme, include } => {
+                write!(f, "theme '{}' includes missing file '{}'", theme, include)
+            }
+            Error::ThemeIncludeCycle(theme) => {
+                write!(f, "theme '{}' includes itself", theme)
+            }
             Error::GrammarNotFound(name) => write!(f, "grammar '{}' not found", name),
             Error::ThemeNotFound(name) => write!(f, "theme '{}' not found", name),
             Error::TokenizeRegex(message) => write!(f, "regex compilation error: {}", message),
@@ -71,6 +140,11 @@ impl fmt::Display for Error {
             Error::DumpAfterLinking => {
                 write!(f, "Cannot dump a registry that has been linked")
             }
+            Error::InvalidLineState => {
+                write!(f, "line state does not belong to this registry and grammar")
+            }
+            Error::Cancelled => write!(f, "highlighting was cancelled"),
+            Error::BinaryContent => write!(f, "content looks like binary"),
         }
     }
 }
@@ -80,11 +154,23 @@ impl std::error::Error for Error {
         match self {
             Error::Io(err) => Some(err),
             Error::Json(err) => Some(err),
+            Error::JsonFile { error, .. } => Some(error),
             #[cfg(feature = "dump")]
             Error::Bitcode(err) => Some(err),
+            #[cfg(feature = "plist")]
+            Error::Plist(err) => Some(err),
+            #[cfg(feature = "yaml")]
+            Error::Yaml(err) => Some(err),
             Error::InvalidHexColor { .. }
+            | Error::MissingThemeInclude { .. }
+            | Error::ThemeIncludeCycle(_)
+            | Error::UnsupportedGrammarFormat(_)
+            | Error::UnsupportedThemeFormat(_)
             | Error::UnlinkedGrammars
             | Error::DumpAfterLinking
+            | Error::InvalidLineState
+            | Error::Cancelled
+            | Error::BinaryContent
             | Error::ReplacingGrammarPostLinking(_)
             | Error::GrammarNotFound(_)
             | Error::ThemeNotFound(_)
@@ -111,3 +197,17 @@ impl From<bitcode::Error> for Error {
         Error::Bitcode(value)
     }
 }
+
+#[cfg(feature = "plist")]
+impl From<plist::Error> for Error {
+    fn from(value: plist::Error) -> Self {
+        Error::Plist(value)
+    }
+}
+
+#[cfg(feature = "yaml")]
+impl From<serde_yaml_ng::Error> for Error {
+    fn from(value: serde_yaml_ng::Error) -> Self {
+        Error::Yaml(value)
+    }
+}
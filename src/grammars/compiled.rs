@@ -1,20 +1,22 @@
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
 use std::ops::{Index, IndexMut};
 use std::sync::LazyLock;
 
 use serde::{Deserialize, Serialize};
 
+use crate::grammars::engine::{CompiledRegex, EngineRegex, captures_iter};
 use crate::grammars::injections::{CompiledInjectionMatcher, parse_injection_selector};
 use crate::grammars::raw::{Captures, RawGrammar, RawRule, Reference};
 use crate::grammars::regex::Regex;
-use crate::scope::Scope;
+use crate::scope::{Scope, ScopeRepository};
 
-static CAPTURING_NAME_RE: LazyLock<onig::Regex> =
-    LazyLock::new(|| onig::Regex::new(r"\$(\d+)|\${(\d+):\/(downcase|upcase)}").unwrap());
+static CAPTURING_NAME_RE: LazyLock<CompiledRegex> =
+    LazyLock::new(|| CompiledRegex::new(r"\$(\d+)|\$\{(\d+):\/(downcase|upcase)\}").unwrap());
 
 fn has_captures(pat: Option<&str>) -> bool {
     if let Some(p) = pat {
-        CAPTURING_NAME_RE.find(p).is_some()
+        captures_iter(&CAPTURING_NAME_RE, p).next().is_some()
     } else {
         false
     }
@@ -30,11 +32,15 @@ fn has_backreferences(pattern: &str) -> bool {
     false
 }
 
-fn scopes_from_name(name: &Option<String>, name_is_capturing: bool) -> Vec<Scope> {
+fn scopes_from_name(
+    name: &Option<String>,
+    name_is_capturing: bool,
+    scope_repo: &ScopeRepository,
+) -> Vec<Scope> {
     if name_is_capturing {
         Vec::new()
     } else if let Some(name_ref) = name.as_ref() {
-        Scope::new(name_ref)
+        scope_repo.parse_scopes(name_ref)
     } else {
         Vec::new()
     }
@@ -45,33 +51,47 @@ pub fn replace_captures(
     text: &str,
     captures_pos: &[Option<(usize, usize)>],
 ) -> String {
-    CAPTURING_NAME_RE
-        .replace_all(original_name, |caps: &onig::Captures| {
-            let capture_num = caps
-                .at(1)
-                .or_else(|| caps.at(2))
-                .unwrap_or("0")
-                .parse::<usize>()
-                .unwrap_or(0);
-            let command = caps.at(3);
-
-            if let Some(Some((start, end))) = captures_pos.get(capture_num) {
-                // Remove leading dots that would make the selector invalid
-                let result = text[*start..*end].trim_start_matches('.').to_string();
-                match command {
-                    Some("downcase") => result.to_lowercase(),
-                    Some("upcase") => result.to_uppercase(),
-                    _ => result,
-                }
-            } else if let Some(None) = captures_pos.get(capture_num) {
-                // Capture exists but didn't match (None capture), replace with empty string
-                String::new()
-            } else {
-                // Invalid capture bounds (index out of bounds), return original match
-                caps.at(0).unwrap().to_string()
+    let mut result = String::with_capacity(original_name.len());
+    let mut last_end = 0;
+
+    for caps in captures_iter(&CAPTURING_NAME_RE, original_name) {
+        let at = |i: usize| {
+            caps.get(i)
+                .copied()
+                .flatten()
+                .map(|(s, e)| &original_name[s..e])
+        };
+        let Some((match_start, match_end)) = caps[0] else {
+            continue;
+        };
+        result.push_str(&original_name[last_end..match_start]);
+        last_end = match_end;
+
+        let capture_num = at(1)
+            .or_else(|| at(2))
+            .unwrap_or("0")
+            .parse::<usize>()
+            .unwrap_or(0);
+        let command = at(3);
+
+        if let Some(Some((start, end))) = captures_pos.get(capture_num) {
+            // Remove leading dots that would make the selector invalid
+            let replacement = text[*start..*end].trim_start_matches('.');
+            match command {
+                Some("downcase") => result.push_str(&replacement.to_lowercase()),
+                Some("upcase") => result.push_str(&replacement.to_uppercase()),
+                _ => result.push_str(replacement),
             }
-        })
-        .to_string()
+        } else if let Some(None) = captures_pos.get(capture_num) {
+            // Capture exists but didn't match (None capture), replace with empty string
+        } else {
+            // Invalid capture bounds (index out of bounds), keep the original match
+            result.push_str(&original_name[match_start..match_end]);
+        }
+    }
+    result.push_str(&original_name[last_end..]);
+
+    result
 }
 
 fn process_scope_name(
@@ -326,6 +346,17 @@ impl Rule {
         !self.patterns().is_empty()
     }
 
+    /// The rules applied to the capture groups of all the regexes of this rule
+    pub(crate) fn captures(&self) -> impl Iterator<Item = GlobalRuleRef> + '_ {
+        let captures: [&[Option<GlobalRuleRef>]; 2] = match self {
+            Rule::Match(m) => [&m.captures, &[]],
+            Rule::BeginEnd(b) => [&b.begin_captures, &b.end_captures],
+            Rule::BeginWhile(b) => [&b.begin_captures, &b.while_captures],
+            Rule::IncludeOnly(_) | Rule::Noop => [&[], &[]],
+        };
+        captures.into_iter().flatten().flatten().copied()
+    }
+
     fn repository_stack(&self) -> RepositoryStack {
         match self {
             Rule::BeginEnd(b) => b.repository_stack,
@@ -356,51 +387,53 @@ impl Rule {
     }
 
     /// Get name scopes, either pre-compiled or computed from captures
-    pub fn get_name_scopes(
+    pub(crate) fn get_name_scopes(
         &self,
         input: &str,
         captures_pos: &[Option<(usize, usize)>],
-    ) -> Vec<Scope> {
+        scope_repo: &ScopeRepository,
+    ) -> Cow<'_, [Scope]> {
         let (name_is_capturing, scopes) = match self {
             Rule::Match(m) => (m.name_is_capturing, &m.scopes),
             Rule::IncludeOnly(i) => (i.name_is_capturing, &i.scopes),
             Rule::BeginEnd(b) => (b.name_is_capturing, &b.scopes),
             Rule::BeginWhile(bw) => (bw.name_is_capturing, &bw.scopes),
-            Rule::Noop => return Vec::new(),
+            Rule::Noop => return Cow::Borrowed(&[]),
         };
 
         if name_is_capturing {
             if let Some(name) = self.name(input, captures_pos) {
-                Scope::new(&name)
+                Cow::Owned(scope_repo.parse_dynamic_scopes(&name))
             } else {
-                Vec::new()
+                Cow::Borrowed(&[])
             }
         } else {
-            scopes.clone()
+            Cow::Borrowed(scopes)
         }
     }
 
     /// Get content scopes, either pre-compiled or computed from captures
-    pub fn get_content_scopes(
+    pub(crate) fn get_content_scopes(
         &self,
         input: &str,
         captures_pos: &[Option<(usize, usize)>],
-    ) -> Vec<Scope> {
+        scope_repo: &ScopeRepository,
+    ) -> Cow<'_, [Scope]> {
         let (content_name_is_capturing, content_scopes) = match self {
             Rule::IncludeOnly(i) => (i.content_name_is_capturing, &i.content_scopes),
             Rule::BeginEnd(b) => (b.content_name_is_capturing, &b.content_scopes),
             Rule::BeginWhile(bw) => (bw.content_name_is_capturing, &bw.content_scopes),
-            Rule::Match(_) | Rule::Noop => return Vec::new(),
+            Rule::Match(_) | Rule::Noop => return Cow::Borrowed(&[]),
         };
 
         if content_name_is_capturing {
             if let Some(content_name) = self.content_name(input, captures_pos) {
-                Scope::new(&content_name)
+                Cow::Owned(scope_repo.parse_dynamic_scopes(&content_name))
             } else {
-                Vec::new()
+                Cow::Borrowed(&[])
             }
         } else {
-            content_scopes.clone()
+            Cow::Borrowed(content_scopes)
         }
     }
 }
@@ -432,13 +465,17 @@ pub struct CompiledGrammar {
 }
 
 impl CompiledGrammar {
-    pub(crate) fn from_raw_grammar(raw: RawGrammar, id: GrammarId) -> Self {
+    pub(crate) fn from_raw_grammar(
+        raw: RawGrammar,
+        id: GrammarId,
+        scope_repo: &ScopeRepository,
+    ) -> Self {
         let mut grammar = Self {
             id,
             name: raw.name,
             display_name: raw.display_name,
             scope_name: raw.scope_name.clone(),
-            scope: Scope::new(&raw.scope_name)[0],
+            scope: scope_repo.parse(&raw.scope_name),
             file_types: raw.file_types,
             regexes: Vec::new(),
             rules: Vec::new(),
@@ -446,7 +483,7 @@ impl CompiledGrammar {
             injections: Vec::new(),
             injection_selector: raw
                 .injection_selector
-                .map(|x| parse_injection_selector(&x))
+                .map(|x| parse_injection_selector(&x, scope_repo))
                 .unwrap_or_default(),
             inject_to: raw.inject_to,
             references: Vec::new(),
@@ -462,7 +499,7 @@ impl CompiledGrammar {
 
         // Compile injections
         for (selector, raw_rule) in raw.injections {
-            let matchers = parse_injection_selector(&selector);
+            let matchers = parse_injection_selector(&selector, scope_repo);
             let mut repo_stack = RepositoryStack::default();
             if !grammar.repositories.is_empty() {
                 repo_stack = repo_stack.push(RepositoryId(0));
@@ -480,10 +517,42 @@ impl CompiledGrammar {
 
         // Resolve all Local references after compilation is complete
         grammar.resolve_local_references();
+        grammar.compile_scopes(scope_repo);
 
         grammar
     }
 
+    /// Parses the scopes of all the rules whose names don't depend on captures, those are
+    /// parsed when tokenizing.
+    fn compile_scopes(&mut self, scope_repo: &ScopeRepository) {
+        for rule in &mut self.rules {
+            match rule {
+                Rule::Match(m) => {
+                    m.scopes = scopes_from_name(&m.name, m.name_is_capturing, scope_repo);
+                }
+                Rule::IncludeOnly(i) => {
+                    i.scopes = scopes_from_name(&i.name, i.name_is_capturing, scope_repo);
+                    i.content_scopes =
+                        scopes_from_name(&i.content_name, i.content_name_is_capturing, scope_repo);
+                }
+                Rule::BeginEnd(b) => {
+                    b.scopes = scopes_from_name(&b.name, b.name_is_capturing, scope_repo);
+                    b.content_scopes =
+                        scopes_from_name(&b.content_name, b.content_name_is_capturing, scope_repo);
+                }
+                Rule::BeginWhile(bw) => {
+                    bw.scopes = scopes_from_name(&bw.name, bw.name_is_capturing, scope_repo);
+                    bw.content_scopes = scopes_from_name(
+                        &bw.content_name,
+                        bw.content_name_is_capturing,
+                        scope_repo,
+                    );
+                }
+                Rule::Noop => (),
+            }
+        }
+    }
+
     fn compile_rule(&mut self, raw_rule: RawRule, repository_stack: RepositoryStack) -> RuleId {
         let local_id = RuleId(self.rules.len() as u16);
         let global_id = GlobalRuleRef {
@@ -507,12 +576,12 @@ impl CompiledGrammar {
                 Rule::Noop
             } else {
                 let name_is_capturing = has_captures(name.as_deref());
-                let scopes = scopes_from_name(&name, name_is_capturing);
                 Rule::Match(Match {
                     id: global_id,
                     name_is_capturing,
                     name,
-                    scopes,
+                    // Set by `compile_scopes`
+                    scopes: Vec::new(),
                     regex_id: Some(self.compile_regex(pat).0),
                     captures: self.compile_captures(raw_rule.captures, repository_stack),
                     repository_stack,
@@ -526,16 +595,15 @@ impl CompiledGrammar {
                 let patterns = self.compile_patterns(local_id, raw_rule.patterns, repository_stack);
                 let name_is_capturing = has_captures(name.as_deref());
                 let content_name_is_capturing = has_captures(content_name.as_deref());
-                let scopes = scopes_from_name(&name, name_is_capturing);
-                let content_scopes = scopes_from_name(&content_name, content_name_is_capturing);
                 Rule::BeginWhile(BeginWhile {
                     id: global_id,
                     name_is_capturing,
                     name,
-                    scopes,
+                    // Set by `compile_scopes`
+                    scopes: Vec::new(),
                     content_name_is_capturing,
                     content_name,
-                    content_scopes,
+                    content_scopes: Vec::new(),
                     begin: self.compile_regex(begin_pat).0,
                     begin_captures: self.compile_captures(
                         // Some grammars use "captures" instead of "beginCaptures" for BeginEnd/BeginWhile rules
@@ -569,16 +637,15 @@ impl CompiledGrammar {
                 let patterns = self.compile_patterns(local_id, raw_rule.patterns, repository_stack);
                 let name_is_capturing = has_captures(name.as_deref());
                 let content_name_is_capturing = has_captures(content_name.as_deref());
-                let scopes = scopes_from_name(&name, name_is_capturing);
-                let content_scopes = scopes_from_name(&content_name, content_name_is_capturing);
                 Rule::BeginEnd(BeginEnd {
                     id: global_id,
                     name_is_capturing,
                     name,
-                    scopes,
+                    // Set by `compile_scopes`
+                    scopes: Vec::new(),
                     content_name_is_capturing,
                     content_name,
-                    content_scopes,
+                    content_scopes: Vec::new(),
                     begin: self.compile_regex(begin_pat).0,
                     begin_captures: self.compile_captures(
                         // Some grammars use "captures" instead of "beginCaptures" for BeginEnd/BeginWhile rules
@@ -617,12 +684,12 @@ impl CompiledGrammar {
                 // This is a scope-only rule - create a Match rule with no regex
                 // This handles captures that only assign scopes
                 let name_is_capturing = has_captures(name.as_deref());
-                let scopes = scopes_from_name(&name, name_is_capturing);
                 Rule::Match(Match {
                     id: global_id,
                     name_is_capturing,
                     name,
-                    scopes,
+                    // Set by `compile_scopes`
+                    scopes: Vec::new(),
                     regex_id: None, // Scope-only rule (e.g., capture that only assigns scope)
                     captures: vec![],
                     repository_stack,
@@ -652,26 +719,16 @@ impl CompiledGrammar {
                         self.compile_patterns(local_id, patterns, repository_stack);
                     let name_is_capturing = has_captures(name.as_deref());
                     let content_name_is_capturing = has_captures(raw_rule.content_name.as_deref());
-                    let scopes = if !name_is_capturing {
-                        if let Some(name_ref) = name.as_ref() {
-                            Scope::new(name_ref)
-                        } else {
-                            Vec::new()
-                        }
-                    } else {
-                        Vec::new()
-                    };
-                    let content_scopes =
-                        scopes_from_name(&raw_rule.content_name, content_name_is_capturing);
 
                     Rule::IncludeOnly(IncludeOnly {
                         id: global_id,
                         name_is_capturing,
                         name,
-                        scopes,
+                        // Set by `compile_scopes`
+                        scopes: Vec::new(),
                         content_name_is_capturing,
                         content_name: raw_rule.content_name,
-                        content_scopes,
+                        content_scopes: Vec::new(),
                         repository_stack,
                         patterns: compiled_patterns,
                     })
@@ -877,7 +934,6 @@ impl CompiledGrammar {
         out
     }
 
-    #[cfg(feature = "debug")]
     pub(crate) fn get_original_rule_name(&self, rule_id: RuleId) -> Option<&str> {
         self.rules[rule_id.as_index()].original_name()
     }
@@ -1000,6 +1056,7 @@ mod tests {
     use super::{has_captures, replace_captures};
     use crate::grammars::raw::RawGrammar;
     use crate::grammars::{CompiledGrammar, GrammarId};
+    use crate::scope::ScopeRepository;
     use std::fs;
 
     #[test]
@@ -1134,7 +1191,11 @@ mod tests {
             let raw_grammar = RawGrammar::load_from_file(&path).unwrap();
 
             println!(">> {path:#?}");
-            let _ = CompiledGrammar::from_raw_grammar(raw_grammar, GrammarId(0));
+            let _ = CompiledGrammar::from_raw_grammar(
+                raw_grammar,
+                GrammarId(0),
+                &ScopeRepository::default(),
+            );
         }
     }
 }
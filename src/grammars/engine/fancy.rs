@@ -0,0 +1,259 @@
+use std::sync::OnceLock;
+
+use fancy_regex::RegexBuilder;
+
+use crate::grammars::engine::{CapturePositions, EngineRegSet, EngineRegex};
+use crate::tokenizer::AnchorActive;
+
+/// A pattern that can never match, used to disable \A and \G like vscode-textmate does
+const NEVER_MATCH: &str = r"[^\s\S]";
+
+/// Translates the Oniguruma syntax used by grammars to the fancy-regex one, when they differ.
+fn translate_pattern(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    let mut class_depth = 0usize;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('h') if class_depth > 0 => out.push_str("0-9a-fA-F"),
+                Some('h') => out.push_str("[0-9a-fA-F]"),
+                Some('H') if class_depth == 0 => out.push_str("[^0-9a-fA-F]"),
+                // End of string or before a final newline
+                Some('Z') if class_depth == 0 => out.push_str(r"(?=\n?\z)"),
+                Some(next) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            },
+            '[' => {
+                class_depth += 1;
+                out.push(c);
+                // A `]` right at the start of a class is a literal
+                if chars.peek() == Some(&'^') {
+                    out.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    out.push_str(r"\]");
+                    chars.next();
+                }
+            }
+            ']' if class_depth > 0 => {
+                class_depth -= 1;
+                out.push(c);
+            }
+            // In Oniguruma `(?m)` is what `(?s)` is everywhere else: `^` and `$` always
+            // match at line boundaries
+            '(' if class_depth == 0 && chars.peek() == Some(&'?') => {
+                out.push(c);
+                out.push(chars.next().unwrap());
+                let flags: String = chars
+                    .clone()
+                    .take_while(|c| matches!(c, 'i' | 'm' | 'x' | '-'))
+                    .collect();
+                let after_flags = chars.clone().nth(flags.len());
+                if !flags.is_empty() && matches!(after_flags, Some(':' | ')')) {
+                    out.push_str(&flags.replace('m', "s"));
+                    for _ in 0..flags.len() {
+                        chars.next();
+                    }
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Replaces \A and \G with a pattern that never matches if they are not active
+fn disable_anchors(pattern: &str, anchors: AnchorActive) -> String {
+    let (a_active, g_active) = match anchors {
+        AnchorActive::AG => (true, true),
+        AnchorActive::A => (true, false),
+        AnchorActive::G => (false, true),
+        AnchorActive::None => (false, false),
+    };
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('A') if !a_active => out.push_str(NEVER_MATCH),
+            Some('G') if !g_active => out.push_str(NEVER_MATCH),
+            Some(next) => {
+                out.push('\\');
+                out.push(next);
+            }
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+fn compile(pattern: &str) -> Result<fancy_regex::Regex, String> {
+    RegexBuilder::new(pattern)
+        .multi_line(true)
+        .oniguruma_mode(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn anchor_index(anchors: AnchorActive) -> usize {
+    match anchors {
+        AnchorActive::AG => 0,
+        AnchorActive::A => 1,
+        AnchorActive::G => 2,
+        AnchorActive::None => 3,
+    }
+}
+
+/// fancy-regex doesn't have search options to disable \A and \G so we compile one version of
+/// the pattern per combination of active anchors, when it is first needed.
+pub(crate) struct FancyRegex {
+    /// The pattern translated to fancy-regex syntax, with its anchors
+    pattern: String,
+    has_anchors: bool,
+    /// Indexed by `anchor_index`, the first one has all the anchors active and is always compiled
+    variants: [OnceLock<Option<fancy_regex::Regex>>; 4],
+}
+
+impl FancyRegex {
+    fn variant(&self, anchors: AnchorActive) -> Option<&fancy_regex::Regex> {
+        let index = if self.has_anchors {
+            anchor_index(anchors)
+        } else {
+            0
+        };
+        self.variants[index]
+            .get_or_init(|| compile(&disable_anchors(&self.pattern, anchors)).ok())
+            .as_ref()
+    }
+}
+
+impl EngineRegex for FancyRegex {
+    fn new(pattern: &str) -> Result<Self, String> {
+        let pattern = translate_pattern(pattern);
+        let full = compile(&pattern)?;
+        let variants: [OnceLock<Option<fancy_regex::Regex>>; 4] = Default::default();
+        variants[0].set(Some(full)).unwrap();
+
+        Ok(Self {
+            has_anchors: pattern.contains(r"\A") || pattern.contains(r"\G"),
+            pattern,
+            variants,
+        })
+    }
+
+    fn search(&self, text: &str, pos: usize, anchors: AnchorActive) -> Option<CapturePositions> {
+        // Errors are only about hitting the backtracking limit, we treat it as no match
+        let captures = self.variant(anchors)?.captures_from_pos(text, pos).ok()??;
+        Some(
+            (0..captures.len())
+                .map(|i| captures.get(i).map(|m| (m.start(), m.end())))
+                .collect(),
+        )
+    }
+}
+
+/// fancy-regex has no regset so we search every pattern and keep the leftmost match.
+/// Patterns fancy-regex can't handle are `None` and never match, so one unsupported construct
+/// doesn't disable the rest of the set.
+pub(crate) struct FancyRegSet(Vec<Option<FancyRegex>>);
+
+impl EngineRegSet for FancyRegSet {
+    fn new(patterns: &[&str]) -> Result<Self, String> {
+        Ok(Self(
+            patterns.iter().map(|p| FancyRegex::new(p).ok()).collect(),
+        ))
+    }
+
+    fn search(
+        &self,
+        text: &str,
+        pos: usize,
+        anchors: AnchorActive,
+    ) -> Result<Option<(usize, CapturePositions)>, String> {
+        let mut best: Option<(usize, CapturePositions)> = None;
+
+        for (index, regex) in self.0.iter().enumerate() {
+            let Some(regex) = regex else {
+                continue;
+            };
+            let Some(captures) = regex.search(text, pos, anchors) else {
+                continue;
+            };
+            let Some((start, _)) = captures[0] else {
+                continue;
+            };
+            let is_better = match &best {
+                Some((_, best_captures)) => best_captures[0].is_some_and(|(s, _)| start < s),
+                None => true,
+            };
+            if is_better {
+                best = Some((index, captures));
+                // Nothing can match before the start position
+                if start == pos {
+                    break;
+                }
+            }
+        }
+
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_translate_oniguruma_syntax() {
+        let test_cases = [
+            (r"\h+", r"[0-9a-fA-F]+"),
+            (r"[\h_]", r"[0-9a-fA-F_]"),
+            (r"\H", r"[^0-9a-fA-F]"),
+            (r"a\Z", r"a(?=\n?\z)"),
+            (r"(?m).", r"(?s)."),
+            (r"(?i-m:a)", r"(?i-s:a)"),
+            (r"(?<name>m)", r"(?<name>m)"),
+            (r"[]m]", r"[\]m]"),
+            (r"\\h", r"\\h"),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(translate_pattern(input), expected, "input: {input}");
+        }
+    }
+
+    #[test]
+    fn can_disable_anchors() {
+        assert_eq!(
+            disable_anchors(r"\A\G\\G", AnchorActive::None),
+            format!(r"{NEVER_MATCH}{NEVER_MATCH}\\G")
+        );
+        assert_eq!(
+            disable_anchors(r"\A\G", AnchorActive::G),
+            format!(r"{NEVER_MATCH}\G")
+        );
+    }
+
+    #[test]
+    fn regset_skips_unsupported_patterns() {
+        // Extended grapheme clusters are not supported by fancy-regex
+        assert!(FancyRegex::new(r"a\X").is_err());
+        let set = FancyRegSet::new(&[r"a\X", "b+", "c"]).unwrap();
+        let (index, captures) = set.search("abbc", 0, AnchorActive::None).unwrap().unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(captures[0], Some((1, 3)));
+        let (index, _) = set.search("abbc", 3, AnchorActive::None).unwrap().unwrap();
+        assert_eq!(index, 2);
+    }
+}
@@ -0,0 +1,134 @@
+//! The regex engines that can run the grammar patterns.
+//!
+//! Everything outside this module only uses `CompiledRegex` and `CompiledRegSet`, which are the
+//! types of the engine selected by the cargo features: Oniguruma with the `oniguruma` feature,
+//! the default, or fancy-regex with the `fancy-regex` feature.
+//! Oniguruma wins if both features are enabled.
+
+#[cfg(all(feature = "fancy-regex", not(feature = "oniguruma")))]
+mod fancy;
+#[cfg(feature = "oniguruma")]
+mod oniguruma;
+
+use crate::tokenizer::AnchorActive;
+
+#[cfg(not(any(feature = "oniguruma", feature = "fancy-regex")))]
+compile_error!(
+    "giallo needs a regex engine: enable either the `oniguruma` or `fancy-regex` feature"
+);
+
+#[cfg(all(feature = "fancy-regex", not(feature = "oniguruma")))]
+pub(crate) use fancy::{FancyRegSet as CompiledRegSet, FancyRegex as CompiledRegex};
+#[cfg(all(test, feature = "oniguruma"))]
+pub(crate) use oniguruma::COMPILED_REGSETS;
+#[cfg(feature = "oniguruma")]
+pub(crate) use oniguruma::{OnigRegSet as CompiledRegSet, OnigRegex as CompiledRegex};
+
+/// The absolute positions of the capture groups of a match, the first one being the whole match.
+pub(crate) type CapturePositions = Vec<Option<(usize, usize)>>;
+
+/// A single compiled pattern
+pub(crate) trait EngineRegex: Sized + Send + Sync {
+    fn new(pattern: &str) -> Result<Self, String>;
+
+    /// Finds the first match starting at or after `pos`.
+    /// The text before `pos` is still visible to lookbehinds and `\G` matches at `pos` if active.
+    fn search(&self, text: &str, pos: usize, anchors: AnchorActive) -> Option<CapturePositions>;
+}
+
+/// A set of patterns searched at the same time
+pub(crate) trait EngineRegSet: Sized + Send + Sync {
+    fn new(patterns: &[&str]) -> Result<Self, String>;
+
+    /// Finds the leftmost match of all the patterns, the first pattern winning if several
+    /// match at the same position. Returns the index of the pattern that matched.
+    /// Errors if the engine had to compile the patterns again for this search and failed.
+    fn search(
+        &self,
+        text: &str,
+        pos: usize,
+        anchors: AnchorActive,
+    ) -> Result<Option<(usize, CapturePositions)>, String>;
+}
+
+/// Iterates over the non-overlapping matches of the regex in `text`.
+/// Only used for our own internal regexes, which never match an empty string.
+pub(crate) fn captures_iter<'a>(
+    regex: &'a CompiledRegex,
+    text: &'a str,
+) -> impl Iterator<Item = CapturePositions> + 'a {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let captures = regex.search(text, pos, AnchorActive::AG)?;
+        let (_, end) = captures[0]?;
+        pos = if end > pos {
+            end
+        } else {
+            // Should not happen but let's not loop forever if it does
+            pos + text[pos..].chars().next()?.len_utf8()
+        };
+        Some(captures)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchors_are_only_active_when_asked() {
+        let re = CompiledRegex::new(r"\Gb").unwrap();
+        assert_eq!(
+            re.search("abb", 1, AnchorActive::G).unwrap()[0],
+            Some((1, 2))
+        );
+        assert!(re.search("abb", 1, AnchorActive::None).is_none());
+        // \G only matches where the search starts
+        assert!(re.search("ab", 0, AnchorActive::AG).is_none());
+
+        let re = CompiledRegex::new(r"\Aa|b").unwrap();
+        assert_eq!(
+            re.search("ab", 0, AnchorActive::A).unwrap()[0],
+            Some((0, 1))
+        );
+        assert_eq!(
+            re.search("ab", 0, AnchorActive::G).unwrap()[0],
+            Some((1, 2))
+        );
+    }
+
+    #[test]
+    fn uses_oniguruma_syntax() {
+        // ^ and $ match at line boundaries, (?m) makes `.` match newlines
+        let re = CompiledRegex::new(r"^b$").unwrap();
+        assert_eq!(
+            re.search("a\nb\n", 0, AnchorActive::None).unwrap()[0],
+            Some((2, 3))
+        );
+        let re = CompiledRegex::new(r"(?m:a.b)").unwrap();
+        assert_eq!(
+            re.search("a\nb", 0, AnchorActive::None).unwrap()[0],
+            Some((0, 3))
+        );
+        let re = CompiledRegex::new(r"\h+").unwrap();
+        assert_eq!(
+            re.search("xx0fAz", 0, AnchorActive::None).unwrap()[0],
+            Some((2, 5))
+        );
+        // Lookbehinds can see the text before the start position
+        let re = CompiledRegex::new(r"(?<=a)(b)").unwrap();
+        assert_eq!(
+            re.search("ab", 1, AnchorActive::None).unwrap(),
+            vec![Some((1, 2)), Some((1, 2))]
+        );
+    }
+
+    #[test]
+    fn regset_returns_leftmost_then_first_pattern() {
+        let set = CompiledRegSet::new(&["c", "b+", "bb"]).unwrap();
+        let (index, captures) = set.search("abbc", 0, AnchorActive::None).unwrap().unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(captures[0], Some((1, 3)));
+        assert!(set.search("abbc", 4, AnchorActive::None).unwrap().is_none());
+    }
+}
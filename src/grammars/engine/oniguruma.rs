@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use onig::{RegSet, RegSetLead, RegexOptions, Region, SearchOptions, Syntax};
+
+use crate::grammars::engine::{CapturePositions, EngineRegSet, EngineRegex};
+use crate::tokenizer::AnchorActive;
+
+thread_local! {
+    /// Reused by every search on a thread so we don't allocate a region each time
+    static REGION: RefCell<Region> = RefCell::new(Region::new());
+}
+
+/// Oniguruma can disable \A and \G with search options, no need to rewrite the patterns
+fn search_options(anchors: AnchorActive) -> SearchOptions {
+    match anchors {
+        AnchorActive::AG => SearchOptions::SEARCH_OPTION_NONE,
+        AnchorActive::A => SearchOptions::SEARCH_OPTION_NOT_BEGIN_POSITION,
+        AnchorActive::G => SearchOptions::SEARCH_OPTION_NOT_BEGIN_STRING,
+        AnchorActive::None => {
+            SearchOptions::SEARCH_OPTION_NOT_BEGIN_STRING
+                | SearchOptions::SEARCH_OPTION_NOT_BEGIN_POSITION
+        }
+    }
+}
+
+pub(crate) struct OnigRegex(onig::Regex);
+
+impl EngineRegex for OnigRegex {
+    fn new(pattern: &str) -> Result<Self, String> {
+        onig::Regex::with_options(
+            pattern,
+            RegexOptions::REGEX_OPTION_CAPTURE_GROUP,
+            Syntax::default(),
+        )
+        .map(Self)
+        .map_err(|e| e.to_string())
+    }
+
+    fn search(&self, text: &str, pos: usize, anchors: AnchorActive) -> Option<CapturePositions> {
+        REGION.with_borrow_mut(|region| {
+            self.0.search_with_options(
+                text,
+                pos,
+                text.len(),
+                search_options(anchors),
+                Some(region),
+            )?;
+            Some((0..region.len()).map(|i| region.pos(i)).collect())
+        })
+    }
+}
+
+/// `onig_regset_search` writes to internal region storage, making concurrent searches on the
+/// same RegSet unsafe. Instead of locking the RegSet for the whole search, each search takes a
+/// compiled RegSet out of a pool shared by all the threads and puts it back afterwards.
+/// The pool has one slot per core and threads start looking at different slots, only trying to
+/// lock them so they never wait on each other: a new RegSet is only compiled when every copy is
+/// in use by other threads, and it's dropped after the search if there is no free slot for it.
+pub(crate) struct OnigRegSet {
+    patterns: Vec<String>,
+    pool: Box<[Mutex<Option<RegSet>>]>,
+}
+
+static NEXT_POOL_START: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Where this thread starts looking in the pools
+    static POOL_START: usize = NEXT_POOL_START.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+thread_local! {
+    /// How many RegSets were compiled on this thread
+    pub(crate) static COMPILED_REGSETS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn pool_size() -> usize {
+    static POOL_SIZE: OnceLock<usize> = OnceLock::new();
+    *POOL_SIZE.get_or_init(|| std::thread::available_parallelism().map_or(4, |n| n.get()))
+}
+
+impl OnigRegSet {
+    fn compile(patterns: &[&str]) -> Result<RegSet, String> {
+        #[cfg(test)]
+        COMPILED_REGSETS.set(COMPILED_REGSETS.get() + 1);
+        RegSet::with_options(patterns, RegexOptions::REGEX_OPTION_CAPTURE_GROUP)
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    /// The slots of the pool in the order this thread tries them
+    fn slots(&self) -> impl Iterator<Item = &Mutex<Option<RegSet>>> {
+        let start = POOL_START.with(|start| *start);
+        (0..self.pool.len()).map(move |i| &self.pool[(start + i) % self.pool.len()])
+    }
+
+    fn take(&self) -> Result<RegSet, String> {
+        for slot in self.slots() {
+            if let Ok(mut slot) = slot.try_lock()
+                && let Some(regset) = slot.take()
+            {
+                return Ok(regset);
+            }
+        }
+        let patterns: Vec<&str> = self.patterns.iter().map(String::as_str).collect();
+        Self::compile(&patterns)
+    }
+
+    fn put_back(&self, regset: RegSet) {
+        for slot in self.slots() {
+            if let Ok(mut slot) = slot.try_lock()
+                && slot.is_none()
+            {
+                *slot = Some(regset);
+                return;
+            }
+        }
+    }
+}
+
+impl EngineRegSet for OnigRegSet {
+    fn new(patterns: &[&str]) -> Result<Self, String> {
+        let regset = Self::compile(patterns)?;
+        let pool: Box<[_]> = (0..pool_size()).map(|_| Mutex::new(None)).collect();
+        *pool[0].lock().unwrap() = Some(regset);
+        Ok(Self {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            pool,
+        })
+    }
+
+    fn search(
+        &self,
+        text: &str,
+        pos: usize,
+        anchors: AnchorActive,
+    ) -> Result<Option<(usize, CapturePositions)>, String> {
+        let regset = self.take()?;
+
+        // We need to specify pos/text.len() because some regex might do lookbehind
+        let res = regset
+            .captures_with_options(
+                text,
+                pos,
+                text.len(),
+                RegSetLead::Position,
+                search_options(anchors),
+            )
+            // Positions are already absolute
+            .map(|(pattern_index, captures)| {
+                let capture_pos = (0..captures.len()).map(|i| captures.pos(i)).collect();
+                (pattern_index, capture_pos)
+            });
+
+        self.put_back(regset);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regsets_are_shared_by_threads() {
+        let set = OnigRegSet::new(&["b+"]).unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        let (index, captures) =
+                            set.search("abb", 0, AnchorActive::None).unwrap().unwrap();
+                        assert_eq!((index, captures[0]), (0, Some((1, 3))));
+                    }
+                });
+            }
+        });
+        // Copies compiled by threads searching at the same time are kept for the next ones
+        let num_compiled = set
+            .pool
+            .iter()
+            .filter(|s| s.lock().unwrap().is_some())
+            .count();
+        assert!((1..=pool_size()).contains(&num_compiled));
+
+        // A new thread reuses them
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                set.search("abb", 0, AnchorActive::None).unwrap();
+                assert_eq!(COMPILED_REGSETS.get(), 0);
+            });
+        });
+    }
+}
@@ -1,13 +1,13 @@
-use std::fmt;
 use std::sync::LazyLock;
 
 use serde::{Deserialize, Serialize};
 
-use crate::scope::Scope;
+use crate::grammars::engine::{CompiledRegex, EngineRegex, captures_iter};
+use crate::scope::{Scope, ScopeRepository};
 
 /// Regex for tokenizing injection selectors (matches vscode-textmate exactly except for \* added)
-static TOKEN_REGEX: LazyLock<onig::Regex> = LazyLock::new(|| {
-    onig::Regex::new(r"([LR]:|[\w.:]+[\w\*.:\-]*|[,|\-()])").expect("Invalid selector regex")
+static TOKEN_REGEX: LazyLock<CompiledRegex> = LazyLock::new(|| {
+    CompiledRegex::new(r"([LR]:|[\w.:]+[\w\*.:\-]*|[,|\-()])").expect("Invalid selector regex")
 });
 
 // Only Left matters, Right is the same as no precedence. We keep both just for debug reasons
@@ -20,7 +20,7 @@ pub enum InjectionPrecedence {
 }
 
 /// A compiled injection selector matcher with priority
-#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CompiledInjectionMatcher {
     matcher: SelectorMatcher,
     priority: Option<InjectionPrecedence>,
@@ -37,16 +37,16 @@ impl CompiledInjectionMatcher {
     pub fn precedence(&self) -> InjectionPrecedence {
         self.priority.unwrap_or(InjectionPrecedence::Right)
     }
-}
 
-impl fmt::Debug for CompiledInjectionMatcher {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let selector = match self.priority {
-            Some(InjectionPrecedence::Left) => format!("L:{}", self.matcher),
-            Some(InjectionPrecedence::Right) => format!("R:{}", self.matcher),
-            None => self.matcher.to_string(),
-        };
-        write!(f, "\"{}\"", selector)
+    /// The selector in string form, resolving the scopes with the given repository
+    #[cfg(test)]
+    fn as_string(&self, scope_repo: &ScopeRepository) -> String {
+        let matcher = self.matcher.as_string(scope_repo);
+        match self.priority {
+            Some(InjectionPrecedence::Left) => format!("L:{matcher}"),
+            Some(InjectionPrecedence::Right) => format!("R:{matcher}"),
+            None => matcher,
+        }
     }
 }
 
@@ -69,7 +69,7 @@ impl SelectorMatcher {
                 // Single scope: check if ANY scope in stack is a match
                 scope_stack
                     .iter()
-                    .any(|&stack_scope| scope.is_prefix_of(stack_scope))
+                    .any(|stack_scope| scope.is_prefix_of(stack_scope))
             }
             SelectorMatcher::And(matchers) => {
                 // Sequential matching: each matcher must find a match at or after the previous match
@@ -81,7 +81,7 @@ impl SelectorMatcher {
                             let mut found = false;
                             for (i, scope_item) in scope_stack.iter().enumerate().skip(start_index)
                             {
-                                if scope.is_prefix_of(*scope_item) {
+                                if scope.is_prefix_of(scope_item) {
                                     start_index = i + 1;
                                     found = true;
                                     break;
@@ -112,28 +112,27 @@ impl SelectorMatcher {
             }
         }
     }
-}
 
-impl fmt::Display for SelectorMatcher {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    /// The matcher in string form, resolving the scopes with the given repository
+    #[cfg(test)]
+    fn as_string(&self, scope_repo: &ScopeRepository) -> String {
         match self {
-            SelectorMatcher::Scope(scope) => write!(f, "{}", scope),
+            SelectorMatcher::Scope(scope) => scope_repo.to_string(scope),
             SelectorMatcher::And(matchers) => {
-                let parts: Vec<String> = matchers.iter().map(|m| m.to_string()).collect();
-                write!(f, "{}", parts.join(" "))
+                let parts: Vec<String> = matchers.iter().map(|m| m.as_string(scope_repo)).collect();
+                parts.join(" ")
             }
             SelectorMatcher::Or(matchers) => {
                 if matchers.len() == 1 {
                     // If there's only one item in Or, don't wrap in parentheses
-                    write!(f, "{}", matchers[0])
+                    matchers[0].as_string(scope_repo)
                 } else {
-                    let parts: Vec<String> = matchers.iter().map(|m| m.to_string()).collect();
-                    write!(f, "({})", parts.join(" | "))
+                    let parts: Vec<String> =
+                        matchers.iter().map(|m| m.as_string(scope_repo)).collect();
+                    format!("({})", parts.join(" | "))
                 }
             }
-            SelectorMatcher::Not(matcher) => {
-                write!(f, "-{}", matcher)
-            }
+            SelectorMatcher::Not(matcher) => format!("-{}", matcher.as_string(scope_repo)),
         }
     }
 }
@@ -149,9 +148,13 @@ fn is_identifier(s: &str) -> bool {
     })
 }
 
-fn parse_inner_expression(tokens: &[&str], position: &mut usize) -> SelectorMatcher {
+fn parse_inner_expression(
+    tokens: &[&str],
+    position: &mut usize,
+    scope_repo: &ScopeRepository,
+) -> SelectorMatcher {
     let mut out = Vec::new();
-    while let Some(m) = parse_conjunction(tokens, position) {
+    while let Some(m) = parse_conjunction(tokens, position, scope_repo) {
         out.push(m);
         if *position < tokens.len() && matches!(tokens[*position], "|" | ",") {
             *position += 1;
@@ -174,7 +177,11 @@ fn parse_inner_expression(tokens: &[&str], position: &mut usize) -> SelectorMatc
     }
 }
 
-fn parse_operand(tokens: &[&str], position: &mut usize) -> Option<SelectorMatcher> {
+fn parse_operand(
+    tokens: &[&str],
+    position: &mut usize,
+    scope_repo: &ScopeRepository,
+) -> Option<SelectorMatcher> {
     if *position >= tokens.len() {
         return None;
     }
@@ -182,12 +189,12 @@ fn parse_operand(tokens: &[&str], position: &mut usize) -> Option<SelectorMatche
     match tokens[*position] {
         "-" => {
             *position += 1;
-            let negated = parse_operand(tokens, position)?;
+            let negated = parse_operand(tokens, position, scope_repo)?;
             Some(SelectorMatcher::Not(Box::new(negated)))
         }
         "(" => {
             *position += 1;
-            let inner = parse_inner_expression(tokens, position);
+            let inner = parse_inner_expression(tokens, position, scope_repo);
             if *position < tokens.len() && tokens[*position] == ")" {
                 *position += 1;
             }
@@ -200,9 +207,9 @@ fn parse_operand(tokens: &[&str], position: &mut usize) -> Option<SelectorMatche
                 let token = tokens[*position];
                 let scope = if let Some(pos) = token.find(".*") {
                     let base = &token[..pos];
-                    Scope::new(base.trim_end_matches("."))[0]
+                    scope_repo.parse(base.trim_end_matches("."))
                 } else {
-                    Scope::new(token)[0]
+                    scope_repo.parse(token)
                 };
 
                 if !scopes.contains(&scope) {
@@ -222,10 +229,14 @@ fn parse_operand(tokens: &[&str], position: &mut usize) -> Option<SelectorMatche
     }
 }
 
-fn parse_conjunction(tokens: &[&str], position: &mut usize) -> Option<SelectorMatcher> {
+fn parse_conjunction(
+    tokens: &[&str],
+    position: &mut usize,
+    scope_repo: &ScopeRepository,
+) -> Option<SelectorMatcher> {
     let mut matchers = Vec::new();
 
-    while let Some(m) = parse_operand(tokens, position) {
+    while let Some(m) = parse_operand(tokens, position, scope_repo) {
         matchers.push(m);
     }
 
@@ -238,14 +249,17 @@ fn parse_conjunction(tokens: &[&str], position: &mut usize) -> Option<SelectorMa
 
 /// Parse injection selector string into compiled matchers.
 /// A selector can correspond to multiple matcher, each with their own optional priority
-pub fn parse_injection_selector(selector: &str) -> Vec<CompiledInjectionMatcher> {
+pub fn parse_injection_selector(
+    selector: &str,
+    scope_repo: &ScopeRepository,
+) -> Vec<CompiledInjectionMatcher> {
     let selector = selector.trim();
     if selector.is_empty() {
         return Vec::new();
     }
 
-    let tokens: Vec<_> = TOKEN_REGEX
-        .find_iter(selector)
+    let tokens: Vec<_> = captures_iter(&TOKEN_REGEX, selector)
+        .filter_map(|captures| captures[0])
         .map(|(start, end)| &selector[start..end])
         .filter(|s| !s.is_empty())
         .collect();
@@ -271,7 +285,7 @@ pub fn parse_injection_selector(selector: &str) -> Vec<CompiledInjectionMatcher>
             _ => (),
         };
 
-        if let Some(matcher) = parse_conjunction(&tokens, &mut position) {
+        if let Some(matcher) = parse_conjunction(&tokens, &mut position, scope_repo) {
             res.push(CompiledInjectionMatcher { matcher, priority });
             priority = None;
             if position < tokens.len() && tokens[position] == "," {
@@ -314,8 +328,12 @@ mod tests {
             "L:source.js -comment -string, L:source.js -comment -string, L:source.jsx -comment -string,  L:source.js.jsx -comment -string, L:source.ts -comment -string, L:source.tsx -comment -string, L:source.rescript -comment -string, L:source.vue -comment -string, L:source.svelte -comment -string, L:source.php -comment -string, L:source.rescript -comment -string",
         ];
 
+        let scope_repo = ScopeRepository::default();
         for (i, test_case) in test_cases.into_iter().enumerate() {
-            let result = parse_injection_selector(test_case);
+            let result: Vec<String> = parse_injection_selector(test_case, &scope_repo)
+                .iter()
+                .map(|m| m.as_string(&scope_repo))
+                .collect();
             with_settings!({description => test_case}, {
                 assert_debug_snapshot!(format!("injection_{i}"), result);
             })
@@ -429,14 +447,28 @@ mod tests {
                 vec!["meta.decorator.ts", "text.html"],
                 false,
             ),
+            // === Scopes longer than 8 atoms ===
+            (
+                "L:text.html.markdown.a.b.c.d.e.f -comment",
+                vec!["text.html.markdown.a.b.c.d.e.f.g"],
+                true,
+            ),
+            (
+                "L:text.html.markdown.a.b.c.d.e.x",
+                vec!["text.html.markdown.a.b.c.d.e.f.g"],
+                false,
+            ),
             // === Edge Cases ===
             ("text.html", vec![], false), // empty scope stack
         ];
 
+        let scope_repo = ScopeRepository::default();
         for (selector_str, scope_names, expected) in test_cases {
-            let matchers = parse_injection_selector(selector_str);
-            let scope_stack: Vec<Scope> =
-                scope_names.iter().map(|name| Scope::new(name)[0]).collect();
+            let matchers = parse_injection_selector(selector_str, &scope_repo);
+            let scope_stack: Vec<Scope> = scope_names
+                .iter()
+                .map(|name| scope_repo.parse(name))
+                .collect();
 
             println!("{selector_str} {matchers:?}");
 
@@ -1,4 +1,5 @@
 mod compiled;
+pub(crate) mod engine;
 mod injections;
 mod pattern_set;
 mod raw;
@@ -7,5 +8,5 @@ mod regex;
 pub use compiled::*;
 pub use injections::InjectionPrecedence;
 pub use pattern_set::{PatternSet, PatternSetMatch};
-pub use raw::RawGrammar;
+pub use raw::{GrammarFormat, RawGrammar};
 pub use regex::{Regex, resolve_backreferences};
@@ -1,11 +1,121 @@
 use std::collections::BTreeMap;
-use std::fs::File;
+use std::io::Read;
 use std::ops::Deref;
 use std::path::Path;
 
 use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::error::GialloResult;
+use crate::error::{Error, GialloResult};
+use crate::jsonc;
+
+/// The formats TextMate grammars are distributed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GrammarFormat {
+    /// JSON, eg `.tmLanguage.json`
+    Json,
+    /// XML plist, eg `.tmLanguage`. Requires the `plist` feature.
+    Plist,
+    /// YAML, eg `.tmLanguage.yaml`. Requires the `yaml` feature.
+    Yaml,
+}
+
+impl GrammarFormat {
+    /// Guesses the format from the extension of the path, if it's a known one
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(Self::Json),
+            "tmlanguage" | "plist" | "xml" => Some(Self::Plist),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the content: JSON starts with `{` or a comment, XML with `<`
+    /// and anything else is assumed to be YAML.
+    pub fn detect(content: &str) -> Self {
+        match content
+            .trim_start_matches('\u{feff}')
+            .trim_start()
+            .chars()
+            .next()
+        {
+            // JSONC can start with a `//` or `/*` comment, YAML comments start with `#`
+            Some('{' | '/') => Self::Json,
+            Some('<') => Self::Plist,
+            _ => Self::Yaml,
+        }
+    }
+}
+
+/// Converts a plist value to the equivalent JSON value so it goes through the exact same
+/// deserialization as JSON grammars
+#[cfg(feature = "plist")]
+fn plist_to_json(value: plist::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value {
+        plist::Value::Dictionary(dict) => Value::Object(
+            dict.into_iter()
+                .map(|(k, v)| (k, plist_to_json(v)))
+                .collect(),
+        ),
+        plist::Value::Array(array) => Value::Array(array.into_iter().map(plist_to_json).collect()),
+        plist::Value::Boolean(b) => Value::Bool(b),
+        plist::Value::Integer(i) => i
+            .as_signed()
+            .map(Value::from)
+            .or_else(|| i.as_unsigned().map(Value::from))
+            .unwrap_or(Value::Null),
+        plist::Value::Real(f) => Value::from(f),
+        plist::Value::String(s) => Value::String(s),
+        plist::Value::Date(d) => Value::String(d.to_xml_format()),
+        // Not used in grammars
+        _ => Value::Null,
+    }
+}
+
+/// Converts a YAML value to the equivalent JSON value so it goes through the exact same
+/// deserialization as JSON grammars.
+/// Keys are converted to strings since YAML grammars often have unquoted numbers as capture keys.
+#[cfg(feature = "yaml")]
+fn yaml_to_json(value: serde_yaml_ng::Value) -> serde_json::Value {
+    use serde_json::Value;
+    use serde_yaml_ng::Value as Yaml;
+
+    fn key_to_string(key: Yaml) -> String {
+        match key {
+            Yaml::String(s) => s,
+            Yaml::Number(n) => n.to_string(),
+            Yaml::Bool(b) => b.to_string(),
+            Yaml::Null => String::new(),
+            other => serde_yaml_ng::to_string(&other).unwrap_or_default(),
+        }
+    }
+
+    match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Value::from(i)
+            } else if let Some(u) = n.as_u64() {
+                Value::from(u)
+            } else {
+                n.as_f64().map(Value::from).unwrap_or(Value::Null)
+            }
+        }
+        Yaml::String(s) => Value::String(s),
+        Yaml::Sequence(seq) => Value::Array(seq.into_iter().map(yaml_to_json).collect()),
+        Yaml::Mapping(mapping) => Value::Object(
+            mapping
+                .into_iter()
+                .map(|(k, v)| (key_to_string(k), yaml_to_json(v)))
+                .collect(),
+        ),
+        Yaml::Tagged(tagged) => yaml_to_json(tagged.value),
+    }
+}
 
 /// per vscode-textmate:
 ///  Allowed values:
@@ -233,6 +343,8 @@ pub struct RawRule {
 pub struct RawGrammar {
     /// Human-readable name of the language
     /// Example: "JavaScript", "TypeScript", "Rust"
+    /// Injection grammars often don't have one, the scope name is used instead.
+    #[serde(default)]
     pub name: String,
     /// Optional alternative display name
     /// Example: "JavaScript (ES6)", "TypeScript React"
@@ -267,10 +379,49 @@ pub struct RawGrammar {
 }
 
 impl RawGrammar {
+    /// Loads a grammar file, the format being guessed from the extension or the content if
+    /// the extension is not a known one.
+    /// JSON parsing errors mention the path of the file.
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> GialloResult<Self> {
-        let file = File::open(&path)?;
-        let raw_grammar = serde_json::from_reader(&file)?;
-        Ok(raw_grammar)
+        let content = std::fs::read_to_string(&path)?;
+        let format =
+            GrammarFormat::from_path(&path).unwrap_or_else(|| GrammarFormat::detect(&content));
+        Self::load_from_str(&content, format).map_err(|err| match err {
+            // Plist and YAML grammars also end up as JSON errors, but without real position
+            Error::Json(error) if format == GrammarFormat::Json => Error::JsonFile {
+                path: path.as_ref().to_path_buf(),
+                error,
+            },
+            err => err,
+        })
+    }
+
+    /// Loads a grammar from a reader, the format being guessed from the content
+    pub fn load_from_reader<R: Read>(mut reader: R) -> GialloResult<Self> {
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+        Self::load_from_str(&content, GrammarFormat::detect(&content))
+    }
+
+    /// Loads a grammar in the given format.
+    /// JSON grammars can have comments and trailing commas, like VSCode allows.
+    pub fn load_from_str(content: &str, format: GrammarFormat) -> GialloResult<Self> {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        match format {
+            GrammarFormat::Json => Ok(jsonc::from_str(content)?),
+            #[cfg(feature = "plist")]
+            GrammarFormat::Plist => {
+                let value = plist::Value::from_reader_xml(content.as_bytes())?;
+                Ok(serde_json::from_value(plist_to_json(value))?)
+            }
+            #[cfg(feature = "yaml")]
+            GrammarFormat::Yaml => {
+                let value: serde_yaml_ng::Value = serde_yaml_ng::from_str(content)?;
+                Ok(serde_json::from_value(yaml_to_json(value))?)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::UnsupportedGrammarFormat(format)),
+        }
     }
 }
 
@@ -292,6 +443,88 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_guess_grammar_format() {
+        assert_eq!(
+            GrammarFormat::from_path("a/ini.tmLanguage.json"),
+            Some(GrammarFormat::Json)
+        );
+        assert_eq!(
+            GrammarFormat::from_path("ini.tmLanguage"),
+            Some(GrammarFormat::Plist)
+        );
+        assert_eq!(
+            GrammarFormat::from_path("ini.tmLanguage.yml"),
+            Some(GrammarFormat::Yaml)
+        );
+        assert_eq!(GrammarFormat::from_path("ini"), None);
+
+        assert_eq!(GrammarFormat::detect(" \n{}"), GrammarFormat::Json);
+        assert_eq!(GrammarFormat::detect("// a\n{}"), GrammarFormat::Json);
+        assert_eq!(GrammarFormat::detect("/* a */ {}"), GrammarFormat::Json);
+        assert_eq!(
+            GrammarFormat::detect("\u{feff}<?xml version=\"1.0\"?>"),
+            GrammarFormat::Plist
+        );
+        assert_eq!(GrammarFormat::detect("name: ini"), GrammarFormat::Yaml);
+    }
+
+    #[test]
+    fn can_load_jsonc_grammars() {
+        // Starts with a comment and has an unknown extension
+        let path = "src/fixtures/grammars/jsonc.grammar";
+        let grammar = RawGrammar::load_from_file(path).unwrap();
+        assert_eq!(grammar.name, "jsonc-grammar");
+        assert_eq!(grammar.patterns.len(), 1);
+        let content = fs::read_to_string(path).unwrap();
+        let grammar = RawGrammar::load_from_reader(content.as_bytes()).unwrap();
+        assert_eq!(grammar.scope_name, "source.jsonc-grammar");
+    }
+
+    #[cfg(all(feature = "plist", feature = "yaml"))]
+    #[test]
+    fn can_load_plist_and_yaml_grammars() {
+        let json = RawGrammar::load_from_file("src/fixtures/grammars/ini.tmLanguage.json").unwrap();
+        assert_eq!(json.patterns.len(), 3);
+        assert!(json.patterns[2].apply_end_pattern_last);
+
+        for path in [
+            "src/fixtures/grammars/ini.tmLanguage",
+            "src/fixtures/grammars/ini.tmLanguage.yaml",
+        ] {
+            let grammar = RawGrammar::load_from_file(path).unwrap();
+            assert_eq!(grammar.name, json.name);
+            assert_eq!(grammar.scope_name, json.scope_name);
+            assert_eq!(grammar.file_types, json.file_types);
+            assert_eq!(grammar.patterns, json.patterns, "{path}");
+            assert_eq!(grammar.repository, json.repository, "{path}");
+
+            // Same thing when guessing the format from the content
+            let content = fs::read_to_string(path).unwrap();
+            let grammar = RawGrammar::load_from_reader(content.as_bytes()).unwrap();
+            assert_eq!(grammar.patterns, json.patterns);
+        }
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn only_json_errors_mention_the_file() {
+        // Converted to JSON but there is no JSON position to report
+        let err =
+            RawGrammar::load_from_file("src/fixtures/grammars/missing_scope_name.tmLanguage.yaml")
+                .unwrap_err();
+        assert!(matches!(err, Error::Json(_)), "{err:?}");
+    }
+
+    #[cfg(not(feature = "yaml"))]
+    #[test]
+    fn errors_on_disabled_grammar_format() {
+        assert!(matches!(
+            RawGrammar::load_from_str("name: ini", GrammarFormat::Yaml),
+            Err(Error::UnsupportedGrammarFormat(GrammarFormat::Yaml))
+        ));
+    }
+
     #[test]
     fn can_parse_references() {
         let test_cases = vec![
@@ -1,9 +1,10 @@
 use std::fmt;
 use std::sync::{Arc, OnceLock};
 
-use onig::{RegexOptions, Syntax};
 use serde::{Deserialize, Serialize};
 
+use crate::grammars::engine::{CompiledRegex, EngineRegex};
+
 /// Escapes regular expression characters in a given string
 pub fn escape_regexp_characters(value: &str) -> String {
     value
@@ -85,7 +86,7 @@ fn transform_z_anchor(pattern: &str) -> String {
 pub struct Regex {
     pattern: String,
     #[serde(skip)]
-    compiled: OnceLock<Option<Arc<onig::Regex>>>,
+    compiled: OnceLock<Option<Arc<CompiledRegex>>>,
 }
 
 impl Clone for Regex {
@@ -125,23 +126,15 @@ impl Regex {
         &self.pattern
     }
 
-    pub fn compiled(&self) -> Option<&Arc<onig::Regex>> {
+    pub(crate) fn compiled(&self) -> Option<&Arc<CompiledRegex>> {
         self.compiled
-            .get_or_init(|| {
-                onig::Regex::with_options(
-                    &self.pattern,
-                    RegexOptions::REGEX_OPTION_CAPTURE_GROUP,
-                    Syntax::default(),
-                )
-                .ok()
-                .map(Arc::new)
-            })
+            .get_or_init(|| CompiledRegex::new(&self.pattern).ok().map(Arc::new))
             .as_ref()
     }
 
     /// Validate that this regex pattern compiles successfully
-    pub fn validate(&self) -> Result<(), onig::Error> {
-        onig::Regex::new(&self.pattern).map(|_| ())
+    pub fn validate(&self) -> Result<(), String> {
+        CompiledRegex::new(&self.pattern).map(|_| ())
     }
 }
 
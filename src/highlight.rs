@@ -1,11 +1,12 @@
-use std::collections::HashMap;
 use std::fmt::Write;
 use std::ops::Range;
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+use crate::inspect::{ThemeInspection, ThemeRuleMatch};
 use crate::renderers::html::HtmlEscaped;
-use crate::scope::Scope;
+use crate::scope::{Scope, ScopeRepository};
 use crate::themes::compiled::ThemeType;
 use crate::themes::css::{DARK_SUFFIX, LIGHT_SUFFIX};
 use crate::themes::font_style::FontStyle;
@@ -255,8 +256,6 @@ impl Default for MergingOptions {
 pub(crate) struct Highlighter<'r> {
     // 1 theme for Single, 2 for Dual
     themes: Vec<&'r CompiledTheme>,
-    // Separate cache per theme (max 2)
-    cache: [HashMap<Vec<Scope>, Style>; 2],
 }
 
 impl<'r> Highlighter<'r> {
@@ -264,7 +263,6 @@ impl<'r> Highlighter<'r> {
     pub(crate) fn new(theme: &'r CompiledTheme) -> Self {
         Highlighter {
             themes: vec![theme],
-            cache: [HashMap::new(), HashMap::new()],
         }
     }
 
@@ -272,13 +270,20 @@ impl<'r> Highlighter<'r> {
     pub(crate) fn new_dual(light_theme: &'r CompiledTheme, dark_theme: &'r CompiledTheme) -> Self {
         Highlighter {
             themes: vec![light_theme, dark_theme],
-            cache: [HashMap::new(), HashMap::new()],
+        }
+    }
+
+    /// Create a new highlighter for the theme(s) found in the registry
+    pub(crate) fn from_themes(themes: ThemeVariant<&'r CompiledTheme>) -> Self {
+        match themes {
+            ThemeVariant::Single(theme) => Self::new(theme),
+            ThemeVariant::Dual { light, dark } => Self::new_dual(light, dark),
         }
     }
 
     /// Match a scope stack against theme rules, building styles hierarchically
     /// like vscode-textmate does.
-    fn match_scopes(&mut self, scopes: &[Scope]) -> ThemeVariant<Style> {
+    fn match_scopes(&mut self, scopes: &Arc<[Scope]>) -> ThemeVariant<Style> {
         match self.themes.len() {
             1 => {
                 let style = self.match_scopes_for_theme(scopes, 0);
@@ -297,20 +302,24 @@ impl<'r> Highlighter<'r> {
     }
 
     /// Match scopes for a specific theme index with caching
-    fn match_scopes_for_theme(&mut self, scopes: &[Scope], theme_index: usize) -> Style {
-        if let Some(cached) = self.cache[theme_index].get(scopes) {
-            return *cached;
+    fn match_scopes_for_theme(&mut self, scopes: &Arc<[Scope]>, theme_index: usize) -> Style {
+        let theme = self.themes[theme_index];
+        if let Some(cached) = theme.style_cache.get(scopes) {
+            return cached;
         }
 
         // cache miss, we compute the style
-        let theme = self.themes[theme_index];
         let mut current_style = theme.default_style;
 
         // Build up scope path incrementally, simulating vscode-textmate's approach
         // Each scope level can override the accumulated style
+        let mut candidates = Vec::new();
         for i in 1..=scopes.len() {
             let current_scope_path = &scopes[0..i];
-            for rule in &theme.rules {
+            // Only the rules targeting the innermost scope of the path can match it
+            theme.rule_index.candidates(&scopes[i - 1], &mut candidates);
+            for &rule_index in &candidates {
+                let rule = &theme.rules[rule_index];
                 if rule.selector.matches(current_scope_path) {
                     current_style = rule.style_modifier.apply_to(&current_style);
                 }
@@ -318,31 +327,73 @@ impl<'r> Highlighter<'r> {
             // If no match found, current_style remains unchanged (inheritance!)
         }
         let result = current_style;
-        self.cache[theme_index].insert(scopes.to_vec(), result);
+        theme.style_cache.insert(scopes, result);
         result
     }
 
+    /// Explains the style of a scope stack for each theme: which rules matched, in the order
+    /// `match_scopes_for_theme` applies them, and the resulting style.
+    pub(crate) fn inspect_scopes(
+        &mut self,
+        scopes: &Arc<[Scope]>,
+        scope_repo: &ScopeRepository,
+    ) -> ThemeVariant<ThemeInspection> {
+        let mut inspections = Vec::with_capacity(self.themes.len());
+        for theme_index in 0..self.themes.len() {
+            let theme = self.themes[theme_index];
+            let mut matched_rules = Vec::new();
+            for i in 1..=scopes.len() {
+                let current_scope_path = &scopes[0..i];
+                for rule in &theme.rules {
+                    if rule.selector.matches(current_scope_path) {
+                        matched_rules.push(ThemeRuleMatch {
+                            selector: rule.selector.as_string(scope_repo),
+                            scope_depth: i,
+                            foreground: rule.style_modifier.foreground,
+                            background: rule.style_modifier.background,
+                            font_style: rule.style_modifier.font_style,
+                        });
+                    }
+                }
+            }
+            inspections.push(ThemeInspection {
+                matched_rules,
+                style: self.match_scopes_for_theme(scopes, theme_index),
+            });
+        }
+
+        let mut inspections = inspections.into_iter();
+        match self.themes.len() {
+            1 => ThemeVariant::Single(inspections.next().unwrap()),
+            2 => ThemeVariant::Dual {
+                light: inspections.next().unwrap(),
+                dark: inspections.next().unwrap(),
+            },
+            _ => unreachable!("Highlighter supports only 1 or 2 themes"),
+        }
+    }
+
     /// Apply highlighting to tokenized lines, preserving line structure.
     pub fn highlight_tokens(
         &mut self,
         content: &str,
-        tokens: Vec<Vec<Token>>,
+        tokens: &[Vec<Token>],
         options: MergingOptions,
     ) -> Vec<Vec<HighlightedText>> {
         let mut result = Vec::with_capacity(tokens.len());
         let lines = content.split('\n').collect::<Vec<_>>();
 
-        for (line_tokens, line) in tokens.into_iter().zip(lines) {
+        for (line_tokens, line) in tokens.iter().zip(lines) {
             if line_tokens.is_empty() {
                 result.push(Vec::new());
                 continue;
             }
 
             let mut line_result: Vec<(Range<usize>, ThemeVariant<Style>)> = line_tokens
-                .into_iter()
+                .iter()
                 .map(|x| {
                     let style = self.match_scopes(&x.scopes);
-                    (x.span, style)
+                    (x.span.clone(), style)
                 })
                 .collect();
 
@@ -434,9 +485,9 @@ impl<'r> Highlighter<'r> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::scope::Scope;
+    use crate::scope::{Scope, ScopeRepository};
     use crate::themes::compiled::StyleMap;
-    use crate::themes::compiled::{CompiledThemeRule, StyleModifier, ThemeType};
+    use crate::themes::compiled::{CompiledThemeRule, RuleIndex, StyleModifier, ThemeType};
     use crate::themes::font_style::FontStyle;
     use crate::themes::raw::{Colors, TokenColorRule, TokenColorSettings};
     use crate::themes::selector::parse_selector;
@@ -444,10 +495,13 @@ mod tests {
     use crate::tokenizer::Token;
     use std::ops::Range;
     use std::path::PathBuf;
+    use std::sync::LazyLock;
+
+    static SCOPE_REPO: LazyLock<ScopeRepository> = LazyLock::new(ScopeRepository::default);
 
     // Helper functions
     fn scope(name: &str) -> Scope {
-        Scope::new(name)[0]
+        SCOPE_REPO.parse(name)
     }
 
     fn color(hex: &str) -> Color {
@@ -457,14 +511,14 @@ mod tests {
     fn token(start: usize, end: usize, scope_name: &str) -> Token {
         Token {
             span: Range { start, end },
-            scopes: vec![scope(scope_name)],
+            scopes: [scope(scope_name)].into(),
         }
     }
 
     fn test_theme() -> CompiledTheme {
         let rules = vec![
             CompiledThemeRule {
-                selector: parse_selector("comment").unwrap(),
+                selector: parse_selector("comment", &SCOPE_REPO).unwrap(),
                 style_modifier: StyleModifier {
                     foreground: Some(color("#6A9955")),
                     background: None,
@@ -472,7 +526,7 @@ mod tests {
                 },
             },
             CompiledThemeRule {
-                selector: parse_selector("keyword").unwrap(),
+                selector: parse_selector("keyword", &SCOPE_REPO).unwrap(),
                 style_modifier: StyleModifier {
                     foreground: Some(color("#569CD6")),
                     background: None,
@@ -493,7 +547,9 @@ mod tests {
             line_number_foreground: None,
             highlight_background_color: None,
             style_map,
+            rule_index: RuleIndex::new(&rules),
             rules,
+            style_cache: Default::default(),
         }
     }
 
@@ -503,14 +559,16 @@ mod tests {
         let mut highlighter = Highlighter::new(&test_theme);
 
         // Test matching scopes
-        let ThemeVariant::Single(comment_style) = highlighter.match_scopes(&[scope("comment")])
+        let ThemeVariant::Single(comment_style) =
+            highlighter.match_scopes(&[scope("comment")].into())
         else {
             unreachable!()
         };
         assert_eq!(comment_style.foreground, color("#6A9955"));
         assert_eq!(comment_style.font_style, FontStyle::ITALIC);
 
-        let ThemeVariant::Single(keyword_style) = highlighter.match_scopes(&[scope("keyword")])
+        let ThemeVariant::Single(keyword_style) =
+            highlighter.match_scopes(&[scope("keyword")].into())
         else {
             unreachable!()
         };
@@ -518,7 +576,7 @@ mod tests {
         assert_eq!(keyword_style.font_style, FontStyle::BOLD);
 
         // Test unmatched scope returns default
-        let unknown_style = highlighter.match_scopes(&[scope("unknown")]);
+        let unknown_style = highlighter.match_scopes(&[scope("unknown")].into());
         assert_eq!(
             unknown_style,
             ThemeVariant::Single(highlighter.themes[0].default_style)
@@ -535,7 +593,7 @@ mod tests {
         ];
         let content = "if hello\n//";
 
-        let highlighted = highlighter.highlight_tokens(content, tokens, MergingOptions::default());
+        let highlighted = highlighter.highlight_tokens(content, &tokens, MergingOptions::default());
 
         assert_eq!(highlighted.len(), 2);
         assert_eq!(highlighted[0].len(), 2);
@@ -627,11 +685,12 @@ mod tests {
         };
 
         // Compile using proper pipeline (automatically sorts by specificity)
-        let inheritance_theme = CompiledTheme::from_raw_theme(raw_theme).unwrap();
+        let inheritance_theme = CompiledTheme::from_raw_theme(raw_theme, &SCOPE_REPO).unwrap();
         let mut highlighter = Highlighter::new(&inheritance_theme);
 
         // Test: constant should get its own values
-        let ThemeVariant::Single(style) = highlighter.match_scopes(&[scope("constant")]) else {
+        let ThemeVariant::Single(style) = highlighter.match_scopes(&[scope("constant")].into())
+        else {
             unreachable!()
         };
         assert_eq!(style.foreground, color("#300000"));
@@ -639,7 +698,7 @@ mod tests {
 
         // Test: constant.numeric should inherit fontStyle from constant but override foreground
         let ThemeVariant::Single(style) =
-            highlighter.match_scopes(&[scope("constant"), scope("constant.numeric")])
+            highlighter.match_scopes(&[scope("constant"), scope("constant.numeric")].into())
         else {
             unreachable!()
         };
@@ -647,11 +706,14 @@ mod tests {
         assert_eq!(style.font_style, FontStyle::ITALIC);
 
         // Test: constant.numeric.hex should inherit foreground from constant.numeric but override fontStyle
-        let ThemeVariant::Single(style) = highlighter.match_scopes(&[
-            scope("constant"),
-            scope("constant.numeric"),
-            scope("constant.numeric.hex"),
-        ]) else {
+        let ThemeVariant::Single(style) = highlighter.match_scopes(
+            &[
+                scope("constant"),
+                scope("constant.numeric"),
+                scope("constant.numeric.hex"),
+            ]
+            .into(),
+        ) else {
             unreachable!()
         };
         assert_eq!(style.foreground, color("#400000")); // Should inherit from constant.numeric
@@ -664,23 +726,23 @@ mod tests {
         let theme_path =
             PathBuf::from("grammars-themes/packages/tm-themes/themes/vitesse-black.json");
         let raw_theme = RawTheme::load_from_file(theme_path).unwrap();
-        let compiled_theme = CompiledTheme::from_raw_theme(raw_theme).unwrap();
+        let compiled_theme = CompiledTheme::from_raw_theme(raw_theme, &SCOPE_REPO).unwrap();
         let mut highlighter = Highlighter::new(&compiled_theme);
 
         // Test real tokenizer output from ASP.NET Core Razor with invalid HTML tag
         // Token 1: '<' - HTML tag begin punctuation
-        let token1_scopes = [
+        let token1_scopes: Arc<[Scope]> = Arc::new([
             scope("text.aspnetcorerazor"),
             scope("meta.element.structure.svg.html"),
             scope("meta.element.object.svg.foreignObject.html"),
             scope("meta.element.other.invalid.html"),
             scope("meta.tag.other.invalid.start.html"),
             scope("punctuation.definition.tag.begin.html"),
-        ];
+        ]);
         let style1 = highlighter.match_scopes(&token1_scopes);
 
         // Token 2: 'p' - Invalid/unrecognized HTML tag name
-        let token2_scopes = [
+        let token2_scopes: Arc<[Scope]> = Arc::new([
             scope("text.aspnetcorerazor"),
             scope("meta.element.structure.svg.html"),
             scope("meta.element.object.svg.foreignObject.html"),
@@ -688,18 +750,18 @@ mod tests {
             scope("meta.tag.other.invalid.start.html"),
             scope("entity.name.tag.html"),
             scope("invalid.illegal.unrecognized-tag.html"),
-        ];
+        ]);
         let style2 = highlighter.match_scopes(&token2_scopes);
 
         // Token 3: '>' - HTML tag end punctuation
-        let token3_scopes = [
+        let token3_scopes: Arc<[Scope]> = Arc::new([
             scope("text.aspnetcorerazor"),
             scope("meta.element.structure.svg.html"),
             scope("meta.element.object.svg.foreignObject.html"),
             scope("meta.element.other.invalid.html"),
             scope("meta.tag.other.invalid.start.html"),
             scope("punctuation.definition.tag.end.html"),
-        ];
+        ]);
         let style3 = highlighter.match_scopes(&token3_scopes);
 
         // Verify that styles are not default (theme inheritance is working)
@@ -0,0 +1,354 @@
+use std::ops::Range;
+
+use crate::error::{Error, GialloResult};
+use crate::grammars::GrammarId;
+use crate::highlight::{HighlightedText, Highlighter, MergingOptions};
+use crate::registry::{HighlightOptions, HighlightedCode, Registry, normalize_string};
+use crate::themes::{CompiledTheme, ThemeVariant};
+use crate::tokenizer::{CancellationToken, StateStack, Tokenizer};
+
+/// A document that keeps the tokenizer state of every line so it can be re-highlighted
+/// incrementally after edits.
+///
+/// After an edit, lines are re-tokenized starting from the first changed line until the
+/// tokenizer state at the end of a line is the same as before the edit: at that point
+/// nothing after it can change.
+#[derive(Debug)]
+pub struct IncrementalDocument<'r> {
+    grammar_id: GrammarId,
+    language: &'r str,
+    theme: ThemeVariant<&'r CompiledTheme>,
+    tokenizer: Tokenizer<'r>,
+    highlighter: Highlighter<'r>,
+    merging_options: MergingOptions,
+    /// The options we were created with, we need them to reset the limits on every edit
+    options: HighlightOptions,
+    /// The text of each line, without line terminator
+    lines: Vec<String>,
+    /// The tokenizer state at the end of each line
+    end_states: Vec<StateStack>,
+    /// The highlighted tokens of each line
+    tokens: Vec<Vec<HighlightedText>>,
+    /// Whether each line was fully tokenized or stopped early because of the limits
+    degraded: Vec<bool>,
+}
+
+impl<'r> IncrementalDocument<'r> {
+    /// Highlights `content` with the given options and keeps everything needed to
+    /// re-highlight it after edits.
+    ///
+    /// Lines are split the same way as `Registry::highlight`.
+    pub fn new(
+        registry: &'r Registry,
+        content: &str,
+        options: &HighlightOptions,
+    ) -> GialloResult<Self> {
+        if !registry.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let grammar_id = registry.find_grammar_id(&options.lang, options.fallback_to_plain)?;
+        let theme = registry.find_themes(&options.theme)?;
+
+        let mut doc = Self {
+            grammar_id,
+            language: &registry.grammars[grammar_id].name,
+            theme,
+            tokenizer: Tokenizer::new(grammar_id, registry),
+            highlighter: Highlighter::from_themes(theme),
+            merging_options: options.merging_options(),
+            options: options.clone(),
+            lines: Vec::new(),
+            end_states: Vec::new(),
+            tokens: Vec::new(),
+            degraded: Vec::new(),
+        };
+
+        let normalized_content = normalize_string(content);
+        if !normalized_content.is_empty() {
+            let lines: Vec<String> = normalized_content.split('\n').map(String::from).collect();
+            let num_lines = lines.len();
+            doc.splice(0..0, lines);
+            doc.retokenize(0, num_lines, None)?;
+        }
+
+        Ok(doc)
+    }
+
+    /// Replaces the lines in `lines` with `new_text` and re-highlights what is needed.
+    ///
+    /// `new_text` is split into lines like `str::lines`: an empty string removes the lines
+    /// and a trailing line terminator does not create an extra empty line.
+    /// Use an empty range to insert lines without removing any.
+    ///
+    /// Returns the sorted indices of the lines, in the updated document, whose highlighting
+    /// changed. All inserted lines are included.
+    ///
+    /// The limits from the `HighlightOptions`, if any, apply to each edit. If the edit gets
+    /// cancelled, `Error::Cancelled` is returned and the lines that were not re-highlighted yet
+    /// are left degraded. They are re-highlighted by the next edit, which can be given a new
+    /// token with `set_cancellation_token`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds, like `Vec::splice`.
+    pub fn edit(&mut self, lines: Range<usize>, new_text: &str) -> GialloResult<Vec<usize>> {
+        let normalized_text = normalize_string(new_text);
+        let new_lines: Vec<String> = normalized_text.lines().map(String::from).collect();
+        let num_new_lines = new_lines.len();
+        let start = lines.start;
+
+        // The state the first line after the replaced ones was tokenized with
+        let previous_state = if lines.is_empty() {
+            start.checked_sub(1)
+        } else {
+            Some(lines.end - 1)
+        }
+        .map(|i| self.end_states[i].clone());
+
+        self.splice(lines, new_lines);
+        // Lines left degraded by a previous edit, eg a cancelled one, are re-highlighted as well
+        let first_degraded = self.degraded[..start]
+            .iter()
+            .position(|d| *d)
+            .unwrap_or(start);
+        self.retokenize(
+            first_degraded,
+            start - first_degraded + num_new_lines,
+            previous_state,
+        )
+    }
+
+    /// Replaces the given range of lines, without tokenizing anything.
+    /// The states and tokens of the inserted lines are placeholders until `retokenize` is called.
+    fn splice(&mut self, range: Range<usize>, new_lines: Vec<String>) {
+        let num_new_lines = new_lines.len();
+        // Overwritten when the new lines are tokenized
+        let placeholder = StateStack::new(self.grammar_id, Default::default());
+        self.lines.splice(range.clone(), new_lines);
+        self.end_states.splice(
+            range.clone(),
+            std::iter::repeat_n(placeholder, num_new_lines),
+        );
+        self.tokens.splice(
+            range.clone(),
+            std::iter::repeat_n(Vec::new(), num_new_lines),
+        );
+        self.degraded
+            .splice(range, std::iter::repeat_n(false, num_new_lines));
+    }
+
+    /// Tokenizes the `num_new_lines` lines starting at `start` and then keeps going until
+    /// a line starts in the same state as before the edit.
+    /// `old_state` is the state the first line after the inserted ones started with before the
+    /// edit, `None` meaning it was the first line of the document.
+    fn retokenize(
+        &mut self,
+        start: usize,
+        num_new_lines: usize,
+        mut old_state: Option<StateStack>,
+    ) -> GialloResult<Vec<usize>> {
+        let mut state = start.checked_sub(1).map(|i| self.end_states[i].clone());
+        let mut changed = Vec::with_capacity(num_new_lines);
+        // Limits apply to each edit separately
+        self.tokenizer.set_limits(self.options.limits());
+        // The end state of a degraded line is not the real one so we can't stop on it: once a
+        // line is degraded, we go through all the remaining lines, as well as the lines left
+        // degraded by a previous edit
+        let mut any_degraded = false;
+
+        for i in start..self.lines.len() {
+            let is_new_line = i < start + num_new_lines;
+            if !is_new_line && !any_degraded && !self.degraded[i] && state == old_state {
+                break;
+            }
+
+            let (tokens, end_state) = self
+                .tokenizer
+                .tokenize_next_line(&self.lines[i], state)
+                .map_err(Error::TokenizeRegex)?;
+            let highlighted = self
+                .highlighter
+                .highlight_tokens(&self.lines[i], &[tokens], self.merging_options)
+                .pop()
+                .unwrap_or_default();
+            if is_new_line || highlighted != self.tokens[i] {
+                self.tokens[i] = highlighted;
+                changed.push(i);
+            }
+            self.degraded[i] = self.tokenizer.last_line_degraded();
+            any_degraded |= self.degraded[i];
+
+            let old_end_state = std::mem::replace(&mut self.end_states[i], end_state);
+            if !is_new_line {
+                old_state = Some(old_end_state);
+            }
+            state = Some(self.end_states[i].clone());
+        }
+
+        // We still went through all the lines we had to so the document is consistent,
+        // just degraded
+        if self.tokenizer.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(changed)
+    }
+
+    /// Sets the token that can cancel the next edits, replacing the one from the
+    /// `HighlightOptions`, or removes it with `None`.
+    /// A cancelled token makes every edit using it fail so a new one is needed to keep editing.
+    pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.options.cancellation_token = token;
+    }
+
+    /// The language of the grammar used to highlight this document
+    pub fn language(&self) -> &'r str {
+        self.language
+    }
+
+    /// The number of lines in the document
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// The text of the line at the given index, without its line terminator
+    pub fn line(&self, index: usize) -> Option<&str> {
+        self.lines.get(index).map(String::as_str)
+    }
+
+    /// The highlighted tokens of every line
+    pub fn tokens(&self) -> &[Vec<HighlightedText>] {
+        &self.tokens
+    }
+
+    /// The 0-based indices of the lines that were not fully tokenized because of the limits
+    /// set in the `HighlightOptions`.
+    pub fn degraded_lines(&self) -> Vec<usize> {
+        self.degraded
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &degraded)| degraded.then_some(i))
+            .collect()
+    }
+
+    /// Returns the document in the same shape as `Registry::highlight` so it can be
+    /// given to the renderers.
+    pub fn highlighted(&self) -> HighlightedCode<'r> {
+        HighlightedCode {
+            language: self.language,
+            theme: self.theme,
+            tokens: self.tokens.clone(),
+            degraded_lines: self.degraded_lines(),
+            line_endings: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CancellationToken;
+    use crate::test_utils::get_registry;
+
+    fn assert_same_as_full_highlight(registry: &Registry, doc: &IncrementalDocument) {
+        let content = doc.lines.join("\n");
+        let expected = registry
+            .highlight(
+                &content,
+                &HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black")),
+            )
+            .unwrap();
+        assert_eq!(doc.tokens(), expected.tokens);
+    }
+
+    #[test]
+    fn only_rehighlights_changed_lines() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let mut doc = IncrementalDocument::new(
+            &registry,
+            "let a = 1;\nlet b = 2;\nlet c = 3;\nlet d = 4;",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(doc.line_count(), 4);
+        assert_same_as_full_highlight(&registry, &doc);
+
+        // Opening a block comment changes every line after it
+        let changed = doc.edit(1..2, "/* let b = 2;").unwrap();
+        assert_eq!(changed, vec![1, 2, 3]);
+        assert_same_as_full_highlight(&registry, &doc);
+
+        // Closing it changes the remaining lines back
+        let changed = doc.edit(2..3, "*/ let c = 3;").unwrap();
+        assert_eq!(changed, vec![2, 3]);
+        assert_same_as_full_highlight(&registry, &doc);
+
+        // An edit that doesn't change the state at the end of the line stops right away
+        let changed = doc.edit(0..1, "let a = 10;").unwrap();
+        assert_eq!(changed, vec![0]);
+        assert_same_as_full_highlight(&registry, &doc);
+    }
+
+    #[test]
+    fn can_insert_and_remove_lines() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let mut doc =
+            IncrementalDocument::new(&registry, "let a = 1;\n/* a\nb */\nlet d = 4;", &options)
+                .unwrap();
+
+        let changed = doc.edit(1..1, "let b = 2;\nlet c = 3;\n").unwrap();
+        assert_eq!(changed, vec![1, 2]);
+        assert_eq!(doc.line_count(), 6);
+        assert_same_as_full_highlight(&registry, &doc);
+
+        // Removing the start of the comment changes the line that was in it
+        let changed = doc.edit(3..4, "").unwrap();
+        assert_eq!(changed, vec![3]);
+        assert_eq!(doc.line(3), Some("b */"));
+        assert_same_as_full_highlight(&registry, &doc);
+    }
+
+    #[test]
+    fn degraded_edits_go_through_all_remaining_lines() {
+        let registry = get_registry();
+        let token = CancellationToken::new();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"))
+            .cancellation_token(token.clone());
+        let mut doc =
+            IncrementalDocument::new(&registry, "let a = 1;\nlet b = 2;\nlet c = 3;", &options)
+                .unwrap();
+        assert!(doc.degraded_lines().is_empty());
+
+        // The state at the end of the degraded first line looks unchanged but every line after
+        // it would be in a comment
+        token.cancel();
+        assert!(matches!(doc.edit(0..0, "/*"), Err(Error::Cancelled)));
+        assert_eq!(doc.degraded_lines(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn can_edit_after_a_cancelled_edit() {
+        let registry = get_registry();
+        let token = CancellationToken::new();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"))
+            .cancellation_token(token.clone());
+        let mut doc =
+            IncrementalDocument::new(&registry, "let a = 1;\nlet b = 2;\nlet c = 3;", &options)
+                .unwrap();
+        token.cancel();
+        assert!(matches!(doc.edit(0..0, "/*"), Err(Error::Cancelled)));
+        assert!(matches!(doc.edit(3..3, "d"), Err(Error::Cancelled)));
+
+        // With a new token the next edit goes through the lines left degraded
+        doc.set_cancellation_token(Some(CancellationToken::new()));
+        let changed = doc.edit(4..4, "let e = 5;").unwrap();
+        assert_eq!(changed, vec![0, 1, 2, 3, 4, 5]);
+        assert!(doc.degraded_lines().is_empty());
+        assert_same_as_full_highlight(&registry, &doc);
+
+        doc.set_cancellation_token(None);
+        doc.edit(0..1, "").unwrap();
+        assert_same_as_full_highlight(&registry, &doc);
+    }
+}
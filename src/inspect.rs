@@ -0,0 +1,183 @@
+use std::ops::Range;
+
+use crate::error::{Error, GialloResult};
+use crate::grammars::GrammarId;
+use crate::highlight::Highlighter;
+use crate::registry::Registry;
+use crate::themes::{Color, CompiledTheme, FontStyle, Style, ThemeVariant};
+use crate::tokenizer::{Limits, Tokenizer};
+
+/// Everything we know about how a single token got its style, similar to the
+/// "Inspect Editor Tokens and Scopes" command of VSCode.
+///
+/// Created by `Registry::inspect` and `Registry::inspect_offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenInspection {
+    /// The 0-based index of the line containing the token
+    pub line: usize,
+    /// The byte range of the token in its line
+    pub span: Range<usize>,
+    /// The text of the token
+    pub text: String,
+    /// The scopes of the token, from the outermost to the innermost one
+    pub scopes: Vec<String>,
+    /// The name of the grammar containing the rule that produced the token.
+    /// It is different from the requested language if the token comes from an embedded grammar.
+    pub grammar: String,
+    /// The `name` of the grammar rule that produced the token, as written in the grammar.
+    /// Rules without a name, like the ones only including other patterns, return `None`.
+    pub rule_name: Option<String>,
+    /// How the theme(s) styled the token
+    pub theme: ThemeVariant<ThemeInspection>,
+}
+
+/// How a theme styled a token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThemeInspection {
+    /// The theme rules that matched the scopes of the token, in the order they were applied.
+    /// When several rules set the same property, the last one wins.
+    pub matched_rules: Vec<ThemeRuleMatch>,
+    /// The resulting style, starting from the theme default style
+    pub style: Style,
+}
+
+/// A theme rule that matched a token and what it contributed to its style
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThemeRuleMatch {
+    /// The selector of the rule, eg `source.js meta.function > string`
+    pub selector: String,
+    /// How many scopes, from the outermost one, the selector was matched against.
+    /// Rules matching deeper scopes are applied later.
+    pub scope_depth: usize,
+    /// The foreground color set by the rule, if any
+    pub foreground: Option<Color>,
+    /// The background color set by the rule, if any
+    pub background: Option<Color>,
+    /// The font style set by the rule, if any
+    pub font_style: Option<FontStyle>,
+}
+
+/// Tokenizes `content`, which needs to be normalized already, up to `line` and inspects the
+/// token containing the byte `column` of that line.
+/// Returns `None` if there is no such line or if the column is not inside a token.
+pub(crate) fn inspect_token(
+    registry: &Registry,
+    grammar_id: GrammarId,
+    theme: ThemeVariant<&CompiledTheme>,
+    content: &str,
+    line: usize,
+    column: usize,
+    limits: Limits,
+) -> GialloResult<Option<TokenInspection>> {
+    if content.is_empty() {
+        return Ok(None);
+    }
+    let Some(line_content) = content.split('\n').nth(line) else {
+        return Ok(None);
+    };
+
+    let mut tokenizer = Tokenizer::new(grammar_id, registry);
+    tokenizer.set_limits(limits);
+    // We need the state at the start of the line so we go through all the lines before it
+    let mut state = None;
+    for previous_line in content.split('\n').take(line) {
+        let (_, new_state) = tokenizer
+            .tokenize_next_line(previous_line, state)
+            .map_err(Error::TokenizeRegex)?;
+        state = Some(new_state);
+    }
+    let (tokens, rules, _) = tokenizer
+        .tokenize_next_line_with_rules(line_content, state)
+        .map_err(Error::TokenizeRegex)?;
+    if tokenizer.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+
+    let Some(index) = tokens.iter().position(|t| t.span.contains(&column)) else {
+        return Ok(None);
+    };
+    let token = &tokens[index];
+    let rule_ref = rules[index];
+
+    let (grammar, rule_name) = match registry.grammars.get(rule_ref.grammar.as_index()) {
+        Some(grammar) => (
+            grammar.name.clone(),
+            grammar
+                .get_original_rule_name(rule_ref.rule)
+                .map(String::from),
+        ),
+        None => (registry.grammars[grammar_id].name.clone(), None),
+    };
+
+    Ok(Some(TokenInspection {
+        line,
+        span: token.span.clone(),
+        text: line_content[token.span.clone()].to_string(),
+        scopes: token.scope_names(registry),
+        grammar,
+        rule_name,
+        theme: Highlighter::from_themes(theme).inspect_scopes(&token.scopes, &registry.scope_repo),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registry::HighlightOptions;
+    use crate::test_utils::get_registry;
+    use crate::themes::ThemeVariant;
+
+    #[test]
+    fn can_inspect_token() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let content = "let a = 1;\r\n/* multi\nline */ let s = \"hello\";";
+
+        let inspection = registry.inspect(content, &options, 2, 18).unwrap().unwrap();
+        assert_eq!(inspection.text, "hello");
+        assert_eq!(inspection.grammar, "javascript");
+        assert_eq!(inspection.scopes.first().unwrap(), "source.js");
+        assert!(
+            inspection
+                .scopes
+                .last()
+                .unwrap()
+                .starts_with("string.quoted.double")
+        );
+
+        let ThemeVariant::Single(theme) = &inspection.theme else {
+            panic!("expected a single theme");
+        };
+        let last_foreground = theme
+            .matched_rules
+            .iter()
+            .filter_map(|r| r.foreground)
+            .next_back()
+            .unwrap();
+        assert_eq!(theme.style.foreground, last_foreground);
+        let highlighted = registry.highlight(content, &options).unwrap();
+        let expected = highlighted.tokens[2]
+            .iter()
+            .find(|t| t.text.contains("hello"))
+            .unwrap();
+        assert_eq!(expected.style, ThemeVariant::Single(theme.style));
+
+        // The same token from its offset in the original content, \r\n included
+        let offset = content.find("hello").unwrap();
+        assert_eq!(
+            registry.inspect_offset(content, &options, offset).unwrap(),
+            Some(inspection)
+        );
+
+        // Comments are produced by a rule with a name
+        let comment = registry.inspect(content, &options, 1, 4).unwrap().unwrap();
+        assert!(comment.rule_name.unwrap().starts_with("comment.block"));
+
+        // Line terminators and positions past the end are not in any token
+        assert_eq!(
+            registry.inspect_offset(content, &options, 10).unwrap(),
+            None
+        );
+        assert_eq!(registry.inspect(content, &options, 0, 100).unwrap(), None);
+        assert_eq!(registry.inspect(content, &options, 3, 0).unwrap(), None);
+    }
+}
@@ -0,0 +1,127 @@
+//! Loading of JSON with comments and trailing commas, like VSCode allows in themes and grammars.
+
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, GialloResult};
+
+/// Replaces the comments and trailing commas of JSONC content with spaces so it can be parsed as
+/// JSON. Line breaks are kept and every replaced byte becomes a single space so the positions of
+/// parsing errors are the same as in the original content.
+pub(crate) fn strip_jsonc(content: &str) -> String {
+    let bytes = content.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    // Where the last comma was written in `out`, until we see something that is not whitespace
+    let mut pending_comma = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'"', _) => {
+                pending_comma = None;
+                let start = i;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(bytes.len());
+                out.extend_from_slice(&bytes[start..i]);
+                continue;
+            }
+            (b'/', Some(b'/')) => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    out.push(b' ');
+                    i += 1;
+                }
+                continue;
+            }
+            (b'/', Some(b'*')) => {
+                out.extend_from_slice(b"  ");
+                i += 2;
+                while i < bytes.len() && !bytes[i..].starts_with(b"*/") {
+                    out.push(if bytes[i] == b'\n' { b'\n' } else { b' ' });
+                    i += 1;
+                }
+                if i < bytes.len() {
+                    out.extend_from_slice(b"  ");
+                    i += 2;
+                }
+                continue;
+            }
+            (b',', _) => {
+                pending_comma = Some(out.len());
+                out.push(b',');
+            }
+            (b'}' | b']', _) => {
+                if let Some(pos) = pending_comma.take() {
+                    out[pos] = b' ';
+                }
+                out.push(bytes[i]);
+            }
+            (b, _) => {
+                if !b.is_ascii_whitespace() {
+                    pending_comma = None;
+                }
+                out.push(b);
+            }
+        }
+        i += 1;
+    }
+
+    // Strings are copied as is and the other bytes we replace are ASCII or whole comments, so it's
+    // still valid UTF-8
+    String::from_utf8(out).expect("stripping JSONC keeps valid UTF-8")
+}
+
+/// Parses JSONC content
+pub(crate) fn from_str<T: DeserializeOwned>(content: &str) -> serde_json::Result<T> {
+    serde_json::from_str(&strip_jsonc(content))
+}
+
+/// Reads and parses a JSONC file, parsing errors mentioning the file
+pub(crate) fn from_file<T: DeserializeOwned>(path: &Path) -> GialloResult<T> {
+    let content = std::fs::read_to_string(path)?;
+    from_str(&content).map_err(|error| Error::JsonFile {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_strip_comments_and_trailing_commas() {
+        let content = r#"{
+  // A comment with "quotes", and a comma
+  "name": "a // not a comment", /* inline
+  block */
+  "values": [1, 2, /* end */ ],
+  "escaped": "\" /* still a string */",
+}"#;
+        let stripped = strip_jsonc(content);
+        assert_eq!(stripped.len(), content.len());
+        assert_eq!(stripped.lines().count(), content.lines().count());
+
+        let value: serde_json::Value = from_str(content).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "name": "a // not a comment",
+                "values": [1, 2],
+                "escaped": "\" /* still a string */",
+            })
+        );
+        // Only trailing commas are removed
+        assert!(from_str::<serde_json::Value>("[1,, 2]").is_err());
+    }
+
+    #[test]
+    fn errors_have_the_original_position() {
+        let content = "{\n  // é comment\n  \"a\": 1,\n  \"b\": ?\n}";
+        let error = from_str::<serde_json::Value>(content).unwrap_err();
+        assert_eq!((error.line(), error.column()), (4, 8));
+    }
+}
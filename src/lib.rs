@@ -28,20 +28,38 @@ mod registry;
 mod scope;
 mod themes;
 
+mod decode;
 mod highlight;
+mod incremental;
+mod inspect;
+mod jsonc;
 mod markdown_fence;
+mod offsets;
 mod renderers;
+mod stream;
 mod tokenizer;
+mod vscode;
 
+pub use decode::{DecodedText, HighlightedBytes, TextEncoding};
 pub use error::Error;
+pub use grammars::GrammarFormat;
 pub use highlight::HighlightedText;
+pub use incremental::IncrementalDocument;
+pub use inspect::{ThemeInspection, ThemeRuleMatch, TokenInspection};
 pub use markdown_fence::{ParsedFence, parse_markdown_fence};
-pub use registry::{HighlightOptions, HighlightedCode, PLAIN_GRAMMAR_NAME, Registry};
+pub use offsets::OffsetUnit;
+pub use registry::{
+    HighlightOptions, HighlightedCode, LineEnding, PLAIN_GRAMMAR_NAME, Registry, TokenizedCode,
+};
 pub use renderers::{
     RenderOptions, html::DataAttrPosition, html::ExtraHtmlContent, html::HtmlRenderer,
     terminal::TerminalRenderer,
 };
+pub use scope::Scope;
+pub use stream::HighlightedLines;
 pub use themes::{Color, CompiledTheme, FontStyle, Style, ThemeVariant};
+pub use tokenizer::{CancellationToken, LineState, Token};
+pub use vscode::{GrammarContribution, TokenType};
 
 /// The CSS needed for the line number gutter to display properly
 pub const GIALLO_CSS: &str = r#".giallo-l {
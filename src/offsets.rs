@@ -0,0 +1,177 @@
+use std::ops::Range;
+
+#[cfg(feature = "display-width")]
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::highlight::HighlightedText;
+
+/// The unit positions in a line are counted in.
+///
+/// Token spans are in bytes, which is what Rust strings use, but browsers and editors usually
+/// count in UTF-16 code units and terminals in columns.
+/// Non-exhaustive since `DisplayWidth` only exists with the `display-width` feature, which
+/// another crate can enable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum OffsetUnit {
+    /// UTF-8 bytes, like the indices of Rust strings
+    Bytes,
+    /// Unicode scalar values, like `str::chars`
+    Chars,
+    /// UTF-16 code units, like JavaScript strings and the positions of the language server
+    /// protocol
+    Utf16,
+    /// The columns taken in a terminal: a grapheme cluster, eg an emoji with modifiers, is
+    /// displayed as a single character and wide characters, eg most CJK characters, take two
+    /// columns.
+    #[cfg(feature = "display-width")]
+    DisplayWidth,
+}
+
+impl OffsetUnit {
+    /// The length of `text` in this unit
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            OffsetUnit::Bytes => text.len(),
+            OffsetUnit::Chars => text.chars().count(),
+            OffsetUnit::Utf16 => text.chars().map(char::len_utf16).sum(),
+            #[cfg(feature = "display-width")]
+            OffsetUnit::DisplayWidth => text.graphemes(true).map(grapheme_width).sum(),
+        }
+    }
+
+    /// Converts the byte spans of `line`, eg the spans of the `Token`s of the line, to this
+    /// unit.
+    /// The spans need to be sorted and not overlap.
+    ///
+    /// With `DisplayWidth`, a grapheme cluster cut by a span boundary counts for the span
+    /// containing its start.
+    pub fn convert_spans(
+        self,
+        line: &str,
+        spans: impl IntoIterator<Item = Range<usize>>,
+    ) -> Vec<Range<usize>> {
+        match self {
+            OffsetUnit::Bytes => spans.into_iter().collect(),
+            OffsetUnit::Chars => convert_spans(line.char_indices().map(|(i, _)| (i, 1)), spans),
+            OffsetUnit::Utf16 => {
+                convert_spans(line.char_indices().map(|(i, c)| (i, c.len_utf16())), spans)
+            }
+            #[cfg(feature = "display-width")]
+            OffsetUnit::DisplayWidth => convert_spans(
+                line.grapheme_indices(true)
+                    .map(|(i, grapheme)| (i, grapheme_width(grapheme))),
+                spans,
+            ),
+        }
+    }
+
+    /// The span of each highlighted token of a line in this unit, eg for a line of
+    /// `HighlightedCode::tokens`.
+    pub fn spans(self, line: &[HighlightedText]) -> Vec<Range<usize>> {
+        let mut byte_spans = Vec::with_capacity(line.len());
+        let mut text = String::new();
+        for token in line {
+            byte_spans.push(text.len()..text.len() + token.text.len());
+            text.push_str(&token.text);
+        }
+        self.convert_spans(&text, byte_spans)
+    }
+}
+
+/// Converts byte spans given the segments of a line, as (byte start, length in the target unit).
+/// A segment counts for the position after it as soon as it starts before that position.
+fn convert_spans(
+    segments: impl Iterator<Item = (usize, usize)>,
+    spans: impl IntoIterator<Item = Range<usize>>,
+) -> Vec<Range<usize>> {
+    let mut segments = segments.peekable();
+    let mut offset = 0;
+    let mut to_offset = |byte: usize| {
+        while let Some(&(start, len)) = segments.peek()
+            && start < byte
+        {
+            offset += len;
+            segments.next();
+        }
+        offset
+    };
+
+    spans
+        .into_iter()
+        .map(|span| {
+            let start = to_offset(span.start);
+            let end = to_offset(span.end);
+            start..end
+        })
+        .collect()
+}
+
+#[cfg(feature = "display-width")]
+fn grapheme_width(grapheme: &str) -> usize {
+    // Zero-width graphemes, eg a lone combining mark, are still displayed somewhere
+    unicode_width::UnicodeWidthStr::width(grapheme).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::themes::{Style, ThemeVariant};
+
+    fn highlighted(texts: &[&str]) -> Vec<HighlightedText> {
+        texts
+            .iter()
+            .map(|text| HighlightedText {
+                text: text.to_string(),
+                style: ThemeVariant::Single(Style::default()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn can_convert_spans() {
+        // é is 2 bytes, 𝕏 is 4 bytes and 2 UTF-16 units
+        let line = highlighted(&["let ", "café", " = ", "\"𝕏\"", ";"]);
+        assert_eq!(
+            OffsetUnit::Bytes.spans(&line),
+            vec![0..4, 4..9, 9..12, 12..18, 18..19]
+        );
+        assert_eq!(
+            OffsetUnit::Chars.spans(&line),
+            vec![0..4, 4..8, 8..11, 11..14, 14..15]
+        );
+        assert_eq!(
+            OffsetUnit::Utf16.spans(&line),
+            vec![0..4, 4..8, 8..11, 11..15, 15..16]
+        );
+        assert_eq!(OffsetUnit::Utf16.measure("\"𝕏\""), 4);
+        assert!(OffsetUnit::Chars.spans(&[]).is_empty());
+
+        // Same thing from the byte spans of tokens
+        let text: String = line.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(
+            OffsetUnit::Utf16.convert_spans(&text, [4..9, 12..18]),
+            vec![4..8, 11..15]
+        );
+    }
+
+    #[cfg(feature = "display-width")]
+    #[test]
+    fn can_convert_spans_to_display_width() {
+        // 中 is wide, the family emoji is 5 chars forming a single wide grapheme and the e is
+        // followed by a combining accent
+        let line = highlighted(&["中", "👨‍👩‍👧", "e\u{301}", "a"]);
+        assert_eq!(
+            OffsetUnit::DisplayWidth.spans(&line),
+            vec![0..2, 2..4, 4..5, 5..6]
+        );
+        assert_eq!(OffsetUnit::Chars.spans(&line), vec![0..1, 1..6, 6..8, 8..9]);
+
+        // A token boundary inside a grapheme: it counts for the token where it starts
+        let line = highlighted(&["e", "\u{301}", "a"]);
+        assert_eq!(
+            OffsetUnit::DisplayWidth.spans(&line),
+            vec![0..1, 1..1, 1..2]
+        );
+    }
+}
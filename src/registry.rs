@@ -1,22 +1,30 @@
 use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read};
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 
+use crate::decode::{DecodedText, HighlightedBytes};
 use crate::error::{Error, GialloResult};
 use crate::grammars::{
-    BASE_GLOBAL_RULE_REF, CompiledGrammar, GlobalRuleRef, GrammarId, InjectionPrecedence, Match,
-    NO_OP_GLOBAL_RULE_REF, PatternSet, ROOT_RULE_ID, RawGrammar, Rule, resolve_external_references,
+    BASE_GLOBAL_RULE_REF, CompiledGrammar, GlobalRuleRef, GrammarFormat, GrammarId,
+    InjectionPrecedence, Match, NO_OP_GLOBAL_RULE_REF, PatternSet, ROOT_RULE_ID, RawGrammar, Rule,
+    resolve_external_references,
 };
 use crate::highlight::{HighlightedText, Highlighter, MergingOptions};
+use crate::inspect::{TokenInspection, inspect_token};
+use crate::offsets::OffsetUnit;
+use crate::vscode::{ExtensionManifest, GrammarContribution, LanguageFiles};
 
-use crate::scope::Scope;
-#[cfg(feature = "dump")]
-use crate::scope::ScopeRepository;
+use crate::scope::{Scope, ScopeRepository};
+use crate::stream::HighlightedLines;
 use crate::themes::css::{DARK_SUFFIX, LIGHT_SUFFIX};
 use crate::themes::{CompiledTheme, RawTheme, ThemeVariant};
-use crate::tokenizer::{Token, Tokenizer};
+use crate::tokenizer::{CancellationToken, Limits, LineState, StateStack, Token, Tokenizer};
 
 #[cfg(feature = "dump")]
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -28,17 +36,16 @@ struct Dump {
 
 #[cfg(feature = "dump")]
 impl Dump {
-    pub fn restore(self) -> (Registry, ScopeRepository) {
-        let registry = Registry::restore(self.grammars, self.themes);
+    pub fn restore(self) -> Registry {
         let scope_repo = ScopeRepository::from_atoms(self.atoms);
-        (registry, scope_repo)
+        Registry::restore(self.grammars, self.themes, scope_repo)
     }
 
-    pub fn build(registry: &Registry, scope_repo: &ScopeRepository) -> Self {
+    pub fn build(registry: &Registry) -> Self {
         Dump {
             grammars: registry.grammars.clone(),
             themes: registry.themes.values().cloned().collect(),
-            atoms: scope_repo.atoms.clone(),
+            atoms: registry.scope_repo.atoms(),
         }
     }
 }
@@ -57,6 +64,16 @@ pub struct HighlightOptions {
     pub(crate) merge_whitespaces: bool,
     pub(crate) merge_same_style_tokens: bool,
     pub(crate) fallback_to_plain: bool,
+    #[serde(default)]
+    pub(crate) max_line_length: Option<usize>,
+    #[serde(default)]
+    pub(crate) line_time_limit: Option<Duration>,
+    #[serde(default)]
+    pub(crate) time_limit: Option<Duration>,
+    #[serde(skip)]
+    pub(crate) cancellation_token: Option<CancellationToken>,
+    #[serde(default)]
+    pub(crate) preserve_line_endings: bool,
 }
 
 impl HighlightOptions {
@@ -68,19 +85,17 @@ impl HighlightOptions {
     /// Even if you set it back to `true`, it will be ignored when rendering.
     pub fn new(lang: impl AsRef<str>, theme: ThemeVariant<&str>) -> Self {
         let merge_same_style_tokens = matches!(theme, ThemeVariant::Single(_));
-        let theme = match theme {
-            ThemeVariant::Single(t) => ThemeVariant::Single(t.to_lowercase()),
-            ThemeVariant::Dual { light, dark } => ThemeVariant::Dual {
-                light: light.to_lowercase(),
-                dark: dark.to_lowercase(),
-            },
-        };
         Self {
             lang: lang.as_ref().to_lowercase(),
-            theme,
+            theme: theme.to_lowercase(),
             merge_same_style_tokens,
             merge_whitespaces: true,
             fallback_to_plain: false,
+            max_line_length: None,
+            line_time_limit: None,
+            time_limit: None,
+            cancellation_token: None,
+            preserve_line_endings: false,
         }
     }
 
@@ -102,6 +117,65 @@ impl HighlightOptions {
         self.fallback_to_plain = value;
         self
     }
+
+    /// Lines longer than that many bytes stop being tokenized after that length: the rest of
+    /// the line gets the scopes active at that point, like `editor.maxTokenizationLineLength`
+    /// in VSCode. The line is reported in `HighlightedCode::degraded_lines`.
+    pub fn max_line_length(mut self, value: usize) -> Self {
+        self.max_line_length = Some(value);
+        self
+    }
+
+    /// Maximum time spent tokenizing a single line. Once reached, the rest of the line
+    /// gets the scopes active at that point and the line is reported in
+    /// `HighlightedCode::degraded_lines`.
+    pub fn line_time_limit(mut self, value: Duration) -> Self {
+        self.line_time_limit = Some(value);
+        self
+    }
+
+    /// Maximum time spent tokenizing the whole content, starting when highlighting starts.
+    /// Once reached, the current line is stopped like with `line_time_limit` and the following
+    /// lines are not tokenized at all: they get the scopes active at that point and are all
+    /// reported in `HighlightedCode::degraded_lines`.
+    pub fn time_limit(mut self, value: Duration) -> Self {
+        self.time_limit = Some(value);
+        self
+    }
+
+    /// A token that can be used to cancel highlighting from another thread, in which
+    /// case `Error::Cancelled` is returned.
+    pub fn cancellation_token(mut self, value: CancellationToken) -> Self {
+        self.cancellation_token = Some(value);
+        self
+    }
+
+    /// Keeps track of the line terminator of each line, `\n`, `\r\n` or `\r`, in
+    /// `HighlightedCode::line_endings` so the renderers can output them instead of always
+    /// using `\n`, and `HighlightedCode::line_offsets` matches the original content.
+    ///
+    /// Only used by `Registry::highlight`, `Registry::tokenize_code` and `Registry::highlight_many`.
+    pub fn preserve_line_endings(mut self, value: bool) -> Self {
+        self.preserve_line_endings = value;
+        self
+    }
+
+    /// The tokenizer limits, with deadlines starting now
+    pub(crate) fn limits(&self) -> Limits {
+        Limits {
+            max_line_length: self.max_line_length,
+            line_time_limit: self.line_time_limit,
+            deadline: self.time_limit.map(|limit| Instant::now() + limit),
+            cancellation: self.cancellation_token.clone(),
+        }
+    }
+
+    pub(crate) fn merging_options(&self) -> MergingOptions {
+        MergingOptions {
+            merge_whitespaces: self.merge_whitespaces,
+            merge_same_style_tokens: self.merge_same_style_tokens,
+        }
+    }
 }
 
 /// Highlighted code with language, theme, and tokens
@@ -113,6 +187,88 @@ pub struct HighlightedCode<'a> {
     pub theme: ThemeVariant<&'a CompiledTheme>,
     /// The generated tokens. Each line is a Vector
     pub tokens: Vec<Vec<HighlightedText>>,
+    /// The 0-based indices of the lines that were not fully tokenized because of the limits
+    /// set in the `HighlightOptions`, sorted. Part of those lines kept the scopes active
+    /// when the limit was reached.
+    pub degraded_lines: Vec<usize>,
+    /// The terminator of each line in the original content if the
+    /// `HighlightOptions::preserve_line_endings` option was set. Empty otherwise, every line
+    /// being considered to end with `\n`.
+    pub line_endings: Vec<LineEnding>,
+}
+
+impl HighlightedCode<'_> {
+    /// The terminator of the line at the given index: the original one if the line endings
+    /// were preserved, `\n` otherwise except for the last line.
+    pub fn line_ending(&self, line: usize) -> LineEnding {
+        if let Some(line_ending) = self.line_endings.get(line) {
+            *line_ending
+        } else if line + 1 < self.tokens.len() {
+            LineEnding::Lf
+        } else {
+            LineEnding::None
+        }
+    }
+
+    /// The byte offset of the start of each line in the highlighted content.
+    /// Add them to the spans of the tokens to get offsets in the whole content.
+    ///
+    /// They are offsets in the original content only if the line endings were preserved,
+    /// otherwise in the content with all line terminators replaced by `\n`.
+    pub fn line_offsets(&self) -> Vec<usize> {
+        let mut offset = 0;
+        self.tokens
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let line_start = offset;
+                offset += line.iter().map(|t| t.text.len()).sum::<usize>();
+                offset += self.line_ending(i).as_str().len();
+                line_start
+            })
+            .collect()
+    }
+
+    /// The span of each token in its line in the given unit, in the same shape as `tokens`.
+    /// Useful when the positions are used by something not working with UTF-8 bytes, like
+    /// a browser or a terminal.
+    pub fn spans(&self, unit: OffsetUnit) -> Vec<Vec<Range<usize>>> {
+        self.tokens.iter().map(|line| unit.spans(line)).collect()
+    }
+}
+
+/// Code that went through the tokenizer but has no theme applied yet.
+///
+/// Created by `Registry::tokenize_code`. Give it to `Registry::apply_theme` as many times as
+/// needed to highlight the same code with different themes without tokenizing it again.
+#[derive(Debug, Clone)]
+pub struct TokenizedCode<'a> {
+    language: &'a str,
+    /// The normalized content, the spans of the tokens are relative to its lines
+    content: String,
+    tokens: Vec<Vec<Token>>,
+    degraded_lines: Vec<usize>,
+    merging_options: MergingOptions,
+    /// Only set if the line endings need to be preserved
+    line_endings: Vec<LineEnding>,
+}
+
+impl<'a> TokenizedCode<'a> {
+    /// The language of the grammar used to tokenize the code
+    pub fn language(&self) -> &'a str {
+        self.language
+    }
+
+    /// The tokens of each line
+    pub fn tokens(&self) -> &[Vec<Token>] {
+        &self.tokens
+    }
+
+    /// The 0-based indices of the lines that were not fully tokenized because of the limits
+    /// set in the `HighlightOptions`, sorted.
+    pub fn degraded_lines(&self) -> &[usize] {
+        &self.degraded_lines
+    }
 }
 
 #[inline]
@@ -120,6 +276,57 @@ pub(crate) fn normalize_string(s: &str) -> String {
     s.replace("\r\n", "\n").replace('\r', "\n")
 }
 
+/// How a line ended in the original content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LineEnding {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+    /// `\r` not followed by `\n`
+    Cr,
+    /// The last line of the content has no terminator
+    None,
+}
+
+impl LineEnding {
+    /// The line terminator itself
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+            LineEnding::None => "",
+        }
+    }
+}
+
+/// The terminator of each line of `s`, the lines being the ones of
+/// `normalize_string(s).split('\n')`.
+/// Empty content has no line at all, like when tokenizing it.
+pub(crate) fn line_endings(s: &str) -> Vec<LineEnding> {
+    if s.is_empty() {
+        return Vec::new();
+    }
+    let bytes = s.as_bytes();
+    let mut line_endings = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\r' if bytes.get(i + 1) == Some(&b'\n') => {
+                line_endings.push(LineEnding::CrLf);
+                i += 1;
+            }
+            b'\r' => line_endings.push(LineEnding::Cr),
+            b'\n' => line_endings.push(LineEnding::Lf),
+            _ => {}
+        }
+        i += 1;
+    }
+    line_endings.push(LineEnding::None);
+    line_endings
+}
+
 /// The main struct in giallo.
 ///
 /// Holds all the grammars and themes and is responsible for highlighting a text. It is not
@@ -140,11 +347,19 @@ pub struct Registry {
     // Most of the inner vecs will be empty since few grammars use injectTo
     injections_by_grammar: Vec<HashSet<GrammarId>>,
     // Once a registry has linked grammars, it's not possible to replace existing grammars.
-    linked: bool,
+    pub(crate) linked: bool,
     // We cache the pattern set at the registry level it's compiled only once instead of per
     // highlight. To do that we had to check the end regex in the tokenizer separately from the
     // regset.
-    pattern_cache: papaya::HashMap<(GrammarId, GlobalRuleRef), Arc<PatternSet>>,
+    // It is shared with the clones of the registry until one of them changes its grammars.
+    pattern_cache: Arc<papaya::HashMap<(GrammarId, GlobalRuleRef), Arc<PatternSet>>>,
+    // The scopes of the grammars and themes are only meaningful for the repository that created
+    // them so it is shared with the clones of the registry.
+    pub(crate) scope_repo: Arc<ScopeRepository>,
+    // What VSCode extensions declared about their grammars besides the grammars themselves
+    grammar_contributions: HashMap<GrammarId, GrammarContribution>,
+    // The files each language added by VSCode extensions is for, in the order they were added
+    language_files: Vec<LanguageFiles>,
 }
 
 impl Clone for Registry {
@@ -156,7 +371,10 @@ impl Clone for Registry {
             themes: self.themes.clone(),
             injections_by_grammar: self.injections_by_grammar.clone(),
             linked: self.linked,
-            pattern_cache: papaya::HashMap::new(),
+            pattern_cache: Arc::clone(&self.pattern_cache),
+            scope_repo: Arc::clone(&self.scope_repo),
+            grammar_contributions: self.grammar_contributions.clone(),
+            language_files: self.language_files.clone(),
         }
     }
 }
@@ -165,11 +383,15 @@ impl Registry {
     #[cfg(feature = "dump")]
     /// Restore a registry from a list of grammars and themes.
     /// This is used in loading dumps.
-    fn restore(grammars: Vec<CompiledGrammar>, all_themes: Vec<CompiledTheme>) -> Self {
+    fn restore(
+        grammars: Vec<CompiledGrammar>,
+        all_themes: Vec<CompiledTheme>,
+        scope_repo: ScopeRepository,
+    ) -> Self {
         let mut grammar_id_by_scope_name = HashMap::with_capacity(grammars.len());
         let mut grammar_id_by_name = HashMap::with_capacity(grammars.len());
         let mut injections_by_grammar = Vec::with_capacity(grammars.len());
-        let pattern_cache = papaya::HashMap::new();
+        let pattern_cache = Arc::default();
         let mut themes = HashMap::with_capacity(all_themes.len());
 
         for grammar in &grammars {
@@ -190,20 +412,31 @@ impl Registry {
             injections_by_grammar,
             linked: false,
             pattern_cache,
+            scope_repo: Arc::new(scope_repo),
+            grammar_contributions: HashMap::new(),
+            language_files: Vec::new(),
         };
         this.link_grammars();
 
         this
     }
 
-    fn add_grammar_from_raw(&mut self, raw_grammar: RawGrammar) -> GialloResult<()> {
-        if self.linked && self.grammar_id_by_name.contains_key(&raw_grammar.name) {
-            return Err(Error::ReplacingGrammarPostLinking(
-                raw_grammar.name.to_owned(),
-            ));
+    /// Grammars can't be replaced once they are linked
+    fn check_can_add_grammar(&self, name: &str) -> GialloResult<()> {
+        if self.linked && self.grammar_id_by_name.contains_key(name) {
+            return Err(Error::ReplacingGrammarPostLinking(name.to_owned()));
+        }
+        Ok(())
+    }
+
+    fn add_grammar_from_raw(&mut self, mut raw_grammar: RawGrammar) -> GialloResult<()> {
+        if raw_grammar.name.is_empty() {
+            raw_grammar.name = raw_grammar.scope_name.clone();
         }
+        self.check_can_add_grammar(&raw_grammar.name)?;
         let grammar_id = GrammarId(self.grammars.len() as u16);
-        let grammar = CompiledGrammar::from_raw_grammar(raw_grammar, grammar_id);
+        let grammar = CompiledGrammar::from_raw_grammar(raw_grammar, grammar_id, &self.scope_repo);
+        self.detach_pattern_cache();
         let grammar_name = grammar.name.to_lowercase();
         let grammar_scope_name = grammar.scope_name.clone();
         self.grammars.push(grammar);
@@ -215,11 +448,27 @@ impl Registry {
     }
 
     /// Reads the file and add it as a grammar.
+    ///
+    /// The format is guessed from the extension: `.json` for JSON, `.tmLanguage`/`.plist` for
+    /// XML plist and `.yaml`/`.yml` for YAML, or from the content for other extensions.
     pub fn add_grammar_from_path(&mut self, path: impl AsRef<Path>) -> GialloResult<()> {
         let raw_grammar = RawGrammar::load_from_file(path)?;
         self.add_grammar_from_raw(raw_grammar)
     }
 
+    /// Adds a grammar from its content in JSON, XML plist or YAML, the format being guessed
+    /// from the content.
+    pub fn add_grammar_from_str(&mut self, content: &str) -> GialloResult<()> {
+        let raw_grammar = RawGrammar::load_from_str(content, GrammarFormat::detect(content))?;
+        self.add_grammar_from_raw(raw_grammar)
+    }
+
+    /// Reads a grammar in JSON, XML plist or YAML, the format being guessed from the content.
+    pub fn add_grammar_from_reader(&mut self, reader: impl Read) -> GialloResult<()> {
+        let raw_grammar = RawGrammar::load_from_reader(reader)?;
+        self.add_grammar_from_raw(raw_grammar)
+    }
+
     /// Adds an empty grammar that will not match any token. Useful as a fallback if the grammar is not found.
     ///
     /// It will get the `plain` grammar name.
@@ -248,14 +497,129 @@ impl Registry {
     }
 
     /// Reads the file and add it as a theme.
+    ///
+    /// Files with the `.tmTheme` extension are loaded as Sublime Text/TextMate colour schemes
+    /// if the `plist` feature is enabled, other files as VSCode JSON themes.
     pub fn add_theme_from_path(&mut self, path: impl AsRef<Path>) -> GialloResult<()> {
         let raw_theme = RawTheme::load_from_file(path)?;
-        let compiled_theme = raw_theme.compile()?;
+        self.add_theme_from_raw(raw_theme)
+    }
+
+    fn add_theme_from_raw(&mut self, raw_theme: RawTheme) -> GialloResult<()> {
+        let compiled_theme = raw_theme.compile(&self.scope_repo)?;
         self.themes
             .insert(compiled_theme.name.to_lowercase(), compiled_theme);
         Ok(())
     }
 
+    /// Adds the grammars, languages and themes contributed by the VSCode extension in the given
+    /// directory, as declared in the `contributes` section of its `package.json`:
+    ///
+    /// - grammars are added like with `add_grammar_from_path`, with their `injectTo` scopes,
+    ///   under the id of their language or, for injection grammars, their `scopeName`.
+    ///   The aliases of the language can also be used in `HighlightOptions`.
+    ///   Their `embeddedLanguages` and `tokenTypes` are available from `grammar_contribution`.
+    /// - the `extensions`, `filenames` and `firstLine` of languages are used by `find_language`.
+    /// - themes are added with their `label` as name, light or dark depending on their
+    ///   `uiTheme`.
+    ///
+    /// Like other grammars, `link_grammars` needs to be called after adding extensions.
+    ///
+    /// Aliases are only added for languages whose grammar is in the registry, from this
+    /// extension or one added before: the aliases of other languages are ignored.
+    ///
+    /// All the grammars and themes are loaded before adding anything so the registry is left
+    /// unchanged if any of them fails to load.
+    pub fn add_vscode_extension(&mut self, path: impl AsRef<Path>) -> GialloResult<()> {
+        let extension_dir = path.as_ref();
+        let contributes = ExtensionManifest::load(extension_dir)?.contributes;
+
+        let mut grammars = Vec::with_capacity(contributes.grammars.len());
+        for entry in contributes.grammars {
+            let mut raw_grammar = RawGrammar::load_from_file(extension_dir.join(&entry.path))?;
+            // The names in grammar files are not unique, injection grammars often sharing one
+            raw_grammar.name = entry.language.unwrap_or_else(|| entry.scope_name.clone());
+            raw_grammar.scope_name = entry.scope_name;
+            raw_grammar.inject_to.extend(entry.inject_to);
+            self.check_can_add_grammar(&raw_grammar.name)?;
+            let contribution = GrammarContribution {
+                embedded_languages: entry.embedded_languages,
+                token_types: entry.token_types,
+            };
+            grammars.push((raw_grammar, contribution));
+        }
+
+        let mut themes = Vec::with_capacity(contributes.themes.len());
+        for entry in contributes.themes {
+            let mut raw_theme = RawTheme::load_from_file(extension_dir.join(&entry.path))?;
+            raw_theme.kind = Some(entry.kind().to_owned());
+            raw_theme.name = entry.label;
+            themes.push(raw_theme.compile(&self.scope_repo)?);
+        }
+
+        // Nothing can fail from here
+        for (raw_grammar, contribution) in grammars {
+            let scope_name = raw_grammar.scope_name.clone();
+            self.add_grammar_from_raw(raw_grammar)?;
+            if contribution != GrammarContribution::default() {
+                let grammar_id = self.grammar_id_by_scope_name[&scope_name];
+                self.grammar_contributions.insert(grammar_id, contribution);
+            }
+        }
+
+        for language in &contributes.languages {
+            for alias in &language.aliases {
+                self.add_alias(&language.id, alias);
+            }
+            self.language_files.push(LanguageFiles::new(language));
+        }
+
+        for theme in themes {
+            self.themes.insert(theme.name.to_lowercase(), theme);
+        }
+
+        Ok(())
+    }
+
+    /// What the VSCode extension that added the grammar of the given language declared about
+    /// it, if it declared anything.
+    pub fn grammar_contribution(&self, lang: &str) -> Option<&GrammarContribution> {
+        let grammar_id = self.grammar_id_by_name.get(&lang.to_lowercase())?;
+        self.grammar_contributions.get(grammar_id)
+    }
+
+    /// Finds the language of a file from the languages added by VSCode extensions, like VSCode
+    /// does: from its filename first, then its extension, the longest matching one winning,
+    /// and finally its first line if given.
+    /// Returns the language id, which can be used in `HighlightOptions`.
+    pub fn find_language(&self, path: impl AsRef<Path>, first_line: Option<&str>) -> Option<&str> {
+        let filename = path.as_ref().file_name()?.to_str()?;
+        let language = self
+            .language_files
+            .iter()
+            .find(|language| language.matches_filename(filename))
+            .or_else(|| {
+                self.language_files
+                    .iter()
+                    .filter_map(|language| {
+                        language
+                            .matching_extension_len(filename)
+                            .map(|len| (len, language))
+                    })
+                    // The first language added wins between extensions of the same length
+                    .rev()
+                    .max_by_key(|(len, _)| *len)
+                    .map(|(_, language)| language)
+            })
+            .or_else(|| {
+                let first_line = first_line?;
+                self.language_files
+                    .iter()
+                    .find(|language| language.matches_first_line(first_line))
+            })?;
+        Some(&language.id)
+    }
+
     /// Generates CSS stylesheet content for a theme.
     /// All classes will have the given prefix.
     ///
@@ -297,18 +661,145 @@ impl Registry {
         ))
     }
 
-    pub(crate) fn tokenize(
+    /// Tokenizes the content, returning the tokens as well as the lines that got degraded
+    /// because of the limits.
+    pub(crate) fn tokenize_with_limits(
         &self,
         grammar_id: GrammarId,
         content: &str,
-    ) -> GialloResult<Vec<Vec<Token>>> {
+        limits: Limits,
+    ) -> GialloResult<(Vec<Vec<Token>>, Vec<usize>)> {
         let mut tokenizer = Tokenizer::new(grammar_id, self);
+        tokenizer.set_limits(limits);
         let tokens = tokenizer
             .tokenize_string(content)
             .map_err(Error::TokenizeRegex)?;
+        if tokenizer.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
         Ok(tokens)
     }
 
+    /// Tokenizes the text with the grammar of `lang`, without applying any theme.
+    ///
+    /// This is the equivalent of calling `tokenizeLine` from vscode-textmate on every line: it
+    /// returns the tokens of each line, with byte spans relative to the line and the scopes
+    /// that apply to them. Line endings are normalized like in `highlight`.
+    ///
+    /// Make sure `link_grammars` is called before calling `tokenize`, this will error otherwise.
+    pub fn tokenize(&self, lang: &str, text: &str) -> GialloResult<Vec<Vec<Token>>> {
+        if !self.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let grammar_id = self.find_grammar_id(&lang.to_lowercase(), false)?;
+        let (tokens, _) =
+            self.tokenize_with_limits(grammar_id, &normalize_string(text), Limits::default())?;
+        Ok(tokens)
+    }
+
+    /// The full name of a scope, eg `string.quoted.double.js`.
+    ///
+    /// The scope needs to come from this registry, eg from the tokens returned by `tokenize`.
+    pub fn scope_name(&self, scope: &Scope) -> String {
+        self.scope_repo.to_string(scope)
+    }
+
+    /// Finds the grammar for the given lowercased lang, optionally falling back to the plain grammar
+    pub(crate) fn find_grammar_id(
+        &self,
+        lang: &str,
+        fallback_to_plain: bool,
+    ) -> GialloResult<GrammarId> {
+        self.grammar_id_by_name
+            .get(lang)
+            .or_else(|| {
+                if fallback_to_plain {
+                    self.grammar_id_by_name.get(PLAIN_GRAMMAR_NAME)
+                } else {
+                    None
+                }
+            })
+            .copied()
+            .ok_or_else(|| Error::GrammarNotFound(lang.to_owned()))
+    }
+
+    /// Finds the compiled theme(s) for the given lowercased theme name(s)
+    pub(crate) fn find_themes(
+        &self,
+        theme: &ThemeVariant<String>,
+    ) -> GialloResult<ThemeVariant<&CompiledTheme>> {
+        let get = |name: &String| {
+            self.themes
+                .get(name)
+                .ok_or_else(|| Error::ThemeNotFound(name.clone()))
+        };
+        match theme {
+            ThemeVariant::Single(name) => Ok(ThemeVariant::Single(get(name)?)),
+            ThemeVariant::Dual { light, dark } => Ok(ThemeVariant::Dual {
+                light: get(light)?,
+                dark: get(dark)?,
+            }),
+        }
+    }
+
+    /// Tokenizes a single line of `lang`, starting from the state returned for the previous line.
+    ///
+    /// Use `LineState::default()` for the first line of a document. A trailing line terminator
+    /// (`\n`, `\r\n` or `\r`) is ignored, any other line break in `line` is not handled.
+    /// This returns the tokens for that line, with byte spans relative to the line, as well
+    /// as the state to use for the next line.
+    ///
+    /// Make sure `link_grammars` is called before calling `tokenize_line`, this will error otherwise.
+    pub fn tokenize_line(
+        &self,
+        lang: &str,
+        line: &str,
+        state: &LineState,
+    ) -> GialloResult<(Vec<Token>, LineState)> {
+        if !self.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let grammar_id = self.find_grammar_id(&lang.to_lowercase(), false)?;
+        if let Some(stack) = &state.stack
+            && !self.is_valid_stack(grammar_id, stack)
+        {
+            return Err(Error::InvalidLineState);
+        }
+
+        let line = line
+            .strip_suffix('\n')
+            .map(|l| l.strip_suffix('\r').unwrap_or(l))
+            .or_else(|| line.strip_suffix('\r'))
+            .unwrap_or(line);
+        let stack = state.stack.as_ref().map(|stack| match &state.scope_repo {
+            Some(scope_repo) if Arc::ptr_eq(scope_repo, &self.scope_repo) => stack.clone(),
+            // Deserialized or from another registry: the same atoms can have other numbers
+            _ => {
+                let atom_names = state.atom_names();
+                stack.map_scopes(|scope| self.scope_repo.reintern(scope, &atom_names))
+            }
+        });
+        let mut tokenizer = Tokenizer::new(grammar_id, self);
+        let (tokens, stack) = tokenizer
+            .tokenize_next_line(line, stack)
+            .map_err(Error::TokenizeRegex)?;
+        Ok((tokens, LineState::new(stack, &self.scope_repo)))
+    }
+
+    /// Line states can be deserialized so we need to make sure they are pointing to rules
+    /// that exist before using them
+    fn is_valid_stack(&self, grammar_id: GrammarId, stack: &StateStack) -> bool {
+        let Some(root) = stack.frames.first() else {
+            return false;
+        };
+        root.rule_ref.grammar == grammar_id
+            && stack.frames.iter().all(|frame| {
+                self.grammars
+                    .get(frame.rule_ref.grammar.as_index())
+                    .is_some_and(|g| frame.rule_ref.rule.as_index() < g.rules.len())
+            })
+    }
+
     /// Checks whether the given lang is available in the registry with its grammar name
     /// or aliases
     pub fn contains_grammar(&self, name: &str) -> bool {
@@ -335,78 +826,261 @@ impl Registry {
         if !self.linked {
             return Err(Error::UnlinkedGrammars);
         }
-        let grammar_id = *self
-            .grammar_id_by_name
-            .get(&options.lang)
-            .or_else(|| {
-                if options.fallback_to_plain {
-                    self.grammar_id_by_name.get(PLAIN_GRAMMAR_NAME)
-                } else {
-                    None
-                }
-            })
-            .ok_or_else(|| Error::GrammarNotFound(options.lang.clone()))?;
+        let tokenized = self.tokenize_code(content, options)?;
+        let theme = self.find_themes(&options.theme)?;
+        Ok(self.apply_themes(&tokenized, theme))
+    }
 
-        let normalized_content = normalize_string(content);
-        let tokens = self.tokenize(grammar_id, &normalized_content)?;
+    /// Highlights content given as bytes, eg a file of unknown encoding, which is decoded with
+    /// `DecodedText::decode` first.
+    ///
+    /// Returns `Error::BinaryContent` if the bytes look like binary content rather than text.
+    /// Use `HighlightedBytes::original_spans` to get the positions of the tokens in the bytes.
+    pub fn highlight_bytes(
+        &self,
+        bytes: &[u8],
+        options: &HighlightOptions,
+    ) -> GialloResult<HighlightedBytes<'_>> {
+        let decoded = DecodedText::decode(bytes);
+        if decoded.binary {
+            return Err(Error::BinaryContent);
+        }
+        let highlighted = self.highlight(&decoded.text, options)?;
+        Ok(HighlightedBytes {
+            decoded,
+            highlighted,
+        })
+    }
 
-        let merging_options = MergingOptions {
-            merge_whitespaces: options.merge_whitespaces,
-            merge_same_style_tokens: options.merge_same_style_tokens,
-        };
+    /// Highlights many independent pieces of content, like all the code blocks of a site,
+    /// spreading them over `num_threads` threads.
+    ///
+    /// Returns the result of `highlight` for each item, in the same order as the items: an
+    /// item failing doesn't prevent the others from being highlighted.
+    /// The compiled patterns and the styles computed by the themes are shared by all the
+    /// threads, including the ones started by later calls, and kept for the next highlights.
+    pub fn highlight_many<S>(
+        &self,
+        items: impl IntoIterator<Item = (S, HighlightOptions)>,
+        num_threads: usize,
+    ) -> Vec<GialloResult<HighlightedCode<'_>>>
+    where
+        S: AsRef<str> + Sync,
+    {
+        let items: Vec<_> = items.into_iter().collect();
+        let num_threads = num_threads.clamp(1, items.len().max(1));
+        if num_threads == 1 {
+            return items
+                .iter()
+                .map(|(content, options)| self.highlight(content.as_ref(), options))
+                .collect();
+        }
 
-        match &options.theme {
-            ThemeVariant::Single(theme_name) => {
-                let theme = self
-                    .themes
-                    .get(theme_name)
-                    .ok_or_else(|| Error::ThemeNotFound(theme_name.clone()))?;
-
-                let mut highlighter = Highlighter::new(theme);
-                let highlighted_tokens =
-                    highlighter.highlight_tokens(&normalized_content, tokens, merging_options);
-
-                Ok(HighlightedCode {
-                    language: &self.grammars[grammar_id].name,
-                    theme: ThemeVariant::Single(theme),
-                    tokens: highlighted_tokens,
+        // Items can take very different times to highlight so the threads pick the next one
+        // when they are done rather than getting their share upfront
+        let next_item = AtomicUsize::new(0);
+        let mut results: Vec<_> = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut results = Vec::new();
+                        loop {
+                            let index = next_item.fetch_add(1, Ordering::Relaxed);
+                            let Some((content, options)) = items.get(index) else {
+                                break;
+                            };
+                            results.push((index, self.highlight(content.as_ref(), options)));
+                        }
+                        results
+                    })
                 })
-            }
-            ThemeVariant::Dual { light, dark } => {
-                let light_theme = self
-                    .themes
-                    .get(light)
-                    .ok_or_else(|| Error::ThemeNotFound(light.clone()))?;
-                let dark_theme = self
-                    .themes
-                    .get(dark)
-                    .ok_or_else(|| Error::ThemeNotFound(dark.clone()))?;
-
-                let mut highlighter = Highlighter::new_dual(light_theme, dark_theme);
-                let highlighted_tokens =
-                    highlighter.highlight_tokens(&normalized_content, tokens, merging_options);
-
-                Ok(HighlightedCode {
-                    language: &self.grammars[grammar_id].name,
-                    theme: ThemeVariant::Dual {
-                        light: light_theme,
-                        dark: dark_theme,
-                    },
-                    tokens: highlighted_tokens,
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
                 })
+                .collect()
+        });
+
+        results.sort_unstable_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Runs the tokenizer on the content, with the language, limits and merging options of
+    /// `options`, without applying any theme.
+    ///
+    /// The theme(s) of `options` are not used: apply them, or any other theme, with
+    /// `apply_theme`. This is the same as `highlight` when you need more than one theme for the
+    /// same content since tokenizing is the expensive part.
+    ///
+    /// Make sure `link_grammars` is called before calling `tokenize_code`, this will error otherwise.
+    pub fn tokenize_code(
+        &self,
+        content: &str,
+        options: &HighlightOptions,
+    ) -> GialloResult<TokenizedCode<'_>> {
+        if !self.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let grammar_id = self.find_grammar_id(&options.lang, options.fallback_to_plain)?;
+
+        let normalized_content = normalize_string(content);
+        let (tokens, degraded_lines) =
+            self.tokenize_with_limits(grammar_id, &normalized_content, options.limits())?;
+
+        Ok(TokenizedCode {
+            language: &self.grammars[grammar_id].name,
+            content: normalized_content,
+            tokens,
+            degraded_lines,
+            merging_options: options.merging_options(),
+            line_endings: if options.preserve_line_endings {
+                line_endings(content)
+            } else {
+                Vec::new()
+            },
+        })
+    }
+
+    /// Applies the given theme(s) to code tokenized by `tokenize_code`, giving the same result
+    /// as `highlight` with those themes. Theme names are case-insensitive.
+    ///
+    /// The tokenized code needs to come from this registry.
+    pub fn apply_theme<'a>(
+        &'a self,
+        tokenized: &TokenizedCode<'a>,
+        theme: ThemeVariant<&str>,
+    ) -> GialloResult<HighlightedCode<'a>> {
+        let theme = self.find_themes(&theme.to_lowercase())?;
+        Ok(self.apply_themes(tokenized, theme))
+    }
+
+    fn apply_themes<'a>(
+        &'a self,
+        tokenized: &TokenizedCode<'a>,
+        theme: ThemeVariant<&'a CompiledTheme>,
+    ) -> HighlightedCode<'a> {
+        let mut highlighter = Highlighter::from_themes(theme);
+        let tokens = highlighter.highlight_tokens(
+            &tokenized.content,
+            &tokenized.tokens,
+            tokenized.merging_options,
+        );
+
+        HighlightedCode {
+            language: tokenized.language,
+            theme,
+            tokens,
+            degraded_lines: tokenized.degraded_lines.clone(),
+            line_endings: tokenized.line_endings.clone(),
+        }
+    }
+
+    /// Highlights the content of a reader line by line.
+    ///
+    /// Unlike `highlight`, the content is never fully loaded in memory: each line is read,
+    /// tokenized and highlighted when the returned iterator is advanced. The renderers can write
+    /// those lines progressively with their `render_to_writer` method.
+    ///
+    /// Make sure `link_grammars` is called before calling `highlight_reader`, this will error otherwise.
+    pub fn highlight_reader<R: BufRead>(
+        &self,
+        reader: R,
+        options: &HighlightOptions,
+    ) -> GialloResult<HighlightedLines<'_, R>> {
+        if !self.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let grammar_id = self.find_grammar_id(&options.lang, options.fallback_to_plain)?;
+        let theme = self.find_themes(&options.theme)?;
+
+        Ok(HighlightedLines::new(
+            self, reader, grammar_id, theme, options,
+        ))
+    }
+
+    /// Explains how the token at the given position of `content` was highlighted: its scopes,
+    /// the grammar rule that produced it and the theme rules that styled it.
+    ///
+    /// `line` is 0-based and `column` is a byte offset in that line, lines being split the
+    /// same way as in `highlight`. Tokens are the ones from the tokenizer, before any merging.
+    /// Returns `None` if there is no token at that position, eg on an empty line.
+    ///
+    /// Make sure `link_grammars` is called before calling `inspect`, this will error otherwise.
+    pub fn inspect(
+        &self,
+        content: &str,
+        options: &HighlightOptions,
+        line: usize,
+        column: usize,
+    ) -> GialloResult<Option<TokenInspection>> {
+        if !self.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let grammar_id = self.find_grammar_id(&options.lang, options.fallback_to_plain)?;
+        let theme = self.find_themes(&options.theme)?;
+
+        inspect_token(
+            self,
+            grammar_id,
+            theme,
+            &normalize_string(content),
+            line,
+            column,
+            options.limits(),
+        )
+    }
+
+    /// Same as `inspect` but with a byte offset in `content`, before line endings are
+    /// normalized.
+    ///
+    /// Returns `None` if the offset points to a line terminator or is out of bounds.
+    pub fn inspect_offset(
+        &self,
+        content: &str,
+        options: &HighlightOptions,
+        offset: usize,
+    ) -> GialloResult<Option<TokenInspection>> {
+        let bytes = content.as_bytes();
+        if offset >= bytes.len() || matches!(bytes[offset], b'\n' | b'\r') {
+            return Ok(None);
+        }
+
+        let mut line = 0;
+        let mut line_start = 0;
+        for (i, &b) in bytes[..offset].iter().enumerate() {
+            match b {
+                // \r\n is a single terminator, the line starts after the \n
+                b'\r' if bytes.get(i + 1) == Some(&b'\n') => {}
+                b'\n' | b'\r' => {
+                    line += 1;
+                    line_start = i + 1;
+                }
+                _ => {}
             }
         }
+
+        self.inspect(content, options, line, offset - line_start)
     }
 
     /// Will find all references to external grammars and use the correct target for them.
     /// This needs to be called before trying to highlight anything.
     pub fn link_grammars(&mut self) {
+        self.detach_pattern_cache();
         for i in 0..self.grammars.len() {
             resolve_external_references(i, &mut self.grammars, &self.grammar_id_by_scope_name);
 
             let grammar = &self.grammars[i];
             for inject_to in &grammar.inject_to {
-                if let Some(g_id) = self.grammar_id_by_name.get(inject_to) {
+                // Grammar names in our metadata, scope names in VSCode extensions
+                if let Some(g_id) = self
+                    .grammar_id_by_name
+                    .get(inject_to)
+                    .or_else(|| self.grammar_id_by_scope_name.get(inject_to))
+                {
                     self.injections_by_grammar[g_id.as_index()].insert(grammar.id);
                 }
             }
@@ -415,6 +1089,98 @@ impl Registry {
         self.linked = true;
     }
 
+    /// Compiles ahead of time everything needed to highlight the given languages so the first
+    /// highlight of each doesn't have to, spreading the work over `num_threads` threads.
+    ///
+    /// The compiled patterns are shared by all the threads using this registry and its clones,
+    /// including the ones cloned before calling this.
+    /// Patterns that fail to compile are skipped: the error will be returned by the highlights
+    /// that need them, like without warming up.
+    ///
+    /// Make sure `link_grammars` is called before calling `warm_up`, this will error otherwise.
+    pub fn warm_up(&self, languages: &[&str], num_threads: usize) -> GialloResult<()> {
+        if !self.linked {
+            return Err(Error::UnlinkedGrammars);
+        }
+        let mut pattern_sets = Vec::new();
+        for lang in languages {
+            let grammar_id = self.find_grammar_id(&lang.to_lowercase(), false)?;
+            pattern_sets.extend(
+                self.reachable_pattern_sets(grammar_id)
+                    .into_iter()
+                    .map(|rule_ref| (grammar_id, rule_ref)),
+            );
+        }
+
+        let num_threads = num_threads.clamp(1, pattern_sets.len().max(1));
+        std::thread::scope(|s| {
+            for thread_index in 0..num_threads {
+                let pattern_sets = &pattern_sets;
+                s.spawn(move || {
+                    for &(grammar_id, rule_ref) in
+                        pattern_sets.iter().skip(thread_index).step_by(num_threads)
+                    {
+                        let _ = self.get_or_create_pattern_set(grammar_id, rule_ref);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    /// The rules whose pattern set can be needed when highlighting with the given grammar:
+    /// its root rule, the rules that can be pushed on the stack, the captures that are
+    /// tokenized again and the injections.
+    fn reachable_pattern_sets(&self, base_grammar_id: GrammarId) -> Vec<GlobalRuleRef> {
+        let mut to_visit = vec![GlobalRuleRef {
+            grammar: base_grammar_id,
+            rule: ROOT_RULE_ID,
+        }];
+        to_visit.extend(
+            self.grammars[base_grammar_id]
+                .injections
+                .iter()
+                .map(|(_, rule)| *rule),
+        );
+        to_visit.extend(
+            self.injections_by_grammar[base_grammar_id.as_index()]
+                .iter()
+                .map(|&injector_id| GlobalRuleRef {
+                    grammar: injector_id,
+                    rule: ROOT_RULE_ID,
+                }),
+        );
+
+        let mut visited = HashSet::new();
+        let mut out = Vec::new();
+        while let Some(rule_ref) = to_visit.pop() {
+            if !visited.insert(rule_ref) {
+                continue;
+            }
+            out.push(rule_ref);
+
+            for (matched, _) in self.collect_patterns(base_grammar_id, rule_ref) {
+                let rule = &self.grammars[matched.grammar].rules[matched.rule];
+                if matches!(rule, Rule::BeginEnd(_) | Rule::BeginWhile(_)) {
+                    to_visit.push(matched);
+                }
+                to_visit.extend(rule.captures().filter(|capture| {
+                    self.grammars[capture.grammar].rules[capture.rule].has_patterns()
+                }));
+            }
+        }
+
+        out
+    }
+
+    /// Stops sharing the pattern cache with the clones of this registry, and empties it.
+    /// Needs to be called whenever the grammars change since the cached pattern sets might not
+    /// be valid anymore.
+    fn detach_pattern_cache(&mut self) {
+        self.pattern_cache = Arc::default();
+    }
+
     fn get_rule_patterns(
         &self,
         base_grammar_id: GrammarId,
@@ -537,15 +1303,23 @@ impl Registry {
         self.pattern_cache.pin().clear();
     }
 
+    #[doc(hidden)]
+    pub fn clear_style_caches(&self) {
+        for theme in self.themes.values() {
+            theme.style_cache.clear();
+        }
+    }
+
     pub(crate) fn get_or_create_pattern_set(
         &self,
         base_grammar_id: GrammarId,
         rule_ref: GlobalRuleRef,
     ) -> Result<Arc<PatternSet>, String> {
         let cache_key = (base_grammar_id, rule_ref);
-        let guard = self.pattern_cache.guard();
+        let pattern_cache = self.pattern_cache.as_ref();
+        let guard = pattern_cache.guard();
 
-        if let Some(pattern_set) = self.pattern_cache.get(&cache_key, &guard) {
+        if let Some(pattern_set) = pattern_cache.get(&cache_key, &guard) {
             return Ok(Arc::clone(pattern_set));
         }
 
@@ -557,9 +1331,7 @@ impl Registry {
         let pattern_set = Arc::new(PatternSet::new(patterns)?);
 
         // Use get_or_insert for concurrent-safe lazy init
-        let inserted = self
-            .pattern_cache
-            .get_or_insert(cache_key, pattern_set, &guard);
+        let inserted = pattern_cache.get_or_insert(cache_key, pattern_set, &guard);
 
         Ok(Arc::clone(inserted))
     }
@@ -567,15 +1339,11 @@ impl Registry {
     #[cfg(feature = "dump")]
     /// Dump the registry + scope repository to a binary file that can be loaded later.
     pub fn dump(&self) -> GialloResult<Vec<u8>> {
-        use crate::scope::lock_global_scope_repo;
         if self.linked {
             return Err(Error::DumpAfterLinking);
         }
 
-        let dump = {
-            let scope_repo = lock_global_scope_repo();
-            Dump::build(self, &scope_repo)
-        };
+        let dump = Dump::build(self);
 
         let bitcode_data = bitcode::serialize(&dump)?;
         let compressed = zstd::encode_all(bitcode_data.as_slice(), 5)?;
@@ -586,32 +1354,23 @@ impl Registry {
     #[cfg(feature = "dump")]
     /// Loads a byte slice from a dump.
     pub fn load(buf: &[u8]) -> GialloResult<Self> {
-        use crate::scope::replace_global_scope_repo;
-        use std::io::Read;
-
         let mut decoder = zstd::Decoder::new(buf)?;
         let mut data = Vec::new();
         decoder.read_to_end(&mut data)?;
         let dump: Dump = bitcode::deserialize(&data)?;
-        let (registry, scope_repo) = dump.restore();
-        replace_global_scope_repo(scope_repo);
-
-        Ok(registry)
+        Ok(dump.restore())
     }
 
     #[cfg(feature = "dump")]
     /// Read a binary dump from giallo and load registry + scope repository from it
-    /// This is a no-op when called multiple times: eg if you have multiple threads all trying
-    /// to call Registry::load_from_file, only the first one will actually load things.
     pub fn load_from_file(path: impl AsRef<Path>) -> GialloResult<Self> {
         let compressed_data = std::fs::read(path)?;
         Self::load(&compressed_data)
     }
 
     #[cfg(feature = "dump")]
-    /// Load the builtin registry containing all grammars and themes from grammars-themes
-    /// This is a no-op when called multiple times: eg if you have multiple threads all trying
-    /// to call Registry::builtin, only the first one will actually load things.
+    /// Load the builtin registry containing all grammars and themes from grammars-themes.
+    /// Each call decompresses the whole dump: load it once and clone the registry if needed.
     pub fn builtin() -> GialloResult<Self> {
         Self::load(BUILTIN_DATA)
     }
@@ -624,7 +1383,11 @@ mod tests {
     use super::*;
     use crate::highlight::HighlightedText;
     use crate::test_utils::get_registry;
+    use crate::themes::compiled::ThemeType;
     use crate::themes::font_style::FontStyle;
+    use crate::{
+        Color, HtmlRenderer, RenderOptions, Style, TerminalRenderer, TextEncoding, TokenType,
+    };
 
     fn format_highlighted_tokens(
         highlighted_tokens: &[Vec<HighlightedText>],
@@ -681,7 +1444,7 @@ mod tests {
         result
     }
 
-    fn format_tokens(input: &str, lines_tokens: Vec<Vec<Token>>) -> String {
+    fn format_tokens(registry: &Registry, input: &str, lines_tokens: Vec<Vec<Token>>) -> String {
         let normalized = input.replace("\r\n", "\n").replace('\r', "\n");
         let lines: Vec<&str> = normalized.split('\n').collect();
 
@@ -696,7 +1459,7 @@ mod tests {
                     "{}: '{}' (line {})\n", // Match fixture format: [start-end] (line N)
                     token_idx, text, line_idx
                 ));
-                for scope in &token.scopes {
+                for scope in token.scope_names(registry) {
                     out.push_str(&format!("  - {scope}\n"));
                 }
                 out.push('\n');
@@ -741,14 +1504,525 @@ mod tests {
             let sample_path = format!("grammars-themes/samples/{grammar}.sample");
             println!("Checking {sample_path}");
             let sample_content = normalize_string(&fs::read_to_string(sample_path).unwrap());
-            let tokens = registry
-                .tokenize(registry.grammar_id_by_name[&grammar], &sample_content)
-                .unwrap();
-            let out = format_tokens(&sample_content, tokens);
+            let tokens = registry.tokenize(&grammar, &sample_content).unwrap();
+            let out = format_tokens(&registry, &sample_content, tokens);
             assert_eq!(expected.trim(), out.trim());
         }
     }
 
+    #[test]
+    fn can_tokenize_without_theme() {
+        let registry = get_registry();
+        let tokens = registry
+            .tokenize("JavaScript", "let a = \"b\";\r\n")
+            .unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens[1].is_empty());
+
+        let line = &tokens[0];
+        assert_eq!(line.first().unwrap().span.start, 0);
+        assert_eq!(line.last().unwrap().span.end, 12);
+        assert!(line.windows(2).all(|t| t[0].span.end == t[1].span.start));
+        assert!(
+            line.iter()
+                .all(|t| t.scope_names(&registry)[0] == "source.js")
+        );
+        let string_token = line
+            .iter()
+            .find(|t| t.span == (9..10))
+            .unwrap()
+            .scope_names(&registry);
+        assert!(string_token[1].starts_with("string.quoted.double"));
+
+        assert!(matches!(
+            registry.tokenize("unknown", "a"),
+            Err(Error::GrammarNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn can_highlight_from_multiple_threads() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let content = "/* a */ let a = `b ${c}`;\nfunction d(e) { return e * 2; }";
+        let expected = registry.highlight(content, &options).unwrap().tokens;
+        registry.clear_pattern_cache();
+        // The styles computed by the first highlight are kept for the next ones
+        assert!(registry.themes["vitesse-black"].style_cache.len() > 0);
+
+        // All the threads share the same pattern sets
+        std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..20)
+                            .map(|_| registry.highlight(content, &options).unwrap().tokens)
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for handle in handles {
+                for tokens in handle.join().unwrap() {
+                    assert_eq!(tokens, expected);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn can_highlight_many() {
+        let registry = get_registry();
+        let theme = ThemeVariant::Single("vitesse-black");
+        let items: Vec<(String, HighlightOptions)> = (0..50)
+            .map(|i| match i % 5 {
+                0 => (
+                    format!("{{\"a\": {i}}}"),
+                    HighlightOptions::new("json", theme),
+                ),
+                1 => ("a".to_owned(), HighlightOptions::new("unknown", theme)),
+                _ => (
+                    format!("let a{i} = `b ${{c}}`;\n// {i}"),
+                    HighlightOptions::new("javascript", theme),
+                ),
+            })
+            .collect();
+
+        for num_threads in [4, 0, 1] {
+            let results = registry.highlight_many(items.clone(), num_threads);
+            assert_eq!(results.len(), items.len());
+            for ((content, options), result) in items.iter().zip(results) {
+                match registry.highlight(content, options) {
+                    Ok(expected) => assert_eq!(result.unwrap().tokens, expected.tokens),
+                    Err(_) => assert!(matches!(result, Err(Error::GrammarNotFound(_)))),
+                }
+            }
+            // What the worker threads compiled is used by this thread
+            #[cfg(feature = "oniguruma")]
+            assert_eq!(crate::grammars::engine::COMPILED_REGSETS.get(), 0);
+        }
+        assert!(
+            registry
+                .highlight_many(Vec::<(&str, HighlightOptions)>::new(), 4)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn can_add_vscode_extension() {
+        let mut registry = get_registry();
+        registry
+            .add_vscode_extension("src/fixtures/vscode-extension")
+            .unwrap();
+        registry.link_grammars();
+
+        // The language id and its aliases point to the grammar, not the name in the grammar file
+        for lang in ["ini", "INI", "Properties"] {
+            assert!(registry.contains_grammar(lang), "{lang}");
+        }
+        assert!(!registry.contains_grammar("INI file"));
+        // The theme is found by its label and is light because of its `uiTheme`
+        let theme = &registry.themes["ini light"];
+        assert_eq!(theme.theme_type, ThemeType::Light);
+
+        // Injection grammars are injected in the ini grammar from its scope name and the ones
+        // with the same name or without one don't replace each other
+        let options = HighlightOptions::new("ini", ThemeVariant::Single("INI Light"));
+        let highlighted = registry
+            .highlight("\"TODO, FIXME, NOTE\"", &options)
+            .unwrap();
+        for word in ["TODO", "FIXME", "NOTE"] {
+            let token = highlighted.tokens[0]
+                .iter()
+                .find(|t| t.text == word)
+                .unwrap();
+            assert_eq!(
+                token.style,
+                ThemeVariant::Single(Style {
+                    foreground: Color::from_hex("#FF0000").unwrap(),
+                    ..theme.default_style
+                }),
+                "{word}"
+            );
+        }
+
+        let contribution = registry.grammar_contribution("INI").unwrap();
+        assert_eq!(
+            contribution.embedded_language(&["source.ini", "meta.embedded.expression.ini"]),
+            Some("javascript")
+        );
+        assert_eq!(
+            contribution.token_type(&["source.ini", "string.quoted.double.ini"]),
+            Some(TokenType::String)
+        );
+        assert!(registry.grammar_contribution("javascript").is_none());
+
+        assert_eq!(registry.find_language("a/b/config.CFG", None), Some("ini"));
+        assert_eq!(registry.find_language("gitconfig", None), Some("ini"));
+        assert_eq!(registry.find_language("a/GitConfig", None), Some("ini"));
+        assert_eq!(
+            registry.find_language("a.desktop.ini", None),
+            Some("desktop")
+        );
+        assert_eq!(
+            registry.find_language("config", Some("[core]")),
+            Some("ini")
+        );
+        assert_eq!(registry.find_language("config", Some("core")), None);
+    }
+
+    #[test]
+    fn failing_vscode_extension_adds_nothing() {
+        let mut registry = get_registry();
+        let num_grammars = registry.grammars.len();
+        let num_themes = registry.themes.len();
+        // The grammar loads fine but the theme is missing
+        assert!(
+            registry
+                .add_vscode_extension("src/fixtures/vscode-extension-broken")
+                .is_err()
+        );
+
+        assert_eq!(registry.grammars.len(), num_grammars);
+        assert_eq!(registry.themes.len(), num_themes);
+        assert!(!registry.contains_grammar("ini"));
+        assert!(!registry.contains_grammar("INI"));
+        assert_eq!(registry.find_language("a.ini", None), None);
+    }
+
+    #[test]
+    fn can_add_grammar_from_str() {
+        let mut registry = get_registry();
+        let content = fs::read_to_string("src/fixtures/grammars/ini.tmLanguage.json").unwrap();
+        registry.add_grammar_from_str(&content).unwrap();
+        registry.link_grammars();
+
+        let options = HighlightOptions::new("ini", ThemeVariant::Single("vitesse-black"));
+        let highlighted = registry.highlight("[section] ; comment", &options).unwrap();
+        assert!(highlighted.tokens[0].len() > 1);
+        assert!(matches!(
+            registry.add_grammar_from_str("{\"name\": 1}"),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn can_highlight_bytes() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let content = "let a = \"café\";\r\nlet b = 1;";
+
+        // UTF-16 with BOM: same tokens as the string, with spans in the original bytes
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(content.encode_utf16().flat_map(u16::to_le_bytes));
+        let highlighted = registry.highlight_bytes(&bytes, &options).unwrap();
+        let expected = registry.highlight(content, &options).unwrap();
+        assert_eq!(highlighted.highlighted.tokens, expected.tokens);
+        let spans = highlighted.original_spans();
+        for (line, line_spans) in highlighted.highlighted.tokens.iter().zip(&spans) {
+            for (token, span) in line.iter().zip(line_spans) {
+                let units: Vec<u16> = bytes[span.clone()]
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .collect();
+                assert_eq!(String::from_utf16(&units).unwrap(), token.text);
+            }
+        }
+        // After the BOM and 17 UTF-16 units
+        assert_eq!(spans[1][0].start, 36);
+
+        // Latin-1
+        let highlighted = registry
+            .highlight_bytes(b"let a = \"caf\xE9\";", &options)
+            .unwrap();
+        assert_eq!(highlighted.decoded.encoding, TextEncoding::Latin1);
+        assert_eq!(highlighted.decoded.text, "let a = \"café\";");
+        assert_eq!(highlighted.original_spans()[0].last().unwrap().end, 15);
+
+        assert!(matches!(
+            registry.highlight_bytes(b"\x00\x01\x02", &options),
+            Err(Error::BinaryContent)
+        ));
+    }
+
+    #[test]
+    fn can_preserve_line_endings() {
+        let registry = get_registry();
+        let content = "let a = 1;\r\nlet b = 2;\rlet c = 3;\n\r\n";
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"))
+            .preserve_line_endings(true);
+        let highlighted = registry.highlight(content, &options).unwrap();
+        assert_eq!(
+            highlighted.line_endings,
+            vec![
+                LineEnding::CrLf,
+                LineEnding::Cr,
+                LineEnding::Lf,
+                LineEnding::CrLf,
+                LineEnding::None
+            ]
+        );
+        // Same tokens as without the option
+        let normalized = registry
+            .highlight(content, &options.clone().preserve_line_endings(false))
+            .unwrap();
+        assert_eq!(highlighted.tokens, normalized.tokens);
+        assert!(normalized.line_endings.is_empty());
+
+        // The offsets are in the original content
+        let offsets = highlighted.line_offsets();
+        assert_eq!(offsets, vec![0, 12, 23, 34, 36]);
+        for (i, line) in highlighted.tokens.iter().enumerate() {
+            let text: String = line.iter().map(|t| t.text.as_str()).collect();
+            assert!(content[offsets[i]..].starts_with(&text));
+        }
+        assert_eq!(normalized.line_offsets(), vec![0, 11, 22, 33, 34]);
+
+        // And renderers give back the exact content
+        let html = HtmlRenderer::default().render(&highlighted, &RenderOptions::default());
+        assert!(html.contains("\r\n"));
+        let ansi = TerminalRenderer::default().render(&highlighted, &RenderOptions::default());
+        let mut stripped = String::new();
+        let mut chars = ansi.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                stripped.push(c);
+            }
+        }
+        assert_eq!(stripped, content);
+    }
+
+    #[test]
+    fn can_warm_up_pattern_sets() {
+        let registry = get_registry();
+        let cloned_before = registry.clone();
+        assert!(matches!(
+            registry.warm_up(&["unknown"], 1),
+            Err(Error::GrammarNotFound(_))
+        ));
+        registry.warm_up(&["JavaScript", "markdown"], 4).unwrap();
+        let num_pattern_sets = registry.pattern_cache.len();
+        assert!(num_pattern_sets > 0);
+        assert_eq!(cloned_before.pattern_cache.len(), num_pattern_sets);
+
+        // Highlighting doesn't need to compile anything else, even on a thread that didn't
+        // take part in the warm up
+        let jquery = fs::read_to_string("src/fixtures/samples/jquery.js").unwrap();
+        let markdown = "# Title\n\n> Some *quote* with `code`\n\n- [link](https://example.com)\n\n```js\nconst a = `b ${c}`;\n```\n\n<div class=\"a\">Hello</div>\n";
+        let cloned_after = registry.clone();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for (lang, content) in [("javascript", jquery.as_str()), ("markdown", markdown)] {
+                    let options =
+                        HighlightOptions::new(lang, ThemeVariant::Single("vitesse-black"));
+                    cloned_after.highlight(content, &options).unwrap();
+                }
+                #[cfg(feature = "oniguruma")]
+                assert_eq!(crate::grammars::engine::COMPILED_REGSETS.get(), 0);
+            });
+        });
+        assert_eq!(registry.pattern_cache.len(), num_pattern_sets);
+
+        // Changing the grammars of a clone doesn't affect the others
+        let mut changed = registry.clone();
+        changed.add_plain_grammar(&[]).unwrap();
+        assert_eq!(changed.pattern_cache.len(), 0);
+        assert_eq!(registry.pattern_cache.len(), num_pattern_sets);
+    }
+
+    #[test]
+    fn can_apply_themes_to_tokenized_code() {
+        let mut registry = get_registry();
+        registry
+            .add_theme_from_path("grammars-themes/packages/tm-themes/themes/vitesse-light.json")
+            .unwrap();
+        let content = "/* a */ let a = `b ${c}`;\r\nfunction d(e) { return e * 2; }";
+        let options = HighlightOptions::new("JavaScript", ThemeVariant::Single("vitesse-black"));
+        let tokenized = registry.tokenize_code(content, &options).unwrap();
+        assert_eq!(tokenized.language(), "javascript");
+        assert_eq!(tokenized.tokens().len(), 2);
+
+        for theme in [
+            ThemeVariant::Single("vitesse-black"),
+            ThemeVariant::Single("Vitesse-Light"),
+            ThemeVariant::Dual {
+                light: "vitesse-light",
+                dark: "vitesse-black",
+            },
+        ] {
+            let highlighted = registry.apply_theme(&tokenized, theme).unwrap();
+            let mut expected_options = HighlightOptions::new("javascript", theme);
+            // The merging options come from the options the code was tokenized with
+            expected_options.merge_same_style_tokens = options.merge_same_style_tokens;
+            let expected = registry.highlight(content, &expected_options).unwrap();
+            assert_eq!(highlighted.tokens, expected.tokens);
+            assert_eq!(highlighted.theme, expected.theme);
+        }
+
+        assert!(matches!(
+            registry.apply_theme(&tokenized, ThemeVariant::Single("unknown")),
+            Err(Error::ThemeNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn tokens_share_their_scopes() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let tokenized = registry
+            .tokenize_code("let a = 1;\nlet b = 2;\n", &options)
+            .unwrap();
+        let tokens = tokenized.tokens();
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens[0].len(), tokens[1].len());
+        for (a, b) in tokens[0].iter().zip(&tokens[1]) {
+            assert!(Arc::ptr_eq(&a.scopes, &b.scopes));
+        }
+        assert!(tokens[2].is_empty());
+    }
+
+    #[test]
+    fn registries_have_their_own_scopes() {
+        let registry = get_registry();
+        // Registering the grammars in another order gives different atoms to the same scopes
+        let mut json_only = Registry::default();
+        json_only
+            .add_grammar_from_path("grammars-themes/packages/tm-grammars/grammars/json.json")
+            .unwrap();
+        json_only.link_grammars();
+
+        let content = "{\"a\": 1}";
+        let tokens = registry.tokenize("json", content).unwrap();
+        let json_only_tokens = json_only.tokenize("json", content).unwrap();
+        assert_eq!(tokens.len(), json_only_tokens.len());
+        for (token, json_only_token) in tokens[0].iter().zip(&json_only_tokens[0]) {
+            assert_eq!(
+                token.scope_names(&registry),
+                json_only_token.scope_names(&json_only)
+            );
+        }
+        assert_eq!(registry.scope_name(&tokens[0][0].scopes[0]), "source.json");
+
+        // Clones share the scopes of the original
+        let cloned = json_only.clone();
+        assert_eq!(
+            cloned.scope_name(&json_only_tokens[0][0].scopes[0]),
+            "source.json"
+        );
+    }
+
+    #[test]
+    fn can_tokenize_line_by_line() {
+        let registry = get_registry();
+        let sample_content = normalize_string(
+            &fs::read_to_string("grammars-themes/samples/javascript.sample").unwrap(),
+        );
+        let expected = registry.tokenize("javascript", &sample_content).unwrap();
+
+        let mut state = LineState::default();
+        let mut tokens = Vec::new();
+        for line in sample_content.split('\n') {
+            // Persisting and restoring the state should not change anything
+            let restored: LineState =
+                serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+            assert_eq!(state, restored);
+            let (line_tokens, next_state) = registry
+                .tokenize_line("javascript", &format!("{line}\r\n"), &restored)
+                .unwrap();
+            tokens.push(line_tokens);
+            state = next_state;
+        }
+        assert_eq!(tokens, expected);
+
+        // A state is tied to its grammar
+        let err = registry.tokenize_line("json", "{}", &state).unwrap_err();
+        assert!(matches!(err, Error::InvalidLineState));
+    }
+
+    #[test]
+    fn can_restore_line_state_in_another_registry() {
+        let registry = get_registry();
+        // Same grammars but the theme registers its atoms first so they have other numbers
+        let mut other = Registry::default();
+        other
+            .add_theme_from_path("grammars-themes/packages/tm-themes/themes/vitesse-black.json")
+            .unwrap();
+        for entry in fs::read_dir("grammars-themes/packages/tm-grammars/grammars").unwrap() {
+            other.add_grammar_from_path(entry.unwrap().path()).unwrap();
+        }
+        other.link_grammars();
+
+        let lines = ["/* a", "b */ let s = `c", "${d}`;"];
+        let mut state = LineState::default();
+        let mut other_state = LineState::default();
+        for line in lines {
+            let (tokens, next_state) = registry.tokenize_line("javascript", line, &state).unwrap();
+            let restored: LineState =
+                serde_json::from_str(&serde_json::to_string(&other_state).unwrap()).unwrap();
+            let (other_tokens, next_other_state) =
+                other.tokenize_line("javascript", line, &restored).unwrap();
+            let scope_names = |tokens: &[Token], registry: &Registry| {
+                tokens
+                    .iter()
+                    .map(|t| t.scope_names(registry))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                scope_names(&other_tokens, &other),
+                scope_names(&tokens, &registry),
+                "{line}"
+            );
+            // States are also usable in memory by another registry
+            let (tokens_from_other_state, _) = registry
+                .tokenize_line("javascript", line, &other_state)
+                .unwrap();
+            assert_eq!(
+                scope_names(&tokens_from_other_state, &registry),
+                scope_names(&tokens, &registry)
+            );
+            state = next_state;
+            other_state = next_other_state;
+        }
+    }
+
+    #[test]
+    fn can_limit_tokenization() {
+        let registry = get_registry();
+        let code = "let a = 1; let b = 2;\nlet c = 3;";
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"))
+            .merge_whitespace(false)
+            .merge_same_style_tokens(false);
+        let full = registry.highlight(code, &options).unwrap();
+        assert!(full.degraded_lines.is_empty());
+
+        // Only the long line is stopped early, the rest of it gets a single token
+        let highlighted = registry
+            .highlight(code, &options.clone().max_line_length(10))
+            .unwrap();
+        assert_eq!(highlighted.degraded_lines, vec![0]);
+        assert_eq!(highlighted.tokens[1], full.tokens[1]);
+        let last_token = highlighted.tokens[0].last().unwrap();
+        assert!(last_token.text.ends_with(" let b = 2;"));
+        let text: String = highlighted.tokens[0]
+            .iter()
+            .map(|t| t.text.as_str())
+            .collect();
+        assert_eq!(text, "let a = 1; let b = 2;");
+
+        // No time left: nothing is tokenized
+        let highlighted = registry
+            .highlight(code, &options.clone().time_limit(Duration::ZERO))
+            .unwrap();
+        assert_eq!(highlighted.degraded_lines, vec![0, 1]);
+        assert!(highlighted.tokens.iter().all(|line| line.len() == 1));
+
+        let token = CancellationToken::new();
+        token.clone().cancel();
+        let res = registry.highlight(code, &options.cancellation_token(token));
+        assert!(matches!(res, Err(Error::Cancelled)));
+    }
+
     #[test]
     fn can_highlight_plain_grammar() {
         let mut registry = Registry::default();
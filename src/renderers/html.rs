@@ -1,12 +1,16 @@
 use std::collections::BTreeMap;
 use std::fmt;
+use std::io::{BufRead, Write};
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::GialloResult;
+use crate::highlight::HighlightedText;
 use crate::registry::HighlightedCode;
 use crate::renderers::RenderOptions;
+use crate::stream::HighlightedLines;
 use crate::themes::css::{DARK_SUFFIX, LIGHT_SUFFIX};
-use crate::themes::{Color, ThemeVariant};
+use crate::themes::{Color, CompiledTheme, ThemeVariant};
 
 /// Where to put the additional attributes
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -58,139 +62,72 @@ impl HtmlRenderer {
     /// Renders the given highlighted code to an HTML string.
     /// This will also handle automatic light/dark theming and escaping characters.
     pub fn render(&self, highlighted: &HighlightedCode, options: &RenderOptions) -> String {
-        let lang = highlighted.language;
-        let css_prefix = self.css_class_prefix.as_deref();
-
-        // Precompute structural CSS classes (code, hl).
-        // For dual themes, we insert both light and dark classes
-        let (code_class, hl_class) = match css_prefix {
-            Some(p) => match &highlighted.theme {
-                ThemeVariant::Single(_) => (Some(format!("{p}code")), format!("{p}hl")),
-                ThemeVariant::Dual { .. } => (
-                    Some(format!("{p}{LIGHT_SUFFIX}code {p}{DARK_SUFFIX}code")),
-                    format!("{p}{LIGHT_SUFFIX}hl {p}{DARK_SUFFIX}hl"),
-                ),
-            },
-            None => (None, String::new()),
-        };
-
-        // Pre-compute highlight background CSS/class if available
-        let highlight_attr = if !options.highlight_lines.is_empty() {
-            if css_prefix.is_some() {
-                // CSS class mode: use hl class
-                Some(format!(r#" class="{hl_class}""#))
-            } else {
-                // Inline style mode
-                match &highlighted.theme {
-                    ThemeVariant::Single(theme) => theme
-                        .highlight_background_color
-                        .as_ref()
-                        .map(|c| format!(r#" style="{}""#, c.as_css_bg_color_property())),
-                    ThemeVariant::Dual { light, dark } => {
-                        match (
-                            &light.highlight_background_color,
-                            &dark.highlight_background_color,
-                        ) {
-                            (Some(l), Some(d)) => Some(format!(
-                                r#" style="{}""#,
-                                Color::as_css_light_dark_bg_color_property(l, d)
-                            )),
-                            _ => None,
-                        }
-                    }
-                }
-            }
-        } else {
-            None
-        };
-
-        // Pre-compute line number color style if available (inline style mode only)
-        let line_number_style = if options.show_line_numbers && css_prefix.is_none() {
-            match &highlighted.theme {
-                ThemeVariant::Single(theme) => theme
-                    .line_number_foreground
-                    .as_ref()
-                    .map(|c| format!(r#" style="{}""#, c.as_css_color_property())),
-                ThemeVariant::Dual { light, dark } => {
-                    match (&light.line_number_foreground, &dark.line_number_foreground) {
-                        (Some(l), Some(d)) => Some(format!(
-                            r#" style="{}""#,
-                            Color::as_css_light_dark_color_property(l, d)
-                        )),
-                        _ => None,
-                    }
-                }
-            }
-        } else {
-            None
-        };
-
         let line_numbers_size = options.line_number_width(highlighted.tokens.len());
-        let mut lines = Vec::with_capacity(highlighted.tokens.len() + 4);
+        let line_renderer =
+            HtmlLineRenderer::new(self, &highlighted.theme, options, line_numbers_size);
+
+        let mut lines = String::new();
         let mut tokens = highlighted.tokens.iter().enumerate().peekable();
         while let Some((idx, line_tokens)) = tokens.next() {
-            let line_num = idx + 1; // 1-indexed
-
-            // Skip trailing empty line
-            if tokens.peek().is_none() && line_tokens.is_empty() {
-                continue;
+            if let Some(line_html) =
+                line_renderer.render_line(idx, line_tokens, tokens.peek().is_none())
+            {
+                // With preserved line endings, every line is followed by its own so we get
+                // back the exact content. Otherwise lines are separated by `\n`.
+                if highlighted.line_endings.is_empty() {
+                    if !lines.is_empty() {
+                        lines.push('\n');
+                    }
+                    lines.push_str(&line_html);
+                } else {
+                    lines.push_str(&line_html);
+                    lines.push_str(highlighted.line_endings[idx].as_str());
+                }
             }
+        }
 
-            // Skip hidden lines
-            if options.hide_lines.iter().any(|r| r.contains(&line_num)) {
-                continue;
-            }
+        let (open, close) = self.wrapper_tags(highlighted.language, &highlighted.theme);
+        format!("{open}{lines}{close}")
+    }
 
-            // Render tokens
-            let mut line_content = Vec::with_capacity(line_tokens.len());
-            for tok in line_tokens {
-                line_content.push(tok.as_html(&highlighted.theme, css_prefix));
-            }
-            let line_content = line_content.join("");
-
-            // Line number (uses original source line number, padded with spaces)
-            let display_line_num = options.line_number_start + (idx as isize);
-            let line_number_html = if options.show_line_numbers {
-                let line_num_s = display_line_num.to_string();
-                let padded =
-                    std::iter::repeat_n(' ', line_numbers_size - line_num_s.chars().count())
-                        .chain(line_num_s.chars())
-                        .collect::<String>();
-                format!(
-                    r#"<span aria-hidden="true" class="giallo-ln"{}>{padded}</span>"#,
-                    line_number_style.as_deref().unwrap_or_default()
-                )
-            } else {
-                String::new()
-            };
+    /// Renders the lines of a [`HighlightedLines`] to the writer as they get highlighted, without
+    /// keeping the whole output in memory.
+    ///
+    /// The output is the same as `render` except that line numbers are not padded to the same
+    /// width since the number of lines is not known ahead.
+    pub fn render_to_writer<R: BufRead, W: Write>(
+        &self,
+        lines: HighlightedLines<'_, R>,
+        options: &RenderOptions,
+        writer: &mut W,
+    ) -> GialloResult<()> {
+        let theme = lines.theme();
+        let line_numbers_size = options.line_number_width(0);
+        let line_renderer = HtmlLineRenderer::new(self, &theme, options, line_numbers_size);
 
-            // Build line span, with highlight if applicable
-            let is_highlighted = options
-                .highlight_lines
-                .iter()
-                .any(|r| r.contains(&line_num));
-            let line_html = match (is_highlighted, &highlight_attr) {
-                (true, Some(hl_class_or_style)) => {
-                    format!(
-                        r#"<span class="giallo-l{hl_class_or_style}"{hl_style}>{line_number_html}{line_content}</span>"#,
-                        hl_class_or_style = if css_prefix.is_some() {
-                            format!(" {hl_class}")
-                        } else {
-                            String::new()
-                        },
-                        hl_style = if css_prefix.is_none() {
-                            hl_class_or_style
-                        } else {
-                            ""
-                        }
-                    )
+        let (open, close) = self.wrapper_tags(lines.language(), &theme);
+        writer.write_all(open.as_bytes())?;
+        let mut is_first_line = true;
+        let mut lines = lines.enumerate().peekable();
+        while let Some((idx, line_tokens)) = lines.next() {
+            let line_tokens = line_tokens?;
+            if let Some(line_html) =
+                line_renderer.render_line(idx, &line_tokens, lines.peek().is_none())
+            {
+                if !is_first_line {
+                    writer.write_all(b"\n")?;
                 }
-                _ => format!(r#"<span class="giallo-l">{line_number_html}{line_content}</span>"#),
-            };
-
-            lines.push(line_html);
+                writer.write_all(line_html.as_bytes())?;
+                is_first_line = false;
+            }
         }
-        let lines = lines.join("\n");
+        writer.write_all(close.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the HTML to put before and after the lines
+    fn wrapper_tags(&self, lang: &str, theme: &ThemeVariant<&CompiledTheme>) -> (String, String) {
+        let css_prefix = self.css_class_prefix.as_deref();
 
         // Build data attributes from other_metadata
         let mut data_attrs = format!(r#"data-lang="{lang}""#);
@@ -225,21 +162,29 @@ impl HtmlRenderer {
             .as_deref()
             .unwrap_or_default();
         let after_code_html = self.extra_html_content.after.as_deref().unwrap_or_default();
+        let close = format!("</code>{after_code_html}</pre>");
 
         // CSS class mode: output class instead of inline styles on <pre>
-        if let Some(code_class) = &code_class {
-            return format!(
-                r#"<pre class="giallo {code_class}" {pre_data_attrs}>{before_code_html}<code {code_data_attrs}>{lines}</code>{after_code_html}</pre>"#
+        if let Some(p) = css_prefix {
+            let code_class = match theme {
+                ThemeVariant::Single(_) => format!("{p}code"),
+                ThemeVariant::Dual { .. } => format!("{p}{LIGHT_SUFFIX}code {p}{DARK_SUFFIX}code"),
+            };
+            return (
+                format!(
+                    r#"<pre class="giallo {code_class}" {pre_data_attrs}>{before_code_html}<code {code_data_attrs}>"#
+                ),
+                close,
             );
         }
 
         // Inline style mode
-        match &highlighted.theme {
+        let open = match theme {
             ThemeVariant::Single(theme) => {
                 let fg = theme.default_style.foreground.as_css_color_property();
                 let bg = theme.default_style.background.as_css_bg_color_property();
                 format!(
-                    r#"<pre class="giallo" style="{fg} {bg}" {pre_data_attrs}>{before_code_html}<code {code_data_attrs}>{lines}</code>{after_code_html}</pre>"#
+                    r#"<pre class="giallo" style="{fg} {bg}" {pre_data_attrs}>{before_code_html}<code {code_data_attrs}>"#
                 )
             }
             ThemeVariant::Dual { light, dark } => {
@@ -252,11 +197,180 @@ impl HtmlRenderer {
                     &dark.default_style.background,
                 );
                 format!(
-                    r#"<pre class="giallo" style="color-scheme: light dark; {fg} {bg}" {pre_data_attrs}>{before_code_html}<code {code_data_attrs}>{lines}</code>{after_code_html}</pre>"#
+                    r#"<pre class="giallo" style="color-scheme: light dark; {fg} {bg}" {pre_data_attrs}>{before_code_html}<code {code_data_attrs}>"#
                 )
             }
+        };
+        (open, close)
+    }
+}
+
+/// Everything needed to render a single line, computed once per render
+struct HtmlLineRenderer<'a> {
+    theme: &'a ThemeVariant<&'a CompiledTheme>,
+    options: &'a RenderOptions,
+    css_prefix: Option<&'a str>,
+    line_numbers_size: usize,
+    hl_class: String,
+    highlight_attr: Option<String>,
+    line_number_style: Option<String>,
+}
+
+impl<'a> HtmlLineRenderer<'a> {
+    fn new(
+        renderer: &'a HtmlRenderer,
+        theme: &'a ThemeVariant<&'a CompiledTheme>,
+        options: &'a RenderOptions,
+        line_numbers_size: usize,
+    ) -> Self {
+        let css_prefix = renderer.css_class_prefix.as_deref();
+
+        // Precompute structural CSS classes (hl).
+        // For dual themes, we insert both light and dark classes
+        let hl_class = match css_prefix {
+            Some(p) => match theme {
+                ThemeVariant::Single(_) => format!("{p}hl"),
+                ThemeVariant::Dual { .. } => format!("{p}{LIGHT_SUFFIX}hl {p}{DARK_SUFFIX}hl"),
+            },
+            None => String::new(),
+        };
+
+        // Pre-compute highlight background CSS/class if available
+        let highlight_attr = if !options.highlight_lines.is_empty() {
+            if css_prefix.is_some() {
+                // CSS class mode: use hl class
+                Some(format!(r#" class="{hl_class}""#))
+            } else {
+                // Inline style mode
+                match theme {
+                    ThemeVariant::Single(theme) => theme
+                        .highlight_background_color
+                        .as_ref()
+                        .map(|c| format!(r#" style="{}""#, c.as_css_bg_color_property())),
+                    ThemeVariant::Dual { light, dark } => {
+                        match (
+                            &light.highlight_background_color,
+                            &dark.highlight_background_color,
+                        ) {
+                            (Some(l), Some(d)) => Some(format!(
+                                r#" style="{}""#,
+                                Color::as_css_light_dark_bg_color_property(l, d)
+                            )),
+                            _ => None,
+                        }
+                    }
+                }
+            }
+        } else {
+            None
+        };
+
+        // Pre-compute line number color style if available (inline style mode only)
+        let line_number_style = if options.show_line_numbers && css_prefix.is_none() {
+            match theme {
+                ThemeVariant::Single(theme) => theme
+                    .line_number_foreground
+                    .as_ref()
+                    .map(|c| format!(r#" style="{}""#, c.as_css_color_property())),
+                ThemeVariant::Dual { light, dark } => {
+                    match (&light.line_number_foreground, &dark.line_number_foreground) {
+                        (Some(l), Some(d)) => Some(format!(
+                            r#" style="{}""#,
+                            Color::as_css_light_dark_color_property(l, d)
+                        )),
+                        _ => None,
+                    }
+                }
+            }
+        } else {
+            None
+        };
+
+        Self {
+            theme,
+            options,
+            css_prefix,
+            line_numbers_size,
+            hl_class,
+            highlight_attr,
+            line_number_style,
         }
     }
+
+    /// Renders the line at the given index, returning `None` if it should not be displayed.
+    /// `is_last_line` is whether there are no lines after this one.
+    fn render_line(
+        &self,
+        idx: usize,
+        line_tokens: &[HighlightedText],
+        is_last_line: bool,
+    ) -> Option<String> {
+        let options = self.options;
+        let css_prefix = self.css_prefix;
+        let line_num = idx + 1; // 1-indexed
+
+        // Skip trailing empty line
+        if is_last_line && line_tokens.is_empty() {
+            return None;
+        }
+
+        // Skip hidden lines
+        if options.hide_lines.iter().any(|r| r.contains(&line_num)) {
+            return None;
+        }
+
+        // Render tokens
+        let mut line_content = Vec::with_capacity(line_tokens.len());
+        for tok in line_tokens {
+            line_content.push(tok.as_html(self.theme, css_prefix));
+        }
+        let line_content = line_content.join("");
+
+        // Line number (uses original source line number, padded with spaces)
+        let display_line_num = options.line_number_start + (idx as isize);
+        let line_number_html = if options.show_line_numbers {
+            let line_num_s = display_line_num.to_string();
+            let padded = std::iter::repeat_n(
+                ' ',
+                self.line_numbers_size
+                    .saturating_sub(line_num_s.chars().count()),
+            )
+            .chain(line_num_s.chars())
+            .collect::<String>();
+            format!(
+                r#"<span aria-hidden="true" class="giallo-ln"{}>{padded}</span>"#,
+                self.line_number_style.as_deref().unwrap_or_default()
+            )
+        } else {
+            String::new()
+        };
+
+        // Build line span, with highlight if applicable
+        let is_highlighted = options
+            .highlight_lines
+            .iter()
+            .any(|r| r.contains(&line_num));
+        let line_html = match (is_highlighted, &self.highlight_attr) {
+            (true, Some(hl_class_or_style)) => {
+                format!(
+                    r#"<span class="giallo-l{hl_class_or_style}"{hl_style}>{line_number_html}{line_content}</span>"#,
+                    hl_class_or_style = if css_prefix.is_some() {
+                        format!(" {}", self.hl_class)
+                    } else {
+                        String::new()
+                    },
+                    hl_style = if css_prefix.is_none() {
+                        hl_class_or_style
+                    } else {
+                        ""
+                    }
+                )
+            }
+            _ => format!(r#"<span class="giallo-l">{line_number_html}{line_content}</span>"#),
+        };
+
+        Some(line_html)
+    }
 }
 
 // From syntect
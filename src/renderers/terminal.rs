@@ -1,4 +1,10 @@
-use crate::{HighlightedCode, RenderOptions, themes::compiled::ThemeType};
+use std::io::{BufRead, Write};
+
+use crate::error::GialloResult;
+use crate::highlight::HighlightedText;
+use crate::stream::HighlightedLines;
+use crate::themes::{Color, CompiledTheme, ThemeVariant};
+use crate::{HighlightedCode, LineEnding, RenderOptions, themes::compiled::ThemeType};
 
 /// Terminal renderer via ANSI escape codes. Requires a terminal that supports truecolor
 #[derive(Default, Copy, Clone, PartialEq, Eq)]
@@ -13,84 +19,165 @@ impl TerminalRenderer {
     pub fn render(&self, highlighted: &HighlightedCode, options: &RenderOptions) -> String {
         let mut output = String::new();
         let line_numbers_size = options.line_number_width(highlighted.tokens.len());
+        let line_renderer =
+            TerminalLineRenderer::new(self, &highlighted.theme, options, line_numbers_size);
+
+        let mut tokens = highlighted.tokens.iter().enumerate().peekable();
+        while let Some((idx, line_tokens)) = tokens.next() {
+            line_renderer.render_line(
+                idx,
+                line_tokens,
+                tokens.peek().is_none(),
+                highlighted.line_endings.get(idx).copied(),
+                &mut output,
+            );
+        }
+
+        output
+    }
+
+    /// Renders the lines of a [`HighlightedLines`] to the writer as they get highlighted, without
+    /// keeping the whole output in memory.
+    ///
+    /// The output is the same as `render` except that line numbers are not padded to the same
+    /// width since the number of lines is not known ahead.
+    pub fn render_to_writer<R: BufRead, W: Write>(
+        &self,
+        lines: HighlightedLines<'_, R>,
+        options: &RenderOptions,
+        writer: &mut W,
+    ) -> GialloResult<()> {
+        let theme = lines.theme();
+        let line_numbers_size = options.line_number_width(0);
+        let line_renderer = TerminalLineRenderer::new(self, &theme, options, line_numbers_size);
 
+        let mut output = String::new();
+        let mut lines = lines.enumerate().peekable();
+        while let Some((idx, line_tokens)) = lines.next() {
+            let line_tokens = line_tokens?;
+            output.clear();
+            line_renderer.render_line(idx, &line_tokens, lines.peek().is_none(), None, &mut output);
+            writer.write_all(output.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Everything needed to render a single line, computed once per render
+struct TerminalLineRenderer<'a> {
+    theme: &'a ThemeVariant<&'a CompiledTheme>,
+    theme_type: Option<ThemeType>,
+    options: &'a RenderOptions,
+    line_numbers_size: usize,
+    line_number_foreground: Option<Color>,
+    highlight_background_color: Option<Color>,
+}
+
+impl<'a> TerminalLineRenderer<'a> {
+    fn new(
+        renderer: &TerminalRenderer,
+        theme: &'a ThemeVariant<&'a CompiledTheme>,
+        options: &'a RenderOptions,
+        line_numbers_size: usize,
+    ) -> Self {
         // Color of line numbers
-        let (line_number_foreground, highlight_background_color) = match highlighted.theme {
-            crate::ThemeVariant::Single(theme) => (
+        let (line_number_foreground, highlight_background_color) = match theme {
+            ThemeVariant::Single(theme) => (
                 theme.line_number_foreground,
                 theme.highlight_background_color,
             ),
-            crate::ThemeVariant::Dual { light, .. }
-                if self.theme_type == Some(ThemeType::Light) =>
-            {
-                (
-                    light.line_number_foreground,
-                    light.highlight_background_color,
-                )
-            }
-            crate::ThemeVariant::Dual { dark, .. } if self.theme_type == Some(ThemeType::Dark) => {
+            ThemeVariant::Dual { light, .. } if renderer.theme_type == Some(ThemeType::Light) => (
+                light.line_number_foreground,
+                light.highlight_background_color,
+            ),
+            ThemeVariant::Dual { dark, .. } if renderer.theme_type == Some(ThemeType::Dark) => {
                 (dark.line_number_foreground, dark.highlight_background_color)
             }
             _ => unreachable!(),
         };
 
-        let line_count = highlighted.tokens.len();
-        let mut tokens = highlighted.tokens.iter().enumerate().peekable();
-        while let Some((idx, line_tokens)) = tokens.next() {
-            let line_num = idx + 1; // 1-indexed
-            let is_last_line = line_count == line_num;
+        Self {
+            theme,
+            theme_type: renderer.theme_type,
+            options,
+            line_numbers_size,
+            line_number_foreground,
+            highlight_background_color,
+        }
+    }
 
-            // Special case: If the current line is the last newline of the file,
-            // then don't render it. This matches the behaviour of "cat" and "bat"
-            if tokens.peek().is_none() && line_tokens.is_empty() {
-                continue;
-            }
-            // Semantically, it's as if this newline is being added at the end of each iteration.
-            // But if the previous condition fires, then we don't want the newline to have been added.
-            else if idx != 0 && !is_last_line {
-                output.push('\n');
-            }
+    /// Renders the line at the given index to the output.
+    /// `is_last_line` is whether there are no lines after this one.
+    /// `line_ending` is the original line ending of the line if they were preserved, in which
+    /// case it is written after the line instead of a `\n` before the next one.
+    fn render_line(
+        &self,
+        idx: usize,
+        line_tokens: &[HighlightedText],
+        is_last_line: bool,
+        line_ending: Option<LineEnding>,
+        output: &mut String,
+    ) {
+        let options = self.options;
+        let line_num = idx + 1; // 1-indexed
 
-            // Skip hidden lines
-            if options.hide_lines.iter().any(|r| r.contains(&line_num)) {
-                continue;
-            }
+        // Special case: If the current line is the last newline of the file,
+        // then don't render it. This matches the behaviour of "cat" and "bat"
+        if is_last_line && line_tokens.is_empty() {
+            return;
+        }
+        // Semantically, it's as if this newline is being added at the end of each iteration.
+        // But if the previous condition fires, then we don't want the newline to have been added.
+        else if line_ending.is_none() && idx != 0 && !is_last_line {
+            output.push('\n');
+        }
 
-            let is_highlighted = options
-                .highlight_lines
-                .iter()
-                .any(|r| r.contains(&line_num));
-
-            if options.show_line_numbers {
-                let line_num = options.line_number_start + (idx as isize);
-                let line_num_s = line_num.to_string();
-                let s = std::iter::repeat_n(' ', line_numbers_size - line_num_s.chars().count())
-                    .chain(line_num_s.chars())
-                    .collect::<String>();
-                if let Some(line_number_foreground) = line_number_foreground {
-                    output.push_str("\x1b[");
-                    line_number_foreground.as_ansi_fg(&mut output);
-                    output.push('m');
-                }
-                output.push_str(&format!("  {s} "));
-                if line_number_foreground.is_some() {
-                    // reset
-                    output.push_str("\x1b[0m");
-                }
-            }
+        // Skip hidden lines
+        if options.hide_lines.iter().any(|r| r.contains(&line_num)) {
+            return;
+        }
 
-            // Highlight individual tokens
-            for token in line_tokens {
-                token.as_ansi(
-                    &highlighted.theme,
-                    self.theme_type,
-                    highlight_background_color.filter(|_| is_highlighted),
-                    &mut output,
-                )
+        let is_highlighted = options
+            .highlight_lines
+            .iter()
+            .any(|r| r.contains(&line_num));
+
+        if options.show_line_numbers {
+            let line_num = options.line_number_start + (idx as isize);
+            let line_num_s = line_num.to_string();
+            let s = std::iter::repeat_n(
+                ' ',
+                self.line_numbers_size
+                    .saturating_sub(line_num_s.chars().count()),
+            )
+            .chain(line_num_s.chars())
+            .collect::<String>();
+            if let Some(line_number_foreground) = self.line_number_foreground {
+                output.push_str("\x1b[");
+                line_number_foreground.as_ansi_fg(output);
+                output.push('m');
+            }
+            output.push_str(&format!("  {s} "));
+            if self.line_number_foreground.is_some() {
+                // reset
+                output.push_str("\x1b[0m");
             }
         }
 
-        output
+        // Highlight individual tokens
+        for token in line_tokens {
+            token.as_ansi(
+                self.theme,
+                self.theme_type,
+                self.highlight_background_color.filter(|_| is_highlighted),
+                output,
+            )
+        }
+
+        if let Some(line_ending) = line_ending {
+            output.push_str(line_ending.as_str());
+        }
     }
 }
 
@@ -3,12 +3,23 @@
 //! Scopes like "source.rust.meta.function" are packed into a single u128:
 //! Memory layout: `[atom0][atom1][atom2][atom3][atom4][atom5][atom6][atom7]`
 //! Each atom is 16 bits, storing repository_index + 1 (0 = unused slot)
-//! Any atom above the 8th will be ignored
+//! Scopes with more than 8 atoms keep the rest in a shared tail on the heap. They are rare
+//! enough that most scopes never allocate.
+//!
+//! The atoms are stored in a `ScopeRepository` owned by the registry, a scope is only meaningful
+//! for the repository that created it.
+//!
+//! Atoms are never removed from a repository. Scope names built from captures while tokenizing can
+//! contain anything from the input so they can only register up to `MAX_DYNAMIC_ATOMS` new atoms.
+//! When a new atom can't be registered, either because of that or because the repository is
+//! full, the scope is truncated right before it: `meta.tag.custom-element.html` becomes
+//! `meta.tag` and gets styled like its parent scope.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::hash::Hash;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use serde::{Deserialize, Serialize};
 
@@ -19,34 +30,38 @@ pub const MAX_ATOMS_IN_REPOSITORY: usize = u16::MAX as usize - 2;
 pub const EMPTY_ATOM_INDEX: usize = u16::MAX as usize - 1;
 // Stored atom number for empty atoms
 pub const EMPTY_ATOM_NUMBER: u16 = u16::MAX;
+// How many atoms scope names built from captures can add to a repository
+pub const MAX_DYNAMIC_ATOMS: usize = 16_384;
 
 /// A scope represents a hierarchical position in source code like "source.rust.meta.function"
-/// Internally stored as a single u128 with up to 8 atoms packed as 16-bit indices
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Copy, Default, Hash, Serialize, Deserialize)]
+/// Internally stored as a single u128 with up to 8 atoms packed as 16-bit indices, the atoms
+/// after the 8th one being stored in a tail.
+///
+/// The atoms are indices in the scope repository of the registry that created the scope:
+/// use `Registry::scope_name` to get its string form.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Serialize, Deserialize)]
 pub struct Scope {
     /// Packed atoms in MSB-first order for lexicographic comparison
     atoms: u128,
+    /// The atom numbers after the 8 packed ones, only set if there are any.
+    /// Deriving `Ord` still sorts scopes lexicographically since `None` comes first.
+    tail: Option<Arc<[u16]>>,
 }
 
 impl Scope {
-    /// Create a new scope from a dot-separated string, truncating to 8 atoms if longer.
-    /// It returns a Vec as the scope string might contain spaces, in which case it will split
-    /// on it and return multiple scopes
-    /// e.g., "string.json support.type.property-name.json" -> [Scope("string.json"), Scope("support.type.property-name.json")]
-    pub fn new(scope_str: &str) -> Vec<Scope> {
-        let mut repo = lock_global_scope_repo();
-        scope_str
-            .split_whitespace()
-            .map(|part| repo.parse(part.trim()))
-            .collect()
-    }
-
-    /// Extract a single atom at the given index (0-7)
+    /// Extract a single atom at the given index
     /// Returns atom_number: 0 for unused slots, u16::MAX for empty atoms or (atom_index + 1) for valid
     /// non-empty atoms
     #[inline]
-    pub fn atom_at(self, index: usize) -> u16 {
-        debug_assert!(index < MAX_ATOMS_IN_SCOPE);
+    pub fn atom_at(&self, index: usize) -> u16 {
+        if index >= MAX_ATOMS_IN_SCOPE {
+            return self
+                .tail
+                .as_ref()
+                .and_then(|tail| tail.get(index - MAX_ATOMS_IN_SCOPE))
+                .copied()
+                .unwrap_or(0);
+        }
         // MSB-first layout: index 0 is in bits [127:112], index 1 in [111:96], etc.
         let shift = (MAX_ATOMS_IN_SCOPE - 1 - index) * 16;
         ((self.atoms >> shift) & 0xFFFF) as u16
@@ -54,26 +69,38 @@ impl Scope {
 
     /// Count the number of atoms in this scope
     #[inline]
-    pub fn len(self) -> u32 {
-        MAX_ATOMS_IN_SCOPE as u32 - self.missing_atoms()
+    pub fn len(&self) -> u32 {
+        let tail_len = self.tail.as_ref().map_or(0, |tail| tail.len() as u32);
+        MAX_ATOMS_IN_SCOPE as u32 - self.missing_atoms() + tail_len
     }
 
+    /// Whether this scope has no atoms at all
     #[inline]
-    pub fn is_empty(self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.atoms == 0
     }
 
     /// Count unused slots by finding trailing zeros (LSB side has unused atoms)
     /// Since atoms are packed MSB-first, unused slots create trailing zeros
     #[inline]
-    fn missing_atoms(self) -> u32 {
+    fn missing_atoms(&self) -> u32 {
         self.atoms.trailing_zeros() / 16
     }
 
     /// Check if this scope is a prefix of another scope using bitwise masking
-    /// This is the core operation for theme selector matching - must be O(1)
+    /// This is the core operation for theme selector matching - must be O(1) for scopes of
+    /// up to 8 atoms
     #[inline]
-    pub fn is_prefix_of(self, other: Scope) -> bool {
+    pub fn is_prefix_of(&self, other: &Scope) -> bool {
+        if let Some(tail) = &self.tail {
+            // We have 8+ atoms so they all need to be the same
+            return self.atoms == other.atoms
+                && other
+                    .tail
+                    .as_ref()
+                    .is_some_and(|other_tail| other_tail.starts_with(tail));
+        }
+
         let missing = self.missing_atoms();
 
         if missing == MAX_ATOMS_IN_SCOPE as u32 {
@@ -88,120 +115,208 @@ impl Scope {
         (self.atoms ^ other.atoms) & mask == 0
     }
 
-    /// Convert back to string form - expensive, only use for debugging/display
-    pub fn build_string(self) -> String {
-        let repo = lock_global_scope_repo();
-        repo.to_string(self)
+    /// The scope made of the first `len` atoms of this one
+    pub(crate) fn prefix(&self, len: usize) -> Scope {
+        if len >= self.len() as usize {
+            return self.clone();
+        }
+        if len > MAX_ATOMS_IN_SCOPE {
+            return Scope {
+                atoms: self.atoms,
+                tail: self
+                    .tail
+                    .as_ref()
+                    .map(|tail| tail[..len - MAX_ATOMS_IN_SCOPE].into()),
+            };
+        }
+
+        let mask = match len {
+            0 => 0,
+            _ => u128::MAX << ((MAX_ATOMS_IN_SCOPE - len) * 16),
+        };
+        Scope {
+            atoms: self.atoms & mask,
+            tail: None,
+        }
     }
 }
 
-impl fmt::Debug for Scope {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Scope(\"{}\")", self.build_string())
+impl Scope {
+    /// The atom numbers of this scope, in order
+    pub(crate) fn atom_numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len() as usize).map(|i| self.atom_at(i))
     }
 }
 
-impl fmt::Display for Scope {
+impl fmt::Debug for Scope {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.build_string())
+        // We don't have access to the repository here so we can only show the atom numbers
+        let atoms: Vec<u16> = (0..self.len() as usize).map(|i| self.atom_at(i)).collect();
+        write!(f, "Scope({atoms:?})")
     }
 }
 
-/// Global repository that maps atom strings to indices for deduplication
-#[derive(Debug, Clone, Default)]
+/// Repository that maps atom strings to indices for deduplication.
+///
+/// Each registry owns one, shared with its clones. It can be used from several threads at once
+/// without locking: new atoms can be registered while tokenizing, eg for scope names using
+/// captures.
+#[derive(Debug, Default)]
 pub(crate) struct ScopeRepository {
     /// Index-to-string mapping
-    pub(crate) atoms: Vec<String>,
+    atoms: papaya::HashMap<usize, String>,
     /// String-to-index for fast lookup
-    atom_index_map: HashMap<String, usize>,
+    atom_index_map: papaya::HashMap<String, usize>,
+    /// The index the next registered atom will get
+    next_index: AtomicUsize,
+    /// How many atoms were registered while tokenizing
+    dynamic_atoms: AtomicUsize,
 }
 
 impl ScopeRepository {
-    fn new() -> Self {
-        Self::default()
-    }
-
     #[cfg(feature = "dump")]
     pub fn from_atoms(atoms: Vec<String>) -> Self {
-        let mut atom_index_map = HashMap::with_capacity(atoms.len());
-        for (index, atom) in atoms.iter().enumerate() {
-            atom_index_map.insert(atom.clone(), index);
-        }
-        Self {
-            atoms,
-            atom_index_map,
+        let repo = Self {
+            next_index: AtomicUsize::new(atoms.len()),
+            ..Default::default()
+        };
+        {
+            let atoms_pin = repo.atoms.pin();
+            let atom_index_map = repo.atom_index_map.pin();
+            for (index, atom) in atoms.into_iter().enumerate() {
+                atom_index_map.insert(atom.clone(), index);
+                atoms_pin.insert(index, atom);
+            }
         }
+        repo
+    }
+
+    /// All the atoms, in index order
+    #[cfg(feature = "dump")]
+    pub fn atoms(&self) -> Vec<String> {
+        let atoms = self.atoms.pin();
+        (0..self.next_index.load(Ordering::Acquire))
+            .map(|i| atoms.get(&i).cloned().unwrap_or_default())
+            .collect()
     }
 
     /// Get existing index or register new atom, returning repository index
-    /// Returns atom_index: 0-based position in the repository atoms vector
-    fn atom_to_index(&mut self, atom: &str) -> usize {
+    /// Returns atom_index: 0-based position in the repository atoms vector, or None if the atom
+    /// is new and we can't register it.
+    fn atom_to_index(&self, atom: &str, dynamic: bool) -> Option<usize> {
         // Handle empty atoms specially - return reserved index
         if atom.is_empty() {
-            return EMPTY_ATOM_INDEX;
+            return Some(EMPTY_ATOM_INDEX);
         }
 
+        let atom_index_map = self.atom_index_map.pin();
         // Fast path: atom already registered
-        if let Some(&index) = self.atom_index_map.get(atom) {
-            return index;
+        if let Some(&index) = atom_index_map.get(atom) {
+            return Some(index);
         }
 
-        if self.atoms.len() >= MAX_ATOMS_IN_REPOSITORY {
-            panic!(
-                "Too many atoms in repository: exceeded MAX_ATOMS_IN_REPOSITORY of {MAX_ATOMS_IN_REPOSITORY}"
-            );
+        // Slow path: register new atom if we still have room for it
+        if dynamic {
+            reserve(&self.dynamic_atoms, MAX_DYNAMIC_ATOMS)?;
         }
+        let index = reserve(&self.next_index, MAX_ATOMS_IN_REPOSITORY)?;
+        // The string needs to be resolvable before anyone can get the index. If another thread
+        // registered the same atom in the meantime, we use its index and ours is left unused.
+        self.atoms.pin().insert(index, atom.to_owned());
+        Some(*atom_index_map.get_or_insert(atom.to_owned(), index))
+    }
+
+    /// Parse a string containing one or more scopes.
+    /// It returns a Vec as the scope string might contain spaces, in which case it will split
+    /// on it and return multiple scopes
+    /// e.g., "string.json support.type.property-name.json" -> [Scope("string.json"), Scope("support.type.property-name.json")]
+    pub(crate) fn parse_scopes(&self, scope_str: &str) -> Vec<Scope> {
+        scope_str
+            .split_whitespace()
+            .map(|part| self.parse(part.trim()))
+            .collect()
+    }
 
-        // Slow path: register new atom
-        let index = self.atoms.len();
-        self.atoms.push(atom.to_owned());
-        self.atom_index_map.insert(atom.to_owned(), index);
-        index
+    /// Same as `parse_scopes` but for scope names built from captures while tokenizing, which
+    /// can only register up to `MAX_DYNAMIC_ATOMS` new atoms.
+    pub(crate) fn parse_dynamic_scopes(&self, scope_str: &str) -> Vec<Scope> {
+        scope_str
+            .split_whitespace()
+            .map(|part| self.parse_with(part.trim(), true))
+            .collect()
     }
 
-    /// Convert atom number back to string
-    /// Takes atom_number: 1-based encoded value stored in Scope bits (atom_index + 1)
-    pub(crate) fn atom_number_to_str(&self, atom_number: u16) -> &str {
-        debug_assert!(atom_number > 0);
-        &self.atoms[(atom_number - 1) as usize]
+    /// Parse dot-separated string into bit-packed scope
+    pub(crate) fn parse(&self, scope_str: &str) -> Scope {
+        self.parse_with(scope_str, false)
     }
 
-    /// Parse dot-separated string into bit-packed scope, truncating if > 8 atoms
-    fn parse(&mut self, scope_str: &str) -> Scope {
+    fn parse_with(&self, scope_str: &str, dynamic: bool) -> Scope {
         if scope_str.is_empty() {
             return Scope::default();
         }
 
-        let parts: Vec<&str> = scope_str.split('.').collect();
-        let atoms_to_process = parts.len().min(MAX_ATOMS_IN_SCOPE); // Truncate to 8 atoms
         let mut atoms = 0u128;
+        let mut tail = Vec::new();
 
-        for (i, &part) in parts.iter().take(atoms_to_process).enumerate() {
+        for (i, part) in scope_str.split('.').enumerate() {
             // Process ALL atoms including empty ones (now handled by atom_to_index)
-            let index = self.atom_to_index(part); // atom_index: 0-based repository position
+            // atom_index: 0-based repository position
+            let Some(index) = self.atom_to_index(part, dynamic) else {
+                // We're out of room for new atoms, keep the part of the scope we know
+                break;
+            };
             // Convert to atom_number: 1-based encoded value (index + 1) so that 0 can mean "unused slot"
-            let atom_number = (index + 1) as u128;
+            let atom_number = (index + 1) as u16;
+
+            if i < MAX_ATOMS_IN_SCOPE {
+                // Pack MSB-first: first atom goes in highest bits for lexicographic ordering
+                let shift = (MAX_ATOMS_IN_SCOPE - 1 - i) * 16;
+                atoms |= (atom_number as u128) << shift;
+            } else {
+                tail.push(atom_number);
+            }
+        }
+
+        Scope {
+            atoms,
+            tail: (!tail.is_empty()).then(|| tail.into()),
+        }
+    }
 
-            // Pack MSB-first: first atom goes in highest bits for lexicographic ordering
-            let shift = (MAX_ATOMS_IN_SCOPE - 1 - i) * 16;
-            atoms |= atom_number << shift;
+    /// The string of an atom number, as stored in scopes
+    pub(crate) fn atom_name(&self, atom_number: u16) -> Option<String> {
+        match atom_number {
+            0 => None,
+            EMPTY_ATOM_NUMBER => Some(String::new()),
+            a => self.atoms.pin().get(&(a as usize - 1)).cloned(),
         }
+    }
 
-        Scope { atoms }
+    /// The scope of another repository in this one, given the names of its atoms there.
+    /// New atoms count as dynamic ones and the scope ends at the first atom without name.
+    pub(crate) fn reintern(&self, scope: &Scope, atom_names: &BTreeMap<u16, String>) -> Scope {
+        let parts: Vec<&str> = scope
+            .atom_numbers()
+            .map_while(|a| atom_names.get(&a).map(String::as_str))
+            .collect();
+        self.parse_with(&parts.join("."), true)
     }
 
     /// Reconstruct string from bit-packed scope
-    fn to_string(&self, scope: Scope) -> String {
+    pub(crate) fn to_string(&self, scope: &Scope) -> String {
+        let atoms = self.atoms.pin();
         let mut parts = Vec::new();
 
-        for i in 0..MAX_ATOMS_IN_SCOPE {
+        for i in 0..scope.len() as usize {
             match scope.atom_at(i) {
                 0 => break,
                 a if a == EMPTY_ATOM_NUMBER => {
                     parts.push("");
                 }
+                // Stored as atom_index + 1 so that 0 can mean "unused slot"
                 a => {
-                    parts.push(self.atom_number_to_str(a));
+                    parts.push(atoms.get(&(a as usize - 1)).map_or("", String::as_str));
                 }
             }
         }
@@ -210,23 +325,13 @@ impl ScopeRepository {
     }
 }
 
-/// Global singleton repository with thread-safe access
-static SCOPE_REPO: std::sync::OnceLock<Mutex<ScopeRepository>> = std::sync::OnceLock::new();
-
-pub(crate) fn lock_global_scope_repo() -> MutexGuard<'static, ScopeRepository> {
-    SCOPE_REPO
-        .get_or_init(|| Mutex::new(ScopeRepository::new()))
-        .lock()
-        .expect("Failed to lock scope repository")
-}
-
-/// Replace the global ScopeRepository with a new one
-/// This is used when loading a registry from disk to restore the complete state
-/// Note: Only the first call succeeds; subsequent calls are silently ignored
-#[cfg(feature = "dump")]
-pub(crate) fn replace_global_scope_repo(new_repo: ScopeRepository) {
-    // Ignore error if already set: first call wins
-    let _ = SCOPE_REPO.set(Mutex::new(new_repo));
+/// Increments the counter if it is below the limit, returning its previous value
+fn reserve(counter: &AtomicUsize, limit: usize) -> Option<usize> {
+    counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            (n < limit).then_some(n + 1)
+        })
+        .ok()
 }
 
 #[cfg(test)]
@@ -235,40 +340,67 @@ mod tests {
 
     #[test]
     fn test_basic_scope_creation() {
-        let scope = Scope::new("source.rust.meta.function")[0];
+        let repo = ScopeRepository::default();
+        let scope = repo.parse("source.rust.meta.function");
         assert_eq!(scope.len(), 4);
-        assert_eq!(scope.build_string(), "source.rust.meta.function");
+        assert_eq!(repo.to_string(&scope), "source.rust.meta.function");
     }
 
     #[test]
     fn test_empty_scope() {
-        let scope = Scope::new("");
+        let repo = ScopeRepository::default();
+        let scope = repo.parse_scopes("");
         assert_eq!(scope.len(), 0);
         assert!(scope.is_empty());
     }
 
     #[test]
     fn test_prefix_matching() {
-        let prefix = Scope::new("source.rust")[0];
-        let full = Scope::new("source.rust.meta.function")[0];
-        let different = Scope::new("source.javascript")[0];
-
-        assert!(prefix.is_prefix_of(full));
-        assert!(prefix.is_prefix_of(prefix));
-        assert!(!prefix.is_prefix_of(different));
+        let repo = ScopeRepository::default();
+        let prefix = repo.parse("source.rust");
+        let full = repo.parse("source.rust.meta.function");
+        let different = repo.parse("source.javascript");
+
+        assert!(prefix.is_prefix_of(&full));
+        assert!(prefix.is_prefix_of(&prefix));
+        assert!(!prefix.is_prefix_of(&different));
     }
 
     #[test]
-    fn test_atom_truncation() {
-        // Scopes with >8 atoms should be truncated to first 8
-        let long_scope = Scope::new("a.b.c.d.e.f.g.h.i.j.k.l")[0];
-        assert_eq!(long_scope.len(), 8);
-        assert_eq!(long_scope.build_string(), "a.b.c.d.e.f.g.h");
+    fn test_long_scopes() {
+        let repo = ScopeRepository::default();
+        // Atoms after the 8th one are kept in the tail
+        let long_scope = repo.parse("a.b.c.d.e.f.g.h.i.j.k.l");
+        assert_eq!(long_scope.len(), 12);
+        assert_eq!(repo.to_string(&long_scope), "a.b.c.d.e.f.g.h.i.j.k.l");
+        assert_ne!(long_scope.atom_at(11), 0);
+        assert_eq!(long_scope.atom_at(12), 0);
+
+        let eight = repo.parse("a.b.c.d.e.f.g.h");
+        let nine = repo.parse("a.b.c.d.e.f.g.h.i");
+        let other_nine = repo.parse("a.b.c.d.e.f.g.h.x");
+        assert!(eight.is_prefix_of(&long_scope));
+        assert!(nine.is_prefix_of(&long_scope));
+        assert!(long_scope.is_prefix_of(&long_scope));
+        assert!(!long_scope.is_prefix_of(&nine));
+        assert!(!nine.is_prefix_of(&eight));
+        assert!(!other_nine.is_prefix_of(&long_scope));
+        assert!(!repo.parse("z.b.c.d.e.f.g.h.i").is_prefix_of(&long_scope));
+
+        // Still sorted lexicographically
+        assert!(eight < nine);
+        assert!(nine < long_scope);
+
+        assert_eq!(long_scope.prefix(9), nine);
+        assert_eq!(long_scope.prefix(8), eight);
+        assert_eq!(long_scope.prefix(1), repo.parse("a"));
+        assert_eq!(long_scope.prefix(20), long_scope);
     }
 
     #[test]
     fn test_atom_extraction() {
-        let scope = Scope::new("source.rust.meta")[0];
+        let repo = ScopeRepository::default();
+        let scope = repo.parse("source.rust.meta");
 
         assert_ne!(scope.atom_at(0), 0); // "source" is present
         assert_ne!(scope.atom_at(1), 0); // "rust" is present
@@ -279,8 +411,9 @@ mod tests {
 
     #[test]
     fn test_scope_ordering() {
-        let scope1 = Scope::new("source.rust")[0];
-        let scope2 = Scope::new("source.rust.meta")[0];
+        let repo = ScopeRepository::default();
+        let scope1 = repo.parse("source.rust");
+        let scope2 = repo.parse("source.rust.meta");
 
         // Longer scopes should sort after shorter prefixes
         assert!(scope1 < scope2);
@@ -288,9 +421,10 @@ mod tests {
 
     #[test]
     fn test_scope_equality() {
-        let scope1 = Scope::new("source.rust.meta")[0];
-        let scope2 = Scope::new("source.rust.meta")[0];
-        let scope3 = Scope::new("source.rust")[0];
+        let repo = ScopeRepository::default();
+        let scope1 = repo.parse("source.rust.meta");
+        let scope2 = repo.parse("source.rust.meta");
+        let scope3 = repo.parse("source.rust");
 
         assert_eq!(scope1, scope2);
         assert_ne!(scope1, scope3);
@@ -298,17 +432,80 @@ mod tests {
 
     #[test]
     fn test_empty_atom_preservation() {
+        let repo = ScopeRepository::default();
         // Test the main bug fix: scope names with double dots should be preserved
-        let scope = Scope::new("meta.tag.object.svg..end.html")[0];
-        assert_eq!(scope.build_string(), "meta.tag.object.svg..end.html");
+        let scope = repo.parse("meta.tag.object.svg..end.html");
+        assert_eq!(repo.to_string(&scope), "meta.tag.object.svg..end.html");
         assert_eq!(scope.len(), 7);
     }
 
     #[test]
     fn test_empty_atoms_various_positions() {
+        let repo = ScopeRepository::default();
         // Test empty atoms in different positions
-        assert_eq!(Scope::new("a...b")[0].build_string(), "a...b");
-        assert_eq!(Scope::new(".start.end")[0].build_string(), ".start.end");
-        assert_eq!(Scope::new("start.end.")[0].build_string(), "start.end.");
+        assert_eq!(repo.to_string(&repo.parse("a...b")), "a...b");
+        assert_eq!(repo.to_string(&repo.parse(".start.end")), ".start.end");
+        assert_eq!(repo.to_string(&repo.parse("start.end.")), "start.end.");
+    }
+
+    #[test]
+    fn test_dynamic_atoms_are_bounded() {
+        let repo = ScopeRepository::default();
+        let known = repo.parse("meta.tag.html");
+
+        for i in 0..MAX_DYNAMIC_ATOMS {
+            let scope = repo
+                .parse_dynamic_scopes(&format!("meta.tag.el{i}.html"))
+                .remove(0);
+            assert_eq!(scope.len(), 4);
+        }
+
+        // No room left for new atoms: the scope stops right before the new one
+        let scope = repo
+            .parse_dynamic_scopes("meta.tag.overflow.html")
+            .remove(0);
+        assert_eq!(repo.to_string(&scope), "meta.tag");
+        assert!(scope.is_prefix_of(&known));
+        // Existing atoms can still be used
+        let scope = repo.parse_dynamic_scopes("meta.tag.el0.html").remove(0);
+        assert_eq!(repo.to_string(&scope), "meta.tag.el0.html");
+        // And grammars/themes can still register new ones
+        assert_eq!(
+            repo.to_string(&repo.parse("meta.overflow")),
+            "meta.overflow"
+        );
+    }
+
+    #[test]
+    fn test_repositories_are_independent() {
+        let repo1 = ScopeRepository::default();
+        let repo2 = ScopeRepository::default();
+        let scope1 = repo1.parse("source.rust");
+        let scope2 = repo2.parse("string.quoted");
+
+        // Same atom numbers in both, but they resolve to their own strings
+        assert_eq!(scope1, scope2);
+        assert_eq!(repo1.to_string(&scope1), "source.rust");
+        assert_eq!(repo2.to_string(&scope2), "string.quoted");
+    }
+
+    #[test]
+    fn test_can_register_atoms_from_multiple_threads() {
+        let repo = ScopeRepository::default();
+        let names: Vec<String> = (0..50).map(|i| format!("atom{i}.shared")).collect();
+
+        let scopes: Vec<Vec<Scope>> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| names.iter().map(|n| repo.parse(n)).collect::<Vec<_>>()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        for thread_scopes in &scopes {
+            assert_eq!(thread_scopes, &scopes[0]);
+        }
+        for (name, scope) in names.iter().zip(&scopes[0]) {
+            assert_eq!(&repo.to_string(scope), name);
+        }
     }
 }
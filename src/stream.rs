@@ -0,0 +1,254 @@
+use std::io::{self, BufRead};
+
+use crate::error::{Error, GialloResult};
+use crate::grammars::GrammarId;
+use crate::highlight::{HighlightedText, Highlighter, MergingOptions};
+use crate::registry::{HighlightOptions, Registry};
+use crate::themes::{CompiledTheme, ThemeVariant};
+use crate::tokenizer::{StateStack, Tokenizer};
+
+/// An iterator over the highlighted lines of a reader, created by `Registry::highlight_reader`.
+///
+/// Lines are read and highlighted one at a time, only the tokenizer state is carried from one
+/// line to the next so memory usage does not depend on the size of the input.
+/// Lines are split on `\n`, `\r\n` and `\r` the same way as `Registry::highlight`.
+///
+/// Iteration stops after the first error, which is either an I/O error, including for
+/// invalid UTF-8, a tokenization error or a cancellation.
+#[derive(Debug)]
+pub struct HighlightedLines<'r, R> {
+    reader: R,
+    language: &'r str,
+    theme: ThemeVariant<&'r CompiledTheme>,
+    tokenizer: Tokenizer<'r>,
+    highlighter: Highlighter<'r>,
+    merging_options: MergingOptions,
+    /// The tokenizer state at the end of the previous line, `None` before the first line
+    state: Option<StateStack>,
+    /// Index of the next line to be read
+    line_index: usize,
+    degraded_lines: Vec<usize>,
+    /// The bytes of the current line, reused across lines
+    buf: Vec<u8>,
+    /// The previous line ended with `\r`, a `\n` right after it is part of the same terminator
+    skip_next_lf: bool,
+    /// The previous line had a terminator: we need to yield a final empty line at EOF
+    ended_with_terminator: bool,
+    done: bool,
+}
+
+impl<'r, R: BufRead> HighlightedLines<'r, R> {
+    pub(crate) fn new(
+        registry: &'r Registry,
+        reader: R,
+        grammar_id: GrammarId,
+        theme: ThemeVariant<&'r CompiledTheme>,
+        options: &HighlightOptions,
+    ) -> Self {
+        let mut tokenizer = Tokenizer::new(grammar_id, registry);
+        tokenizer.set_limits(options.limits());
+
+        Self {
+            reader,
+            language: &registry.grammars[grammar_id].name,
+            theme,
+            tokenizer,
+            highlighter: Highlighter::from_themes(theme),
+            merging_options: options.merging_options(),
+            state: None,
+            line_index: 0,
+            degraded_lines: Vec::new(),
+            buf: Vec::new(),
+            skip_next_lf: false,
+            ended_with_terminator: false,
+            done: false,
+        }
+    }
+
+    /// The language of the grammar used to highlight
+    pub fn language(&self) -> &'r str {
+        self.language
+    }
+
+    /// The compiled theme(s) used to highlight
+    pub fn theme(&self) -> ThemeVariant<&'r CompiledTheme> {
+        self.theme
+    }
+
+    /// The 0-based indices of the lines yielded so far that were not fully tokenized because
+    /// of the limits set in the `HighlightOptions`.
+    pub fn degraded_lines(&self) -> &[usize] {
+        &self.degraded_lines
+    }
+
+    /// Reads the next line into `self.buf`, without its terminator.
+    /// Returns false if there are no more lines.
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.buf.clear();
+
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if available.is_empty() {
+                // Like `str::split`, a trailing terminator is followed by an empty line
+                let has_line = !self.buf.is_empty() || self.ended_with_terminator;
+                self.ended_with_terminator = false;
+                return Ok(has_line);
+            }
+
+            if self.skip_next_lf {
+                self.skip_next_lf = false;
+                if available[0] == b'\n' {
+                    self.reader.consume(1);
+                    continue;
+                }
+            }
+
+            match available.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(i) => {
+                    self.buf.extend_from_slice(&available[..i]);
+                    self.skip_next_lf = available[i] == b'\r';
+                    self.ended_with_terminator = true;
+                    self.reader.consume(i + 1);
+                    return Ok(true);
+                }
+                None => {
+                    let len = available.len();
+                    self.buf.extend_from_slice(available);
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+
+    fn highlight_next_line(&mut self) -> GialloResult<Option<Vec<HighlightedText>>> {
+        if !self.read_line()? {
+            return Ok(None);
+        }
+
+        let line = std::str::from_utf8(&self.buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let (tokens, state) = self
+            .tokenizer
+            .tokenize_next_line(line, self.state.take())
+            .map_err(Error::TokenizeRegex)?;
+        if self.tokenizer.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if self.tokenizer.last_line_degraded() {
+            self.degraded_lines.push(self.line_index);
+        }
+        self.state = Some(state);
+        self.line_index += 1;
+
+        Ok(self
+            .highlighter
+            .highlight_tokens(line, &[tokens], self.merging_options)
+            .pop())
+    }
+}
+
+impl<R: BufRead> Iterator for HighlightedLines<'_, R> {
+    type Item = GialloResult<Vec<HighlightedText>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let res = self.highlight_next_line().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use super::*;
+    use crate::registry::HighlightOptions;
+    use crate::test_utils::get_registry;
+    use crate::{HtmlRenderer, RenderOptions, TerminalRenderer};
+
+    const CODE: &str = "let a = 1;\r\n/* a\rb */\n\nlet s = \"é\";\r\n";
+
+    #[test]
+    fn can_stream_like_highlight() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let expected = registry.highlight(CODE, &options).unwrap();
+
+        // Small buffers to make sure we handle lines and \r\n split across reads
+        for capacity in [1, 2, 3, 64] {
+            let reader = BufReader::with_capacity(capacity, CODE.as_bytes());
+            let lines = registry
+                .highlight_reader(reader, &options)
+                .unwrap()
+                .collect::<GialloResult<Vec<_>>>()
+                .unwrap();
+            assert_eq!(lines, expected.tokens, "capacity {capacity}");
+        }
+    }
+
+    #[test]
+    fn stops_on_invalid_utf8() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let mut lines = registry
+            .highlight_reader(Cursor::new(b"let a;\n\xff\nlet b;"), &options)
+            .unwrap();
+        assert!(lines.next().unwrap().is_ok());
+        assert!(matches!(lines.next(), Some(Err(Error::Io(_)))));
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn can_render_progressively() {
+        let registry = get_registry();
+        let options = HighlightOptions::new("javascript", ThemeVariant::Single("vitesse-black"));
+        let highlighted = registry.highlight(CODE, &options).unwrap();
+        let render_options = RenderOptions {
+            show_line_numbers: true,
+            highlight_lines: vec![2..=2],
+            hide_lines: vec![3..=3],
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        HtmlRenderer::default()
+            .render_to_writer(
+                registry
+                    .highlight_reader(CODE.as_bytes(), &options)
+                    .unwrap(),
+                &render_options,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            HtmlRenderer::default().render(&highlighted, &render_options)
+        );
+
+        let mut out = Vec::new();
+        TerminalRenderer::default()
+            .render_to_writer(
+                registry
+                    .highlight_reader(CODE.as_bytes(), &options)
+                    .unwrap(),
+                &render_options,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            TerminalRenderer::default().render(&highlighted, &render_options)
+        );
+    }
+}
@@ -1,12 +1,97 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 use crate::error::{Error, GialloResult};
+use crate::scope::{Scope, ScopeRepository};
 use crate::themes::Color;
 use crate::themes::font_style::FontStyle;
 use crate::themes::raw::{RawTheme, TokenColorSettings};
 use crate::themes::selector::{ThemeSelector, parse_selector};
 
+/// How many scope stacks a theme remembers the style of before starting over
+const MAX_CACHED_STYLES: usize = 16_384;
+
+/// The styles already computed for scope stacks, shared by every highlight using the theme,
+/// including from other threads.
+/// It is cleared once it gets too big: most scope stacks are common to many documents so it
+/// fills up again quickly with the ones that matter.
+#[derive(Default)]
+pub(crate) struct StyleCache(papaya::HashMap<Arc<[Scope]>, Style>);
+
+impl StyleCache {
+    pub(crate) fn get(&self, scopes: &[Scope]) -> Option<Style> {
+        self.0.pin().get(scopes).copied()
+    }
+
+    pub(crate) fn insert(&self, scopes: &Arc<[Scope]>, style: Style) {
+        let cache = self.0.pin();
+        if cache.len() >= MAX_CACHED_STYLES {
+            cache.clear();
+        }
+        cache.insert(scopes.clone(), style);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.0.pin().clear();
+    }
+}
+
+// The cache is not part of the theme itself: clones start with an empty one and it's ignored
+// when comparing themes
+impl Clone for StyleCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl PartialEq for StyleCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for StyleCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StyleCache({} styles)", self.len())
+    }
+}
+
+/// The indices of the theme rules grouped by their target scope, so we only need to check the
+/// rules whose target scope is a prefix of a given scope instead of all of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RuleIndex(HashMap<Scope, Vec<usize>>);
+
+impl RuleIndex {
+    pub(crate) fn new(rules: &[CompiledThemeRule]) -> Self {
+        let mut index: HashMap<Scope, Vec<usize>> = HashMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            index
+                .entry(rule.selector.target_scope.clone())
+                .or_default()
+                .push(i);
+        }
+        Self(index)
+    }
+
+    /// Replaces the content of `out` with the indices of the rules that can match `scope`,
+    /// in the order of the rules.
+    pub(crate) fn candidates(&self, scope: &Scope, out: &mut Vec<usize>) {
+        out.clear();
+        for len in 1..=scope.len() as usize {
+            if let Some(rules) = self.0.get(&scope.prefix(len)) {
+                out.extend_from_slice(rules);
+            }
+        }
+        out.sort_unstable();
+    }
+}
+
 /// Maps unique colors from a theme to sequential numeric IDs.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct StyleMap {
@@ -71,13 +156,8 @@ struct Specificity {
 
 impl Specificity {
     fn calculate(selector: &ThemeSelector) -> Self {
-        // Count atoms in target scope (dots + 1)
-        let target_scope_string = selector.target_scope.build_string();
-        let scope_depth = if target_scope_string.is_empty() {
-            0
-        } else {
-            (target_scope_string.matches('.').count() + 1) as u32
-        };
+        // Number of atoms in target scope
+        let scope_depth = selector.target_scope.len();
 
         let parent_count = selector.parent_scopes.len() as u32;
 
@@ -203,12 +283,20 @@ pub struct CompiledTheme {
     pub line_number_foreground: Option<Color>,
     /// Theme rules sorted by specificity (most specific first)
     pub(crate) rules: Vec<CompiledThemeRule>,
+    /// Target scope -> rules, to avoid going through all the rules for each scope
+    pub(crate) rule_index: RuleIndex,
     /// Color -> class name for CSS output
     pub(crate) style_map: StyleMap,
+    /// Scope stack -> style, filled when highlighting
+    #[serde(skip)]
+    pub(crate) style_cache: StyleCache,
 }
 
 impl CompiledTheme {
-    pub(crate) fn from_raw_theme(raw_theme: RawTheme) -> GialloResult<Self> {
+    pub(crate) fn from_raw_theme(
+        raw_theme: RawTheme,
+        scope_repo: &ScopeRepository,
+    ) -> GialloResult<Self> {
         let theme_type = raw_theme
             .kind
             .map(|s| ThemeType::from_theme_str(&s))
@@ -257,7 +345,7 @@ impl CompiledTheme {
             let mut selectors = Vec::new();
 
             for scope_pattern in &token_rule.scope {
-                if let Some(selector) = parse_selector(scope_pattern) {
+                if let Some(selector) = parse_selector(scope_pattern, scope_repo) {
                     selectors.push(selector);
                 } else {
                     #[cfg(feature = "debug")]
@@ -299,8 +387,10 @@ impl CompiledTheme {
             default_style,
             highlight_background_color,
             line_number_foreground,
+            rule_index: RuleIndex::new(&rules),
             rules,
             style_map,
+            style_cache: StyleCache::default(),
         })
     }
 }
@@ -322,15 +412,59 @@ mod tests {
             println!("{:?}", path);
             RawTheme::load_from_file(&path)
                 .unwrap()
-                .compile()
+                .compile(&ScopeRepository::default())
                 .unwrap_or_else(|_| panic!("Failed to compile theme: {path:?}"));
         }
     }
 
+    #[test]
+    fn rule_index_finds_all_rules_that_can_match() {
+        let repo = ScopeRepository::default();
+        let theme = RawTheme::load_from_file("src/fixtures/themes/all_scope_styles.json")
+            .unwrap()
+            .compile(&repo)
+            .unwrap();
+        let mut candidates = Vec::new();
+
+        for name in [
+            "comment",
+            "comment.line.double-slash",
+            "variable.language.this.js",
+            "markup.quote",
+            "markup.quote.markdown",
+            "string.quoted",
+            "source",
+            "keyword",
+        ] {
+            let scope = repo.parse(name);
+            theme.rule_index.candidates(&scope, &mut candidates);
+            let expected: Vec<usize> = (0..theme.rules.len())
+                .filter(|&i| theme.rules[i].selector.target_scope.is_prefix_of(&scope))
+                .collect();
+            assert_eq!(candidates, expected, "scope: {name}");
+        }
+    }
+
+    #[test]
+    fn style_cache_is_bounded() {
+        let repo = ScopeRepository::default();
+        let cache = StyleCache::default();
+        let scopes: Vec<Arc<[Scope]>> = (0..MAX_CACHED_STYLES + 10)
+            .map(|i| Arc::from([repo.parse(&format!("scope{i}"))]))
+            .collect();
+
+        for scope_stack in &scopes {
+            cache.insert(scope_stack, Style::default());
+            assert_eq!(cache.get(scope_stack), Some(Style::default()));
+        }
+        assert!(cache.len() <= MAX_CACHED_STYLES);
+        assert_eq!(cache.get(&scopes[0]), None);
+    }
+
     #[test]
     fn can_load_default_from_token_colors() {
         let theme = RawTheme::load_from_file("src/fixtures/themes/all_scope_styles.json").unwrap();
-        let compiled = CompiledTheme::from_raw_theme(theme).unwrap();
+        let compiled = CompiledTheme::from_raw_theme(theme, &ScopeRepository::default()).unwrap();
         assert_eq!(compiled.default_style.background.as_hex(), "#23262E");
         assert_eq!(compiled.default_style.foreground.as_hex(), "#D5CED9");
     }
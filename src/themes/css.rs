@@ -92,6 +92,7 @@ pub(crate) fn generate_css(theme: &CompiledTheme, prefix: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scope::ScopeRepository;
     use crate::themes::RawTheme;
     use insta::assert_snapshot;
 
@@ -101,7 +102,7 @@ mod tests {
             "grammars-themes/packages/tm-themes/themes/vitesse-black.json",
         )
         .unwrap()
-        .compile()
+        .compile(&ScopeRepository::default())
         .unwrap();
         assert!(theme.style_map.fg.len() < theme.rules.len());
         assert_snapshot!(generate_css(&theme, "g-"));
@@ -34,3 +34,16 @@ impl ThemeVariant<Style> {
         }
     }
 }
+
+impl ThemeVariant<&str> {
+    /// Theme names are case-insensitive, we store them lowercased
+    pub(crate) fn to_lowercase(self) -> ThemeVariant<String> {
+        match self {
+            Self::Single(t) => ThemeVariant::Single(t.to_lowercase()),
+            Self::Dual { light, dark } => ThemeVariant::Dual {
+                light: light.to_lowercase(),
+                dark: dark.to_lowercase(),
+            },
+        }
+    }
+}
@@ -1,11 +1,15 @@
 use std::fmt;
-use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use serde::de::{MapAccess, Visitor};
 use serde::{Deserialize, Deserializer, de};
+use serde_json::{Map, Value};
 
-use crate::error::GialloResult;
+use crate::error::{Error, GialloResult};
+use crate::jsonc;
+use crate::scope::ScopeRepository;
+#[cfg(feature = "plist")]
+use crate::themes::Color;
 use crate::themes::compiled::CompiledTheme;
 
 /// Token color settings from VSCode theme JSON
@@ -167,16 +171,233 @@ pub struct RawTheme {
     pub token_colors: Vec<TokenColorRule>,
 }
 
+/// A Sublime Text/TextMate `.tmTheme` colour scheme
+#[cfg(feature = "plist")]
+#[derive(Debug, Deserialize)]
+struct TmTheme {
+    name: Option<String>,
+    settings: Vec<TmThemeItem>,
+}
+
+#[cfg(feature = "plist")]
+#[derive(Debug, Deserialize)]
+struct TmThemeItem {
+    scope: Option<String>,
+    #[serde(default)]
+    settings: TmThemeSettings,
+}
+
+#[cfg(feature = "plist")]
+impl TmThemeItem {
+    /// The item as a VSCode token color rule, without scope if it's the global item
+    fn into_rule(self) -> Value {
+        let mut rule = serde_json::json!({
+            "settings": {
+                "foreground": self.settings.foreground,
+                "background": self.settings.background,
+                "fontStyle": self.settings.font_style,
+            },
+        });
+        if let Some(scope) = self.scope {
+            rule["scope"] = scope.into();
+        }
+        rule
+    }
+}
+
+/// The settings of an item: the global ones are only set in the item without scope
+#[cfg(feature = "plist")]
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TmThemeSettings {
+    foreground: Option<String>,
+    background: Option<String>,
+    font_style: Option<String>,
+    line_highlight: Option<String>,
+    gutter_foreground: Option<String>,
+}
+
+/// Resolves `path` relative to the directory of the theme at `theme_path`, erroring if there is
+/// no such file
+fn theme_relative_path(theme_path: &Path, path: &Value) -> GialloResult<PathBuf> {
+    let path = path.as_str().unwrap_or_default();
+    let full_path = theme_path.parent().unwrap_or(Path::new("")).join(path);
+    if !full_path.is_file() {
+        return Err(Error::MissingThemeInclude {
+            theme: theme_path.display().to_string(),
+            include: path.to_owned(),
+        });
+    }
+    Ok(full_path)
+}
+
+/// The token color rules of the `.tmTheme` at `path`, for `tokenColors` given as a path.
+/// Like in VSCode, every item is a rule, the global one becoming a rule without scope.
+fn load_tm_theme_rules(path: &Path) -> GialloResult<Value> {
+    #[cfg(feature = "plist")]
+    {
+        let tm_theme: TmTheme = plist::from_file(path)?;
+        Ok(tm_theme
+            .settings
+            .into_iter()
+            .map(TmThemeItem::into_rule)
+            .collect())
+    }
+    #[cfg(not(feature = "plist"))]
+    Err(Error::UnsupportedThemeFormat(path.to_path_buf()))
+}
+
+/// Keeps a single key for the foreground and background so merging can't end up with both
+/// `foreground` and `editor.foreground` coming from different themes.
+/// The first one wins, like when deserializing `Colors`.
+fn normalize_colors(theme: &mut Map<String, Value>) {
+    let Some(Value::Object(colors)) = theme.get_mut("colors") else {
+        return;
+    };
+    for key in ["foreground", "background"] {
+        let editor_key = format!("editor.{key}");
+        let first = colors
+            .iter()
+            .find(|(k, _)| **k == key || **k == editor_key)
+            .map(|(_, value)| value.clone());
+        if let Some(value) = first {
+            colors.remove(key);
+            colors.insert(editor_key, value);
+        }
+    }
+}
+
+/// Merges the themes included by the JSON theme at `path`, recursively, and loads its
+/// `tokenColors` if they are the path of a `.tmTheme`.
+/// `parents` is the chain of themes including this one, to detect cycles.
+fn resolve_includes(
+    path: &Path,
+    mut theme: Map<String, Value>,
+    parents: &mut Vec<PathBuf>,
+) -> GialloResult<Map<String, Value>> {
+    if let Some(token_colors @ Value::String(_)) = theme.get("tokenColors") {
+        let rules = load_tm_theme_rules(&theme_relative_path(path, token_colors)?)?;
+        theme.insert("tokenColors".to_owned(), rules);
+    }
+    let Some(include) = theme.remove("include") else {
+        return Ok(theme);
+    };
+    let include_path = theme_relative_path(path, &include)?;
+    parents.push(path.canonicalize()?);
+    if parents.contains(&include_path.canonicalize()?) {
+        return Err(Error::ThemeIncludeCycle(path.display().to_string()));
+    }
+    let included = jsonc::from_file(&include_path)?;
+    let mut merged = resolve_includes(&include_path, included, parents)?;
+    parents.pop();
+
+    normalize_colors(&mut theme);
+    normalize_colors(&mut merged);
+    for (key, value) in theme {
+        match (key.as_str(), merged.get_mut(&key), value) {
+            ("colors", Some(Value::Object(colors)), Value::Object(own_colors)) => {
+                colors.extend(own_colors);
+            }
+            ("tokenColors", Some(Value::Array(rules)), Value::Array(own_rules)) => {
+                rules.extend(own_rules);
+            }
+            (_, _, value) => {
+                merged.insert(key, value);
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Whether the perceived brightness of the colour is over half
+#[cfg(feature = "plist")]
+fn is_light(color: Color) -> bool {
+    let brightness = 299 * u32::from(color.r) + 587 * u32::from(color.g) + 114 * u32::from(color.b);
+    brightness > 1000 * 128
+}
+
 impl RawTheme {
+    /// Loads a VSCode JSON theme or, if the extension is `.tmTheme` and the `plist` feature is
+    /// enabled, a Sublime Text/TextMate colour scheme.
+    ///
+    /// JSON themes can have comments and trailing commas, like VSCode allows, and parsing errors
+    /// mention the path of the file.
+    /// They can also `include` another theme, relative to their own file, which is merged
+    /// the same way VSCode does it: the colors of the including theme override the included
+    /// ones and its token colors are added after the included ones.
+    /// Their `tokenColors` can be the path of a `.tmTheme`, which requires the `plist` feature.
+    ///
+    /// When a theme has both `foreground` and `editor.foreground`, or `background` and
+    /// `editor.background`, the first one is used.
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> GialloResult<Self> {
-        let file = File::open(path)?;
-        let theme = serde_json::from_reader(file)?;
+        let path = path.as_ref();
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("tmTheme"))
+        {
+            #[cfg(feature = "plist")]
+            return Self::load_from_tm_theme(&std::fs::read(path)?);
+            #[cfg(not(feature = "plist"))]
+            return Err(Error::UnsupportedThemeFormat(path.to_path_buf()));
+        }
+        let with_path = |error| Error::JsonFile {
+            path: path.to_path_buf(),
+            error,
+        };
+        let theme = jsonc::from_str(&std::fs::read_to_string(path)?).map_err(with_path)?;
+        let theme = resolve_includes(path, theme, &mut Vec::new())?;
+        serde_json::from_value(Value::Object(theme)).map_err(with_path)
+    }
+
+    /// Loads a `.tmTheme` colour scheme, in XML or binary plist.
+    ///
+    /// The item without scope holds the global colours: `foreground` and `background` are the
+    /// default style, `lineHighlight` the background of highlighted lines and
+    /// `gutterForeground` the colour of line numbers. The other items become token colour rules.
+    /// The theme is light if its background is light.
+    #[cfg(feature = "plist")]
+    pub fn load_from_tm_theme(content: &[u8]) -> GialloResult<Self> {
+        let tm_theme: TmTheme = plist::from_bytes(content)?;
+
+        let mut colors = serde_json::Map::new();
+        let mut token_colors = Vec::new();
+        for item in tm_theme.settings {
+            if item.scope.is_some() {
+                token_colors.push(item.into_rule());
+                continue;
+            }
+            let settings = item.settings;
+            let globals = [
+                ("foreground", settings.foreground),
+                ("background", settings.background),
+                ("editor.lineHighlightBackground", settings.line_highlight),
+                ("editorLineNumber.foreground", settings.gutter_foreground),
+            ];
+            for (key, value) in globals {
+                if let Some(value) = value {
+                    colors.insert(key.to_owned(), value.into());
+                }
+            }
+        }
+
+        let kind = colors
+            .get("background")
+            .and_then(|bg| Color::from_hex(bg.as_str()?).ok())
+            .map(|bg| if is_light(bg) { "light" } else { "dark" });
+
+        // Going through the same deserialization as JSON themes, eg for the missing colours
+        let theme = serde_json::from_value(serde_json::json!({
+            "name": tm_theme.name.unwrap_or_default(),
+            "type": kind,
+            "colors": colors,
+            "tokenColors": token_colors,
+        }))?;
         Ok(theme)
     }
 
     /// Compile this raw grammar into an optimized compiled grammar
-    pub fn compile(self) -> GialloResult<CompiledTheme> {
-        CompiledTheme::from_raw_theme(self)
+    pub fn compile(self, scope_repo: &ScopeRepository) -> GialloResult<CompiledTheme> {
+        CompiledTheme::from_raw_theme(self, scope_repo)
     }
 }
 
@@ -249,9 +470,152 @@ mod tests {
         }
 
         // Test that the theme can be compiled successfully
-        let compiled_theme = theme.compile().expect("Failed to compile test theme");
+        let compiled_theme = theme
+            .compile(&ScopeRepository::default())
+            .expect("Failed to compile test theme");
 
         // Verify the compiled theme has the expected name
         assert_eq!(compiled_theme.name, "test");
     }
+
+    #[test]
+    fn can_load_jsonc_themes() {
+        let theme = RawTheme::load_from_file("src/fixtures/themes/jsonc.json").unwrap();
+        assert_eq!(theme.colors.background, "#1E1E1E");
+        assert_eq!(theme.token_colors[0].scope, vec!["comment", "string"]);
+
+        let err = RawTheme::load_from_file("src/fixtures/themes/broken.json").unwrap_err();
+        assert!(err.to_string().contains("broken.json"));
+        let Error::JsonFile { path, error } = err else {
+            panic!("expected a JSON error, got {err:?}");
+        };
+        assert!(path.ends_with("broken.json"));
+        assert_eq!((error.line(), error.column()), (5, 25));
+    }
+
+    #[test]
+    fn can_resolve_includes() {
+        let theme =
+            RawTheme::load_from_file("src/fixtures/themes/includes/dark_plus.json").unwrap();
+        assert_eq!(theme.name, "Dark+");
+        assert_eq!(theme.kind.as_deref(), Some("dark"));
+        // Colors from every level, the including theme winning
+        assert_eq!(
+            theme.colors,
+            Colors {
+                foreground: "#D4D4D4".to_owned(),
+                background: "#1F1F1F".to_owned(),
+                highlight_background: Some("#2A2D2E".to_owned()),
+                line_number_foreground: Some("#858585".to_owned()),
+            }
+        );
+        // Included rules first
+        let scopes: Vec<_> = theme
+            .token_colors
+            .iter()
+            .map(|r| r.scope.join(","))
+            .collect();
+        assert_eq!(
+            scopes,
+            vec!["comment", "string", "keyword", "keyword.control"]
+        );
+
+        assert!(matches!(
+            RawTheme::load_from_file("src/fixtures/themes/includes/cycle_a.json"),
+            Err(Error::ThemeIncludeCycle(_))
+        ));
+        match RawTheme::load_from_file("src/fixtures/themes/includes/missing_include.json") {
+            Err(Error::MissingThemeInclude { include, .. }) => {
+                assert_eq!(include, "./does_not_exist.json")
+            }
+            other => panic!("expected a missing include, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn first_color_key_wins_on_every_path() {
+        let theme = RawTheme::load_from_file("src/fixtures/themes/both_color_keys.json").unwrap();
+        assert_eq!(theme.colors.foreground, "#111111");
+        assert_eq!(theme.colors.background, "#444444");
+
+        // The including theme wins even if it uses the other key
+        let theme =
+            RawTheme::load_from_file("src/fixtures/themes/includes/plain_keys.json").unwrap();
+        assert_eq!(theme.colors.foreground, "#FFFFFF");
+        assert_eq!(theme.colors.background, "#1E1E1E");
+    }
+
+    #[cfg(feature = "plist")]
+    #[test]
+    fn can_load_token_colors_from_tm_theme() {
+        let theme = RawTheme::load_from_file("src/fixtures/themes/tm_token_colors.json").unwrap();
+        assert_eq!(theme.colors.background, "#272822");
+        let scopes: Vec<_> = theme
+            .token_colors
+            .iter()
+            .map(|r| r.scope.join(","))
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![
+                "",
+                "comment",
+                "string,constant.other.symbol",
+                "storage.type"
+            ]
+        );
+        assert_eq!(theme.token_colors[0].settings.foreground(), Some("#F8F8F2"));
+    }
+
+    #[cfg(not(feature = "plist"))]
+    #[test]
+    fn tm_theme_requires_plist_feature() {
+        let path = "src/fixtures/themes/monokai.tmTheme";
+        assert!(matches!(
+            RawTheme::load_from_file(path),
+            Err(Error::UnsupportedThemeFormat(p)) if p == Path::new(path)
+        ));
+        assert!(matches!(
+            RawTheme::load_from_file("src/fixtures/themes/tm_token_colors.json"),
+            Err(Error::UnsupportedThemeFormat(p)) if p.ends_with("monokai.tmTheme")
+        ));
+    }
+
+    #[cfg(feature = "plist")]
+    #[test]
+    fn can_load_tm_theme() {
+        let theme = RawTheme::load_from_file("src/fixtures/themes/monokai.tmTheme").unwrap();
+        assert_eq!(theme.name, "Monokai");
+        assert_eq!(theme.kind.as_deref(), Some("dark"));
+        assert_eq!(theme.token_colors.len(), 3);
+        assert_eq!(
+            theme.token_colors[1].scope,
+            vec!["string", "constant.other.symbol"]
+        );
+        assert_eq!(
+            theme.token_colors[2].settings.font_style.as_deref(),
+            Some("italic")
+        );
+
+        let compiled = theme.compile(&ScopeRepository::default()).unwrap();
+        assert_eq!(compiled.default_style.foreground.as_hex(), "#F8F8F2");
+        assert_eq!(compiled.default_style.background.as_hex(), "#272822");
+        assert_eq!(
+            compiled.highlight_background_color.map(|c| c.as_hex()),
+            Some("#3E3D32".to_owned())
+        );
+        assert_eq!(
+            compiled.line_number_foreground.map(|c| c.as_hex()),
+            Some("#90908A".to_owned())
+        );
+        assert_eq!(compiled.rules.len(), 4);
+
+        // The global colours are required, like for JSON themes
+        let content = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict><key>settings</key><array></array></dict></plist>"#;
+        assert!(matches!(
+            RawTheme::load_from_tm_theme(content),
+            Err(Error::Json(_))
+        ));
+    }
 }
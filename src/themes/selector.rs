@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::scope::Scope;
+use crate::scope::{Scope, ScopeRepository};
 
 /// Represents a parent scope requirement in a theme selector.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -45,7 +45,7 @@ impl ThemeSelector {
 
         // Check if target scope matches the innermost scope (last in stack)
         let (last, mut rest) = scope_stack.split_last().unwrap();
-        if !self.target_scope.is_prefix_of(*last) {
+        if !self.target_scope.is_prefix_of(last) {
             return false;
         }
 
@@ -61,7 +61,7 @@ impl ThemeSelector {
                 Parent::Direct(parent_scope) => {
                     // Direct parent must match the last remaining parent
                     match rest.split_last() {
-                        Some((last, r)) if parent_scope.is_prefix_of(*last) => {
+                        Some((last, r)) if parent_scope.is_prefix_of(last) => {
                             rest = r;
                         }
                         _ => return false, // No match or no more parents
@@ -71,7 +71,7 @@ impl ThemeSelector {
                     // Find this parent anywhere in remaining parents (from end to start)
                     match rest
                         .iter()
-                        .rposition(|&scope| parent_scope.is_prefix_of(scope))
+                        .rposition(|scope| parent_scope.is_prefix_of(scope))
                     {
                         Some(pos) => {
                             // Consume all parents up to and including this match
@@ -90,6 +90,26 @@ impl ThemeSelector {
 
         true
     }
+
+    /// The selector in string form, resolving the scopes with the given repository
+    pub(crate) fn as_string(&self, scope_repo: &ScopeRepository) -> String {
+        let mut out = String::new();
+        // Parents are stored from right to left
+        for parent in self.parent_scopes.iter().rev() {
+            match parent {
+                Parent::Anywhere(scope) => {
+                    out.push_str(&scope_repo.to_string(scope));
+                    out.push(' ');
+                }
+                Parent::Direct(scope) => {
+                    out.push_str(&scope_repo.to_string(scope));
+                    out.push_str(" > ");
+                }
+            }
+        }
+        out.push_str(&scope_repo.to_string(&self.target_scope));
+        out
+    }
 }
 
 /// Parses a theme selector string into a structured ThemeSelector.
@@ -101,7 +121,7 @@ impl ThemeSelector {
 /// - Parent scopes are processed left to right
 ///
 /// Returns `None` if the selector string is invalid or empty
-pub fn parse_selector(input: &str) -> Option<ThemeSelector> {
+pub fn parse_selector(input: &str, scope_repo: &ScopeRepository) -> Option<ThemeSelector> {
     let parts: Vec<&str> = input.split_whitespace().collect();
     if parts.is_empty() {
         return None;
@@ -111,7 +131,7 @@ pub fn parse_selector(input: &str) -> Option<ThemeSelector> {
     if *last == ">" {
         return None;
     }
-    let target_scope = Scope::new(last)[0];
+    let target_scope = scope_repo.parse(last);
 
     let mut parents = Vec::new();
     let mut is_direct = false;
@@ -120,7 +140,7 @@ pub fn parse_selector(input: &str) -> Option<ThemeSelector> {
             is_direct = true;
             continue;
         }
-        let parent_scope = Scope::new(part)[0];
+        let parent_scope = scope_repo.parse(part);
         parents.push(if is_direct {
             Parent::Direct(parent_scope)
         } else {
@@ -138,80 +158,81 @@ mod tests {
 
     #[test]
     fn test_parse_selector() {
+        let repo = ScopeRepository::default();
         let test_cases = vec![
             (
                 "comment",
                 ThemeSelector {
-                    target_scope: Scope::new("comment").into_iter().next().unwrap(),
+                    target_scope: repo.parse("comment"),
                     parent_scopes: vec![],
                 },
             ),
             (
                 "source.js meta.function string",
                 ThemeSelector {
-                    target_scope: Scope::new("string").into_iter().next().unwrap(),
+                    target_scope: repo.parse("string"),
                     parent_scopes: vec![
-                        Parent::Anywhere(Scope::new("meta.function").into_iter().next().unwrap()), // deepest parent
-                        Parent::Anywhere(Scope::new("source.js").into_iter().next().unwrap()), // shallowest parent
+                        Parent::Anywhere(repo.parse("meta.function")), // deepest parent
+                        Parent::Anywhere(repo.parse("source.js")),     // shallowest parent
                     ],
                 },
             ),
             (
                 "meta.function > string",
                 ThemeSelector {
-                    target_scope: Scope::new("string").into_iter().next().unwrap(),
-                    parent_scopes: vec![Parent::Direct(
-                        Scope::new("meta.function").into_iter().next().unwrap(),
-                    )],
+                    target_scope: repo.parse("string"),
+                    parent_scopes: vec![Parent::Direct(repo.parse("meta.function"))],
                 },
             ),
             (
                 "source.js meta.function > string.quoted",
                 ThemeSelector {
-                    target_scope: Scope::new("string.quoted").into_iter().next().unwrap(),
+                    target_scope: repo.parse("string.quoted"),
                     parent_scopes: vec![
-                        Parent::Direct(Scope::new("meta.function").into_iter().next().unwrap()), // deepest parent
-                        Parent::Anywhere(Scope::new("source.js").into_iter().next().unwrap()), // shallowest parent
+                        Parent::Direct(repo.parse("meta.function")), // deepest parent
+                        Parent::Anywhere(repo.parse("source.js")),   // shallowest parent
                     ],
                 },
             ),
             (
                 "source > meta > string",
                 ThemeSelector {
-                    target_scope: Scope::new("string").into_iter().next().unwrap(),
+                    target_scope: repo.parse("string"),
                     parent_scopes: vec![
-                        Parent::Direct(Scope::new("meta").into_iter().next().unwrap()), // deepest parent
-                        Parent::Direct(Scope::new("source").into_iter().next().unwrap()), // shallowest parent
+                        Parent::Direct(repo.parse("meta")),   // deepest parent
+                        Parent::Direct(repo.parse("source")), // shallowest parent
                     ],
                 },
             ),
             (
                 "  source.js   meta.function  >   string  ",
                 ThemeSelector {
-                    target_scope: Scope::new("string").into_iter().next().unwrap(),
+                    target_scope: repo.parse("string"),
                     parent_scopes: vec![
-                        Parent::Direct(Scope::new("meta.function").into_iter().next().unwrap()), // deepest parent
-                        Parent::Anywhere(Scope::new("source.js").into_iter().next().unwrap()), // shallowest parent
+                        Parent::Direct(repo.parse("meta.function")), // deepest parent
+                        Parent::Anywhere(repo.parse("source.js")),   // shallowest parent
                     ],
                 },
             ),
         ];
 
         for (input, expected) in test_cases {
-            let result = parse_selector(input).unwrap();
+            let result = parse_selector(input, &repo).unwrap();
             assert_eq!(result, expected, "Mismatch for input: '{}'", input);
+            assert_eq!(
+                result.as_string(&repo),
+                input.split_whitespace().collect::<Vec<_>>().join(" ")
+            );
         }
     }
 
-    fn create_scope_stack(scope_names: &[&str]) -> Vec<Scope> {
-        scope_names
-            .iter()
-            .map(|name| Scope::new(name).into_iter().next().unwrap())
-            .collect()
+    fn create_scope_stack(repo: &ScopeRepository, scope_names: &[&str]) -> Vec<Scope> {
+        scope_names.iter().map(|name| repo.parse(name)).collect()
     }
 
     #[test]
     fn test_selector_matches() {
+        let repo = ScopeRepository::default();
         let test_cases = vec![
             // (selector_string, scope_stack, expected_match)
 
@@ -309,12 +330,39 @@ mod tests {
                 vec!["source.js", "meta.class", "meta.function", "string.quoted"],
                 true,
             ),
+            // Scopes longer than 8 atoms
+            (
+                "meta.embedded.block.javascript.jsx.tsx.inner.deep.ninth",
+                vec![
+                    "source.tsx",
+                    "meta.embedded.block.javascript.jsx.tsx.inner.deep.ninth.tenth",
+                ],
+                true,
+            ),
+            (
+                "meta.embedded.block.javascript.jsx.tsx.inner.deep.other",
+                vec![
+                    "source.tsx",
+                    "meta.embedded.block.javascript.jsx.tsx.inner.deep.ninth.tenth",
+                ],
+                false,
+            ),
+            (
+                "a.b.c.d.e.f.g.h.i > string",
+                vec!["a.b.c.d.e.f.g.h.i.j", "string.quoted"],
+                true,
+            ),
+            (
+                "a.b.c.d.e.f.g.h.i > string",
+                vec!["a.b.c.d.e.f.g.h", "string.quoted"],
+                false,
+            ),
         ];
 
         for (selector_str, scope_names, expected) in test_cases {
-            let selector = parse_selector(selector_str)
+            let selector = parse_selector(selector_str, &repo)
                 .unwrap_or_else(|| panic!("Failed to parse selector: '{}'", selector_str));
-            let scope_stack = create_scope_stack(&scope_names);
+            let scope_stack = create_scope_stack(&repo, &scope_names);
             let result = selector.matches(&scope_stack);
 
             assert_eq!(
@@ -1,4 +1,3 @@
-use onig::SearchOptions;
 use std::fmt;
 
 /// We use that as a way to convey both the rule and which anchors should be active
@@ -35,18 +34,6 @@ impl AnchorActive {
             AnchorActive::None
         }
     }
-
-    pub fn to_search_options(self) -> SearchOptions {
-        match self {
-            AnchorActive::AG => SearchOptions::SEARCH_OPTION_NONE,
-            AnchorActive::A => SearchOptions::SEARCH_OPTION_NOT_BEGIN_POSITION,
-            AnchorActive::G => SearchOptions::SEARCH_OPTION_NOT_BEGIN_STRING,
-            AnchorActive::None => {
-                SearchOptions::SEARCH_OPTION_NOT_BEGIN_STRING
-                    | SearchOptions::SEARCH_OPTION_NOT_BEGIN_POSITION
-            }
-        }
-    }
 }
 
 impl fmt::Debug for AnchorActive {
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// A handle that can be used to cancel highlighting from another thread.
+///
+/// Clones share the same flag: cancel any of them and the highlighting using it will
+/// stop with `Error::Cancelled` as soon as possible.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a new token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests cancellation of everything using this token or one of its clones
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel` has been called on this token or one of its clones
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken {}
+
+/// The limits the tokenizer has to respect, built from the highlight options
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limits {
+    /// Lines longer than that, in bytes, stop being tokenized at that position
+    pub max_line_length: Option<usize>,
+    /// How long we can spend on a single line
+    pub line_time_limit: Option<Duration>,
+    /// When we need to stop tokenizing the whole document
+    pub deadline: Option<Instant>,
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl Limits {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// The deadline for a line starting now
+    pub fn line_deadline(&self) -> Option<Instant> {
+        let line_deadline = self.line_time_limit.map(|limit| Instant::now() + limit);
+        match (line_deadline, self.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
@@ -1,32 +1,156 @@
 //! This file replicates the logic of <https://github.com/microsoft/vscode-textmate>
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, btree_map};
+use std::fmt;
 use std::ops::Range;
 use std::sync::Arc;
+use std::time::Instant;
 
-use onig::{Region, SearchOptions};
-
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::Registry;
+use crate::grammars::engine::{CompiledRegex, EngineRegex};
 use crate::grammars::{
     END_RULE_ID, GlobalRuleRef, GrammarId, InjectionPrecedence, PatternSet, PatternSetMatch, Regex,
     RegexId, Rule, resolve_backreferences,
 };
-use crate::scope::Scope;
-use crate::tokenizer::anchors::AnchorActive;
-use crate::tokenizer::stack::StateStack;
+use crate::scope::{Scope, ScopeRepository};
+pub(crate) use crate::tokenizer::anchors::AnchorActive;
+use crate::tokenizer::scope_stacks::ScopeStacks;
+pub(crate) use crate::tokenizer::stack::StateStack;
 
 mod anchors;
+mod limits;
+mod scope_stacks;
 mod stack;
 
+pub use limits::CancellationToken;
+pub(crate) use limits::Limits;
+
+/// A token produced by the tokenizer, before any theme is applied.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Token {
     /// Byte span within the line (start inclusive, end exclusive, 0-based)
     pub span: Range<usize>,
     /// Hierarchical scope names, ordered from outermost to innermost
     /// (e.g., source.js -> string.quoted.double -> punctuation.definition.string).
-    pub scopes: Vec<Scope>,
+    /// Tokens from the same tokenizer with the same scopes share them.
+    pub scopes: Arc<[Scope]>,
+}
+
+impl Token {
+    /// The full names of the scopes of this token, ordered from outermost to innermost,
+    /// e.g. `["source.js", "string.quoted.double.js"]`.
+    ///
+    /// The registry needs to be the one that produced the token.
+    pub fn scope_names(&self, registry: &Registry) -> Vec<String> {
+        self.scopes
+            .iter()
+            .map(|scope| registry.scope_name(scope))
+            .collect()
+    }
+}
+
+/// The tokenizer state at the end of a line, required to tokenize the line after it.
+///
+/// The default value is the state before the first line of a document.
+/// States can be compared to know whether a line needs to be re-tokenized and serialized to
+/// be persisted, but they are only meaningful for the grammar that produced them.
+/// Scopes are serialized by name so a restored state can be used with another `Registry`
+/// having the same grammars added in the same order, eg the same one loaded again in another
+/// process.
+#[derive(Clone, Default)]
+pub struct LineState {
+    /// `None` means we haven't tokenized any line yet
+    pub(crate) stack: Option<StateStack>,
+    /// The repository the scopes of `stack` come from, for states produced by a registry
+    pub(crate) scope_repo: Option<Arc<ScopeRepository>>,
+    /// The names of the atoms of the scopes of `stack`, for deserialized states
+    pub(crate) atom_names: Option<BTreeMap<u16, String>>,
+}
+
+/// How a `LineState` is serialized: scopes are only meaningful with the names of their atoms
+#[derive(Serialize, Deserialize)]
+struct SerializedLineState {
+    stack: Option<StateStack>,
+    #[serde(default)]
+    atoms: BTreeMap<u16, String>,
+}
+
+impl LineState {
+    pub(crate) fn new(stack: StateStack, scope_repo: &Arc<ScopeRepository>) -> Self {
+        Self {
+            stack: Some(stack),
+            scope_repo: Some(Arc::clone(scope_repo)),
+            atom_names: None,
+        }
+    }
+
+    /// Whether this is the state before the first line of a document.
+    pub fn is_initial(&self) -> bool {
+        self.stack.is_none()
+    }
+
+    /// The names of the atoms used by the scopes of the stack
+    pub(crate) fn atom_names(&self) -> BTreeMap<u16, String> {
+        if let Some(atom_names) = &self.atom_names {
+            return atom_names.clone();
+        }
+        let (Some(stack), Some(scope_repo)) = (&self.stack, &self.scope_repo) else {
+            return BTreeMap::new();
+        };
+        let mut atom_names = BTreeMap::new();
+        for frame in &stack.frames {
+            for scope in frame.name_scopes.iter().chain(frame.content_scopes.iter()) {
+                for atom in scope.atom_numbers() {
+                    if let btree_map::Entry::Vacant(entry) = atom_names.entry(atom)
+                        && let Some(name) = scope_repo.atom_name(atom)
+                    {
+                        entry.insert(name);
+                    }
+                }
+            }
+        }
+        atom_names
+    }
+}
+
+impl fmt::Debug for LineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LineState")
+            .field("stack", &self.stack)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Only the stacks are compared: states from different registries can be equal
+impl PartialEq for LineState {
+    fn eq(&self, other: &Self) -> bool {
+        self.stack == other.stack
+    }
+}
+
+impl Eq for LineState {}
+
+impl Serialize for LineState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedLineState {
+            stack: self.stack.clone(),
+            atoms: self.atom_names(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LineState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = SerializedLineState::deserialize(deserializer)?;
+        Ok(Self {
+            stack: state.stack,
+            scope_repo: None,
+            atom_names: Some(state.atoms),
+        })
+    }
 }
 
 /// Small wrapper so we make we only produce valid tokens.
@@ -38,10 +162,19 @@ struct TokenAccumulator {
     /// Position up to which tokens have been generated
     /// (start of next token to be produced)
     last_end_pos: usize,
+    /// The rule that produced each token, only tracked when inspecting tokens
+    rules: Option<Vec<GlobalRuleRef>>,
 }
 
 impl TokenAccumulator {
-    fn produce(&mut self, end_pos: usize, scopes: &[Scope]) {
+    fn new(track_rules: bool) -> Self {
+        Self {
+            rules: track_rules.then(Vec::new),
+            ..Default::default()
+        }
+    }
+
+    fn produce(&mut self, end_pos: usize, scopes: &Arc<[Scope]>, rule_ref: GlobalRuleRef) {
         // Skip empty tokens (can happen with zero-width matches)
         if self.last_end_pos >= end_pos {
             return;
@@ -54,14 +187,17 @@ impl TokenAccumulator {
             self.last_end_pos,
             scopes
                 .iter()
-                .map(|s| format!(" * {s}"))
+                .map(|s| format!(" * {s:?}"))
                 .collect::<Vec<_>>()
                 .join("\n")
         );
         self.tokens.push(Token {
             span: self.last_end_pos..end_pos,
-            scopes: scopes.to_vec(),
+            scopes: scopes.clone(),
         });
+        if let Some(rules) = &mut self.rules {
+            rules.push(rule_ref);
+        }
 
         // Advance to the end of this token
         self.last_end_pos = end_pos;
@@ -75,6 +211,9 @@ impl TokenAccumulator {
             && tok.span.start == line_len - 1
         {
             self.tokens.pop();
+            if let Some(rules) = &mut self.rules {
+                rules.pop();
+            }
         }
 
         // If we have a token that includes the trailing newline,
@@ -98,6 +237,20 @@ pub struct Tokenizer<'g> {
     /// versions of the same regex in there
     /// Some regex content use backref so they are essentially dynamic patterns
     end_regex_cache: HashMap<String, Regex>,
+    /// Time/length/cancellation limits, none by default
+    limits: Limits,
+    /// When we need to stop tokenizing the current line
+    line_deadline: Option<Instant>,
+    /// Where we stop tokenizing the current line if it is longer than the max line length
+    line_stop_pos: Option<usize>,
+    /// Whether the current line was stopped early because of the limits
+    line_degraded: bool,
+    /// Whether to keep track of the rule that produced each token
+    track_rules: bool,
+    /// The scope stacks of the tokens and stack frames we have produced
+    scope_stacks: ScopeStacks,
+    /// Reused to add the line terminator to lines that don't have one
+    line_buffer: String,
 }
 
 impl<'g> Tokenizer<'g> {
@@ -106,9 +259,40 @@ impl<'g> Tokenizer<'g> {
             base_grammar_id,
             registry,
             end_regex_cache: HashMap::new(),
+            limits: Limits::default(),
+            line_deadline: None,
+            line_stop_pos: None,
+            line_degraded: false,
+            track_rules: false,
+            scope_stacks: ScopeStacks::default(),
+            line_buffer: String::new(),
         }
     }
 
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Whether the cancellation token from the limits has been triggered
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.limits.is_cancelled()
+    }
+
+    /// Whether the last line given to `tokenize_next_line` was not fully tokenized because of
+    /// the limits
+    pub(crate) fn last_line_degraded(&self) -> bool {
+        self.line_degraded
+    }
+
+    /// Whether we need to stop tokenizing the current line at that position because of the limits
+    fn should_stop(&self, pos: usize) -> bool {
+        self.line_stop_pos.is_some_and(|stop_pos| pos >= stop_pos)
+            || self
+                .line_deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            || self.limits.is_cancelled()
+    }
+
     /// Matches injection patterns at the current position
     /// Returns (is_left_precedence, PatternSetMatch) for the best match
     fn match_injections(
@@ -138,9 +322,7 @@ impl<'g> Tokenizer<'g> {
                 Some(rule), // Override rule_ref for injection testing
             )?;
 
-            if let Some(found) =
-                pattern_set.find_at(line, pos, anchor_context.to_search_options())?
-            {
+            if let Some(found) = pattern_set.find_at(line, pos, anchor_context)? {
                 if let Some((_, current_best_match)) = &best_match {
                     if found.start >= current_best_match.start {
                         continue;
@@ -168,7 +350,6 @@ impl<'g> Tokenizer<'g> {
         pos: usize,
         is_first_line: bool,
         anchor_position: Option<usize>,
-        region: &mut Region,
     ) -> Result<Option<PatternSetMatch>, String> {
         let anchor_context = AnchorActive::new(is_first_line, anchor_position, pos);
 
@@ -176,13 +357,12 @@ impl<'g> Tokenizer<'g> {
         // The end pattern is done separately from the regex so the regset doesn't need to be updated
         // and can be shared across threads safely
         let pattern_set = self.get_or_create_pattern_set(stack, None)?;
-        let regset_match = pattern_set.find_at(line, pos, anchor_context.to_search_options())?;
+        let regset_match = pattern_set.find_at(line, pos, anchor_context)?;
         let rule_ref = stack.top().rule_ref;
         let apply_end_pattern_last =
             self.registry.grammars[rule_ref.grammar].rules[rule_ref.rule].apply_end_pattern_last();
 
-        let end_match =
-            self.match_end_pattern(stack, line, pos, anchor_context.to_search_options(), region)?;
+        let end_match = self.match_end_pattern(stack, line, pos, anchor_context)?;
 
         // Get injection matches
         let injection_match =
@@ -233,7 +413,6 @@ impl<'g> Tokenizer<'g> {
         pos: &mut usize,
         acc: &mut TokenAccumulator,
         is_first_line: bool,
-        region: &mut Region,
     ) -> Result<(StateStack, Option<usize>, bool), String> {
         // Initialize anchor position: reset to 0 if previous rule captured EOL, otherwise use stack value
         let mut anchor_position: Option<usize> = if stack.top().begin_rule_has_captured_eol {
@@ -304,16 +483,8 @@ impl<'g> Tokenizer<'g> {
 
             let search_text = line.get(*pos..).unwrap_or("");
 
-            if compiled_re
-                .search_with_options(
-                    search_text,
-                    0,
-                    search_text.len(),
-                    active_anchor.to_search_options(),
-                    Some(region),
-                )
-                .is_some()
-                && let Some((start, end)) = region.pos(0)
+            if let Some(while_captures) = compiled_re.search(search_text, 0, active_anchor)
+                && let Some((start, end)) = while_captures[0]
                 && start == 0
             // Must match at current position
             {
@@ -321,7 +492,7 @@ impl<'g> Tokenizer<'g> {
                 let absolute_start = *pos;
                 let absolute_end = *pos + end;
 
-                acc.produce(absolute_start, &frame.content_scopes);
+                acc.produce(absolute_start, &frame.content_scopes, frame.rule_ref);
                 // Handle while captures if they exist
                 if let Some(Rule::BeginWhile(begin_while_rule)) = self.registry.grammars
                     [frame.rule_ref.grammar]
@@ -329,8 +500,9 @@ impl<'g> Tokenizer<'g> {
                     .get(frame.rule_ref.rule.as_index())
                     && !begin_while_rule.while_captures.is_empty()
                 {
-                    let captures_pos: Vec<Option<(usize, usize)>> = (0..region.len())
-                        .map(|i| region.pos(i).map(|(s, e)| (*pos + s, *pos + e)))
+                    let captures_pos: Vec<Option<(usize, usize)>> = while_captures
+                        .iter()
+                        .map(|cap| cap.map(|(s, e)| (*pos + s, *pos + e)))
                         .collect();
 
                     // Create temporary StateStack only for resolve_captures
@@ -348,7 +520,7 @@ impl<'g> Tokenizer<'g> {
                 }
 
                 // Produce token for the while match itself
-                acc.produce(absolute_end, &frame.content_scopes);
+                acc.produce(absolute_end, &frame.content_scopes, frame.rule_ref);
 
                 // Advance position and update anchor - matches VSCode behavior
                 if absolute_end > *pos {
@@ -407,7 +579,7 @@ impl<'g> Tokenizer<'g> {
         resolved_pattern: Option<&str>,
         grammar_id: GrammarId,
         regex_id: RegexId,
-    ) -> Result<&std::sync::Arc<onig::Regex>, String> {
+    ) -> Result<&Arc<CompiledRegex>, String> {
         if let Some(pattern) = resolved_pattern {
             let regex = self
                 .end_regex_cache
@@ -431,8 +603,7 @@ impl<'g> Tokenizer<'g> {
         stack: &StateStack,
         line: &str,
         pos: usize,
-        search_options: SearchOptions,
-        region: &mut Region,
+        anchors: AnchorActive,
     ) -> Result<Option<PatternSetMatch>, String> {
         let rule_ref = stack.top().rule_ref;
         let rule = &self.registry.grammars[rule_ref.grammar].rules[rule_ref.rule];
@@ -445,14 +616,9 @@ impl<'g> Tokenizer<'g> {
             _ => return Ok(None),
         };
 
-        if compiled_re
-            .search_with_options(line, pos, line.len(), search_options, Some(region))
-            .is_some()
-            && let Some((start, end)) = region.pos(0)
+        if let Some(capture_pos) = compiled_re.search(line, pos, anchors)
+            && let Some((start, end)) = capture_pos[0]
         {
-            let capture_pos: Vec<Option<(usize, usize)>> =
-                (0..region.len()).map(|i| region.pos(i)).collect();
-
             return Ok(Some(PatternSetMatch {
                 rule_ref: GlobalRuleRef {
                     grammar: rule_ref.grammar,
@@ -480,8 +646,8 @@ impl<'g> Tokenizer<'g> {
             return Ok(());
         }
 
-        // (scopes, end_pos)[]
-        let mut local_stack: Vec<(Vec<Scope>, usize)> = Vec::with_capacity(2);
+        // (scopes, end_pos, rule_ref)[]
+        let mut local_stack: Vec<(Arc<[Scope]>, usize, GlobalRuleRef)> = Vec::with_capacity(2);
 
         let min = std::cmp::min(rule_captures.len(), captures.len());
 
@@ -502,17 +668,17 @@ impl<'g> Tokenizer<'g> {
 
             // pop captures while needed
             while !local_stack.is_empty()
-                && let Some((scopes, end_pos)) = local_stack.last()
+                && let Some((scopes, end_pos, local_rule_ref)) = local_stack.last()
                 && *end_pos <= cap_start
             {
-                accumulator.produce(*end_pos, scopes);
+                accumulator.produce(*end_pos, scopes, *local_rule_ref);
                 local_stack.pop();
             }
 
-            if let Some((scopes, _)) = local_stack.last() {
-                accumulator.produce(cap_start, scopes);
+            if let Some((scopes, _, local_rule_ref)) = local_stack.last() {
+                accumulator.produce(cap_start, scopes, *local_rule_ref);
             } else {
-                accumulator.produce(cap_start, &stack.top().content_scopes);
+                accumulator.produce(cap_start, &stack.top().content_scopes, stack.top().rule_ref);
             }
 
             //  Check if it has captures. If it does we need to call tokenize_string
@@ -523,20 +689,18 @@ impl<'g> Tokenizer<'g> {
                 retokenization_stack.push(rule_ref, None, false, Some(cap_start));
 
                 // Apply rule name scopes to the new state
-                retokenization_stack
-                    .top_mut()
-                    .name_scopes
-                    .extend(rule.get_name_scopes(line, captures));
+                let name_scopes = self.scope_stacks.extend(
+                    &retokenization_stack.top().name_scopes,
+                    &rule.get_name_scopes(line, captures, &self.registry.scope_repo),
+                );
 
                 // Start with name + content scopes for content scopes
-                retokenization_stack.top_mut().content_scopes =
-                    retokenization_stack.top().name_scopes.clone();
-
-                // Apply content scopes
-                retokenization_stack
-                    .top_mut()
-                    .content_scopes
-                    .extend(rule.get_content_scopes(line, captures));
+                let content_scopes = self.scope_stacks.extend(
+                    &name_scopes,
+                    &rule.get_content_scopes(line, captures, &self.registry.scope_repo),
+                );
+                retokenization_stack.top_mut().name_scopes = name_scopes;
+                retokenization_stack.top_mut().content_scopes = content_scopes;
                 let substring = &line[0..cap_end];
                 #[cfg(feature = "debug")]
                 {
@@ -557,29 +721,33 @@ impl<'g> Tokenizer<'g> {
                     false,
                 )?;
 
-                for token in retokenized_acc.tokens {
+                for (i, token) in retokenized_acc.tokens.iter().enumerate() {
+                    let token_rule_ref = retokenized_acc
+                        .rules
+                        .as_ref()
+                        .map_or(rule_ref, |rules| rules[i]);
                     // Only include tokens that are within the capture bounds (they should all be valid now)
-                    accumulator.produce(token.span.end, &token.scopes);
+                    accumulator.produce(token.span.end, &token.scopes, token_rule_ref);
                 }
                 continue;
             }
 
             // For rules without patterns, we still need to apply their scopes
-            let rule_scopes = rule.get_name_scopes(line, captures);
+            let rule_scopes = rule.get_name_scopes(line, captures, &self.registry.scope_repo);
 
             if !rule_scopes.is_empty() {
-                let mut base = if let Some((scopes, _)) = local_stack.last() {
-                    scopes.clone()
+                let base = if let Some((scopes, _, _)) = local_stack.last() {
+                    scopes
                 } else {
-                    stack.top().content_scopes.clone()
+                    &stack.top().content_scopes
                 };
-                base.extend(rule_scopes);
-                local_stack.push((base, cap_end));
+                let scopes = self.scope_stacks.extend(base, &rule_scopes);
+                local_stack.push((scopes, cap_end, rule_ref));
             }
         }
 
-        while let Some((scopes, end_pos)) = local_stack.pop() {
-            accumulator.produce(end_pos, &scopes);
+        while let Some((scopes, end_pos, local_rule_ref)) = local_stack.pop() {
+            accumulator.produce(end_pos, &scopes, local_rule_ref);
         }
 
         Ok(())
@@ -593,12 +761,11 @@ impl<'g> Tokenizer<'g> {
         is_first_line: bool,
         check_while_conditions: bool,
     ) -> Result<(TokenAccumulator, StateStack), String> {
-        let mut accumulator = TokenAccumulator::default();
+        let mut accumulator = TokenAccumulator::new(self.track_rules);
         let mut pos = line_pos;
         let mut anchor_position = None;
         let mut is_first_line = is_first_line;
         let mut stack = stack;
-        let mut region = Region::new();
 
         // 1. We check if the while pattern is still truthy
         if check_while_conditions {
@@ -608,7 +775,6 @@ impl<'g> Tokenizer<'g> {
                 &mut pos,
                 &mut accumulator,
                 is_first_line,
-                &mut region,
             )?;
             stack = while_res.0;
             anchor_position = while_res.1;
@@ -617,20 +783,40 @@ impl<'g> Tokenizer<'g> {
 
         // 2. We check for any matching patterns
         loop {
+            // Out of budget: the rest of the line gets the current content scopes
+            if self.should_stop(pos) {
+                self.line_degraded = true;
+                accumulator.produce(
+                    line.len(),
+                    &stack.top().content_scopes,
+                    stack.top().rule_ref,
+                );
+                break;
+            }
+
             #[cfg(feature = "debug")]
             {
                 log::trace!("");
                 log::trace!("[tokenize_line] Scanning {pos}: |{:?}|", &line[pos..]);
             }
 
-            if let Some(m) = self.match_rule_or_injections(
-                &stack,
-                line,
-                pos,
-                is_first_line,
-                anchor_position,
-                &mut region,
-            )? {
+            if let Some(m) =
+                self.match_rule_or_injections(&stack, line, pos, is_first_line, anchor_position)?
+            {
+                // Same as above if the next match starts after the max line length
+                if self
+                    .line_stop_pos
+                    .is_some_and(|stop_pos| m.start >= stop_pos)
+                {
+                    self.line_degraded = true;
+                    accumulator.produce(
+                        line.len(),
+                        &stack.top().content_scopes,
+                        stack.top().rule_ref,
+                    );
+                    break;
+                }
+
                 #[cfg(feature = "debug")]
                 log::debug!(
                     "[tokenize_line] Matched rule: {:?} from pos {} to {} => {:?}",
@@ -669,7 +855,7 @@ impl<'g> Tokenizer<'g> {
                         );
                         log::debug!("[BEFORE POP] Stack: {:?}", stack);
                     }
-                    accumulator.produce(m.start, &stack.top().content_scopes);
+                    accumulator.produce(m.start, &stack.top().content_scopes, stack.top().rule_ref);
                     let popped_enter_position = stack.top().enter_position; // Save for infinite loop protection
                     let popped_anchor_position = stack.top().anchor_position;
                     #[cfg(feature = "debug")]
@@ -683,7 +869,7 @@ impl<'g> Tokenizer<'g> {
                         &mut accumulator,
                         is_first_line,
                     )?;
-                    accumulator.produce(m.end, &stack.top().content_scopes);
+                    accumulator.produce(m.end, &stack.top().content_scopes, stack.top().rule_ref);
 
                     // Pop to parent state and update anchor position
                     let popped_frame = stack.pop().unwrap();
@@ -708,14 +894,20 @@ impl<'g> Tokenizer<'g> {
                             "[INFINITE LOOP PROTECTION] Restored rule to stack: {:?}",
                             stack
                         );
-                        accumulator.produce(line.len(), &stack.top().content_scopes);
+                        accumulator.produce(
+                            line.len(),
+                            &stack.top().content_scopes,
+                            stack.top().rule_ref,
+                        );
                         break;
                     }
                 } else {
                     let rule = &self.registry.grammars[m.rule_ref.grammar].rules[m.rule_ref.rule];
-                    accumulator.produce(m.start, &stack.top().content_scopes);
-                    let mut new_scopes = stack.top().content_scopes.clone();
-                    new_scopes.extend(rule.get_name_scopes(line, &m.capture_pos));
+                    accumulator.produce(m.start, &stack.top().content_scopes, stack.top().rule_ref);
+                    let new_scopes = self.scope_stacks.extend(
+                        &stack.top().content_scopes,
+                        &rule.get_name_scopes(line, &m.capture_pos, &self.registry.scope_repo),
+                    );
                     // Use push_with_scopes to avoid double-cloning
                     stack.push_with_scopes(
                         m.rule_ref,
@@ -749,10 +941,20 @@ impl<'g> Tokenizer<'g> {
                             &mut accumulator,
                             is_first_line,
                         )?;
-                        accumulator.produce(m.end, &stack.top().content_scopes);
+                        accumulator.produce(
+                            m.end,
+                            &stack.top().content_scopes,
+                            stack.top().rule_ref,
+                        );
                         anchor_position = Some(m.end);
-                        let mut content_scopes = stack.top().name_scopes.clone();
-                        content_scopes.extend(rule.get_content_scopes(line, &m.capture_pos));
+                        let content_scopes = self.scope_stacks.extend(
+                            &stack.top().name_scopes,
+                            &rule.get_content_scopes(
+                                line,
+                                &m.capture_pos,
+                                &self.registry.scope_repo,
+                            ),
+                        );
                         stack.set_content_scopes(content_scopes);
 
                         if end_has_backrefs {
@@ -787,7 +989,11 @@ impl<'g> Tokenizer<'g> {
                                 &mut accumulator,
                                 is_first_line,
                             )?;
-                            accumulator.produce(m.end, &stack.top().content_scopes);
+                            accumulator.produce(
+                                m.end,
+                                &stack.top().content_scopes,
+                                stack.top().rule_ref,
+                            );
                             // pop rule immediately since it is a MatchRule
                             stack.pop();
 
@@ -797,7 +1003,11 @@ impl<'g> Tokenizer<'g> {
                                 #[cfg(feature = "debug")]
                                 log::warn!("Match rule didn't advance, safe_pop and stop");
                                 stack.safe_pop();
-                                accumulator.produce(line.len(), &stack.top().content_scopes);
+                                accumulator.produce(
+                                    line.len(),
+                                    &stack.top().content_scopes,
+                                    stack.top().rule_ref,
+                                );
                                 break;
                             }
                         }
@@ -814,7 +1024,11 @@ impl<'g> Tokenizer<'g> {
                 #[cfg(feature = "debug")]
                 log::debug!("[tokenize_line] no more matches");
                 // No more matches - emit final token and stop
-                accumulator.produce(line.len(), &stack.top().content_scopes);
+                accumulator.produce(
+                    line.len(),
+                    &stack.top().content_scopes,
+                    stack.top().rule_ref,
+                );
                 break;
             }
         }
@@ -822,31 +1036,129 @@ impl<'g> Tokenizer<'g> {
         Ok((accumulator, stack))
     }
 
-    pub(crate) fn tokenize_string(&mut self, text: &str) -> Result<Vec<Vec<Token>>, String> {
+    /// Tokenizes a single line, without its line terminator, starting from the given stack.
+    /// A `None` stack means it is the first line of the document.
+    /// Returns the tokens of the line and the stack to use for the next line.
+    pub(crate) fn tokenize_next_line(
+        &mut self,
+        line: &str,
+        stack: Option<StateStack>,
+    ) -> Result<(Vec<Token>, StateStack), String> {
+        self.track_rules = false;
+        let (acc, new_state) = self.tokenize_next_line_inner(line, stack)?;
+        Ok((acc.tokens, new_state))
+    }
+
+    /// Same as `tokenize_next_line` but also returns the rule that produced each token.
+    pub(crate) fn tokenize_next_line_with_rules(
+        &mut self,
+        line: &str,
+        stack: Option<StateStack>,
+    ) -> Result<(Vec<Token>, Vec<GlobalRuleRef>, StateStack), String> {
+        self.track_rules = true;
+        let res = self.tokenize_next_line_inner(line, stack);
+        self.track_rules = false;
+        let (acc, new_state) = res?;
+        Ok((acc.tokens, acc.rules.unwrap_or_default(), new_state))
+    }
+
+    fn tokenize_next_line_inner(
+        &mut self,
+        line: &str,
+        stack: Option<StateStack>,
+    ) -> Result<(TokenAccumulator, StateStack), String> {
+        // Always add a new line, some regex expect it
+        let mut line_buffer = std::mem::take(&mut self.line_buffer);
+        line_buffer.clear();
+        line_buffer.push_str(line);
+        line_buffer.push('\n');
+        let res = self.tokenize_terminated_line(&line_buffer, stack);
+        self.line_buffer = line_buffer;
+        res
+    }
+
+    /// Same as `tokenize_next_line_inner` for a line that already ends with `\n`
+    fn tokenize_terminated_line(
+        &mut self,
+        line: &str,
+        stack: Option<StateStack>,
+    ) -> Result<(TokenAccumulator, StateStack), String> {
+        debug_assert!(line.ends_with('\n'));
+        let is_first_line = stack.is_none();
+        let stack = stack.unwrap_or_else(|| {
+            StateStack::new(
+                self.base_grammar_id,
+                self.registry.grammars[self.base_grammar_id].scope.clone(),
+            )
+        });
+
+        self.line_degraded = false;
+        if self.limits.is_cancelled()
+            || self
+                .limits
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            // No budget left at all, no need to even start
+            self.line_degraded = true;
+            let mut acc = TokenAccumulator::new(self.track_rules);
+            acc.produce(
+                line.len() - 1,
+                &stack.top().content_scopes,
+                stack.top().rule_ref,
+            );
+            return Ok((acc, stack));
+        }
+        self.line_deadline = self.limits.line_deadline();
+        self.line_stop_pos = self
+            .limits
+            .max_line_length
+            .filter(|&max_length| line.len() - 1 > max_length);
+
+        let (mut acc, mut new_state) = self.tokenize_line(stack, line, 0, is_first_line, true)?;
+        acc.finalize(line.len());
+        new_state.reset();
+        Ok((acc, new_state))
+    }
+
+    /// Tokenizes a whole document, returning the tokens of each line as well as the indices
+    /// of the lines that were not fully tokenized because of the limits.
+    /// It stops early if the tokenization has been cancelled.
+    pub(crate) fn tokenize_string(
+        &mut self,
+        text: &str,
+    ) -> Result<(Vec<Vec<Token>>, Vec<usize>), String> {
         if text.is_empty() {
-            return Ok(vec![]);
+            return Ok((vec![], vec![]));
         }
 
-        let mut stack = StateStack::new(
-            self.base_grammar_id,
-            self.registry.grammars[self.base_grammar_id].scope,
-        );
+        let mut stack = None;
         let mut lines_tokens = Vec::new();
-        let mut is_first_line = true;
-
-        // Split by lines and tokenize each line
-        for line in text.split('\n') {
-            // Always add a new line, some regex expect it
-            let line = format!("{line}\n");
-            let (mut acc, mut new_state) =
-                self.tokenize_line(stack, &line, 0, is_first_line, true)?;
-            acc.finalize(line.len());
-            lines_tokens.push(acc.tokens);
-            new_state.reset();
-            stack = new_state;
-            is_first_line = false;
+        let mut degraded_lines = Vec::new();
+        self.track_rules = false;
+
+        // Same lines as `text.split('\n')` but we keep the line terminators so we only need to
+        // add one to the last line
+        let lines = text
+            .split_inclusive('\n')
+            .chain(text.ends_with('\n').then_some(""));
+        for (idx, line) in lines.enumerate() {
+            let (acc, new_state) = if line.ends_with('\n') {
+                self.tokenize_terminated_line(line, stack)?
+            } else {
+                self.tokenize_next_line_inner(line, stack)?
+            };
+            let tokens = acc.tokens;
+            if self.line_degraded {
+                degraded_lines.push(idx);
+                if self.is_cancelled() {
+                    break;
+                }
+            }
+            lines_tokens.push(tokens);
+            stack = Some(new_state);
         }
 
-        Ok(lines_tokens)
+        Ok((lines_tokens, degraded_lines))
     }
 }
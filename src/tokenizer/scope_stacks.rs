@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::scope::Scope;
+
+/// How many scope stacks a tokenizer remembers before starting over
+const MAX_INTERNED_STACKS: usize = 16_384;
+
+/// Interns the scope stacks built by a tokenizer so every token and stack frame with the same
+/// scopes shares a single allocation: tokenizing a document allocates once per distinct scope
+/// stack rather than once per token.
+#[derive(Debug, Default)]
+pub(crate) struct ScopeStacks {
+    stacks: HashSet<Arc<[Scope]>>,
+    /// Reused to build the stacks we look up
+    buffer: Vec<Scope>,
+}
+
+impl ScopeStacks {
+    pub(crate) fn intern(&mut self, scopes: &[Scope]) -> Arc<[Scope]> {
+        if let Some(stack) = self.stacks.get(scopes) {
+            return stack.clone();
+        }
+        // Tokens keep their own reference so it's fine to forget about them
+        if self.stacks.len() >= MAX_INTERNED_STACKS {
+            self.stacks.clear();
+        }
+        let stack: Arc<[Scope]> = scopes.into();
+        self.stacks.insert(stack.clone());
+        stack
+    }
+
+    /// Interns `base` followed by `scopes`
+    pub(crate) fn extend(&mut self, base: &[Scope], scopes: &[Scope]) -> Arc<[Scope]> {
+        if scopes.is_empty() {
+            return self.intern(base);
+        }
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        buffer.extend_from_slice(base);
+        buffer.extend_from_slice(scopes);
+        let stack = self.intern(&buffer);
+        self.buffer = buffer;
+        stack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope::ScopeRepository;
+
+    #[test]
+    fn same_scopes_share_an_allocation() {
+        let repo = ScopeRepository::default();
+        let mut stacks = ScopeStacks::default();
+        let source = repo.parse("source.js");
+        let string = repo.parse("string.quoted.double.js");
+
+        let a = stacks.intern(&[source.clone(), string.clone()]);
+        let b = stacks.extend(std::slice::from_ref(&source), std::slice::from_ref(&string));
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(&*a, &[source.clone(), string]);
+        assert!(!Arc::ptr_eq(&a, &stacks.intern(&[source])));
+    }
+
+    #[test]
+    fn interned_stacks_are_bounded() {
+        let repo = ScopeRepository::default();
+        let mut stacks = ScopeStacks::default();
+        let scopes: Vec<Scope> = (0..130).map(|i| repo.parse(&format!("scope{i}"))).collect();
+        for a in &scopes {
+            for b in &scopes {
+                stacks.intern(&[a.clone(), b.clone()]);
+            }
+        }
+        assert!(stacks.stacks.len() <= MAX_INTERNED_STACKS);
+    }
+}
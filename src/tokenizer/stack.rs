@@ -1,18 +1,21 @@
 use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
 
 use crate::grammars::{GlobalRuleRef, GrammarId, ROOT_RULE_ID};
 use crate::scope::Scope;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StackFrame {
     /// Global rule ref that created this stack element
     pub rule_ref: GlobalRuleRef,
     /// "name" scopes - applied to begin/end delimiters
     /// These scopes are active when matching the rule's boundaries
-    pub name_scopes: Vec<Scope>,
+    pub name_scopes: Arc<[Scope]>,
     /// "contentName" scopes - applied to content between delimiters
     /// These scopes are active for the rule's interior content
-    pub content_scopes: Vec<Scope>,
+    pub content_scopes: Arc<[Scope]>,
     /// Dynamic end/while pattern resolved with backreferences
     /// For BeginEnd rules: the end pattern with \1, \2, etc. resolved
     /// For BeginWhile rules: the while pattern with backreferences resolved
@@ -29,7 +32,7 @@ pub struct StackFrame {
 
 /// Keeps track of nested context as well as how to exit that context and the captures
 /// strings used in backreferences.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StateStack {
     /// Stack frames from root to current
     pub frames: Vec<StackFrame>,
@@ -37,14 +40,15 @@ pub struct StateStack {
 
 impl StateStack {
     pub fn new(grammar_id: GrammarId, grammar_scope: Scope) -> Self {
+        let scopes: Arc<[Scope]> = Arc::new([grammar_scope]);
         Self {
             frames: vec![StackFrame {
                 rule_ref: GlobalRuleRef {
                     grammar: grammar_id,
                     rule: ROOT_RULE_ID,
                 },
-                name_scopes: vec![grammar_scope],
-                content_scopes: vec![grammar_scope],
+                name_scopes: scopes.clone(),
+                content_scopes: scopes,
                 end_pattern: None,
                 begin_rule_has_captured_eol: false,
                 anchor_position: None,
@@ -81,7 +85,7 @@ impl StateStack {
         anchor_position: Option<usize>,
         begin_rule_has_captured_eol: bool,
         enter_position: Option<usize>,
-        scopes: Vec<Scope>,
+        scopes: Arc<[Scope]>,
     ) {
         self.frames.push(StackFrame {
             rule_ref,
@@ -94,7 +98,7 @@ impl StateStack {
         });
     }
 
-    pub fn set_content_scopes(&mut self, content_scopes: Vec<Scope>) {
+    pub fn set_content_scopes(&mut self, content_scopes: Arc<[Scope]>) {
         self.top_mut().content_scopes = content_scopes;
     }
 
@@ -102,6 +106,24 @@ impl StateStack {
         self.top_mut().end_pattern = Some(end_pattern);
     }
 
+    /// The same stack with every scope replaced by `f(scope)`
+    pub fn map_scopes(&self, mut f: impl FnMut(&Scope) -> Scope) -> Self {
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let name_scopes = frame.name_scopes.iter().map(&mut f).collect();
+                let content_scopes = frame.content_scopes.iter().map(&mut f).collect();
+                StackFrame {
+                    name_scopes,
+                    content_scopes,
+                    ..frame.clone()
+                }
+            })
+            .collect();
+        Self { frames }
+    }
+
     /// Exits the current context, getting back to the parent
     pub fn pop(&mut self) -> Option<StackFrame> {
         if self.frames.len() > 1 {
@@ -159,7 +181,7 @@ impl fmt::Debug for StateStack {
                     if i > 0 {
                         write!(f, ", ")?;
                     }
-                    write!(f, "{}", scope.build_string())?;
+                    write!(f, "{scope:?}")?;
                 }
                 write!(f, "]")?;
             }
@@ -171,7 +193,7 @@ impl fmt::Debug for StateStack {
                     if i > 0 {
                         write!(f, ", ")?;
                     }
-                    write!(f, "{}", scope.build_string())?;
+                    write!(f, "{scope:?}")?;
                 }
                 write!(f, "]")?;
             }
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::GialloResult;
+use crate::grammars::Regex;
+use crate::grammars::engine::EngineRegex;
+use crate::jsonc;
+use crate::tokenizer::AnchorActive;
+
+/// The `package.json` of a VSCode extension, only what we use
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ExtensionManifest {
+    #[serde(default)]
+    pub(crate) contributes: Contributes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Contributes {
+    #[serde(default)]
+    pub(crate) grammars: Vec<GrammarEntry>,
+    #[serde(default)]
+    pub(crate) languages: Vec<LanguageEntry>,
+    #[serde(default)]
+    pub(crate) themes: Vec<ThemeEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GrammarEntry {
+    /// Not set for injection grammars
+    pub(crate) language: Option<String>,
+    pub(crate) scope_name: String,
+    pub(crate) path: PathBuf,
+    #[serde(default)]
+    pub(crate) embedded_languages: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) inject_to: Vec<String>,
+    #[serde(default)]
+    pub(crate) token_types: BTreeMap<String, TokenType>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LanguageEntry {
+    pub(crate) id: String,
+    #[serde(default)]
+    pub(crate) aliases: Vec<String>,
+    #[serde(default)]
+    pub(crate) extensions: Vec<String>,
+    #[serde(default)]
+    pub(crate) filenames: Vec<String>,
+    pub(crate) first_line: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ThemeEntry {
+    pub(crate) label: String,
+    pub(crate) ui_theme: String,
+    pub(crate) path: PathBuf,
+}
+
+impl ThemeEntry {
+    /// The theme type as we name it, from the VSCode base theme
+    pub(crate) fn kind(&self) -> &'static str {
+        match self.ui_theme.as_str() {
+            "vs" | "hc-light" => "light",
+            _ => "dark",
+        }
+    }
+}
+
+impl ExtensionManifest {
+    pub(crate) fn load(extension_dir: &Path) -> GialloResult<Self> {
+        jsonc::from_file(&extension_dir.join("package.json"))
+    }
+}
+
+/// What a token is according to `tokenTypes` of a grammar in a VSCode extension.
+/// VSCode uses it for editing features, eg not auto-closing brackets in strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    /// Neither a string nor a comment, eg code in a template string
+    Other,
+    /// A comment
+    Comment,
+    /// A string
+    String,
+}
+
+/// What a VSCode extension declares about one of its grammars besides the grammar itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrammarContribution {
+    /// Scope → id of the language embedded in the grammar at that scope,
+    /// eg `meta.embedded.block.css` → `css`
+    pub embedded_languages: BTreeMap<String, String>,
+    /// Scope → type of the tokens with that scope, eg `meta.template.expression` → `Other`
+    pub token_types: BTreeMap<String, TokenType>,
+}
+
+impl GrammarContribution {
+    /// The id of the language embedded at the given scope stack, eg the scopes of a `Token`
+    /// given to `Registry::scope_name`, if any.
+    /// The innermost scope wins, like in VSCode.
+    pub fn embedded_language<S: AsRef<str>>(&self, scopes: &[S]) -> Option<&str> {
+        find_innermost(&self.embedded_languages, scopes).map(String::as_str)
+    }
+
+    /// The type of tokens at the given scope stack if one of the scopes has one, the innermost
+    /// scope winning.
+    pub fn token_type<S: AsRef<str>>(&self, scopes: &[S]) -> Option<TokenType> {
+        find_innermost(&self.token_types, scopes).copied()
+    }
+}
+
+/// The value of the longest key that is a prefix of the innermost scope having one.
+/// `meta.embedded` is a prefix of `meta.embedded.block` but not of `meta.embeddedness`.
+fn find_innermost<'a, T, S: AsRef<str>>(
+    values: &'a BTreeMap<String, T>,
+    scopes: &[S],
+) -> Option<&'a T> {
+    scopes.iter().rev().find_map(|scope| {
+        let scope = scope.as_ref();
+        values
+            .iter()
+            .filter(|(prefix, _)| {
+                scope
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, value)| value)
+    })
+}
+
+/// The files a language contributed by a VSCode extension is used for
+#[derive(Debug, Clone)]
+pub(crate) struct LanguageFiles {
+    pub(crate) id: String,
+    /// Lowercased, with their leading dot, eg `.d.ts`
+    extensions: Vec<String>,
+    /// Lowercased
+    filenames: Vec<String>,
+    first_line: Option<Regex>,
+}
+
+impl LanguageFiles {
+    pub(crate) fn new(language: &LanguageEntry) -> Self {
+        Self {
+            id: language.id.to_lowercase(),
+            extensions: language
+                .extensions
+                .iter()
+                .map(|ext| ext.to_lowercase())
+                .collect(),
+            filenames: language
+                .filenames
+                .iter()
+                .map(|filename| filename.to_lowercase())
+                .collect(),
+            first_line: language.first_line.clone().map(Regex::new),
+        }
+    }
+
+    /// Filenames are compared case-insensitively, like VSCode does
+    pub(crate) fn matches_filename(&self, filename: &str) -> bool {
+        let filename = filename.to_lowercase();
+        self.filenames.contains(&filename)
+    }
+
+    /// The length of the longest extension matching the filename, to prefer `.d.ts` over `.ts`
+    pub(crate) fn matching_extension_len(&self, filename: &str) -> Option<usize> {
+        let filename = filename.to_lowercase();
+        self.extensions
+            .iter()
+            .filter(|ext| filename.ends_with(ext.as_str()))
+            .map(String::len)
+            .max()
+    }
+
+    pub(crate) fn matches_first_line(&self, line: &str) -> bool {
+        self.first_line
+            .as_ref()
+            .and_then(|re| re.compiled())
+            .is_some_and(|re| re.search(line, 0, AnchorActive::AG).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_find_innermost_scope_value() {
+        let contribution = GrammarContribution {
+            embedded_languages: BTreeMap::from([
+                ("meta.embedded.block".to_owned(), "css".to_owned()),
+                ("meta.embedded.block.js".to_owned(), "javascript".to_owned()),
+            ]),
+            token_types: BTreeMap::from([("string.template".to_owned(), TokenType::String)]),
+        };
+        assert_eq!(
+            contribution.embedded_language(&["text.html", "meta.embedded.block.js.x"]),
+            Some("javascript")
+        );
+        assert_eq!(
+            contribution.embedded_language(&["meta.embedded.block.css", "string.quoted"]),
+            Some("css")
+        );
+        assert_eq!(
+            contribution.embedded_language(&["meta.embedded.blocks"]),
+            None
+        );
+        assert_eq!(
+            contribution.token_type(&["source.js", "string.template.js"]),
+            Some(TokenType::String)
+        );
+    }
+}